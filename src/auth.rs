@@ -0,0 +1,224 @@
+use crate::error::DomainError;
+use crate::prelude::*;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tonic::metadata::MetadataValue;
+use tonic::{Request, Status};
+
+// Metadata keys set by the interceptor after verifying the token
+// Incoming values are always dropped, so they cannot be forged
+pub(crate) const CALLER_UID_KEY: &str = "x-commitment-caller-uid";
+pub(crate) const CALLER_ROLE_KEY: &str = "x-commitment-caller-role";
+
+/// Caller role
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+  // Creates and withdraws commitments, manages purchases
+  Manager,
+  // Adds and removes purchases
+  Cashier,
+}
+
+impl std::fmt::Display for Role {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Role::Manager => write!(f, "manager"),
+      Role::Cashier => write!(f, "cashier"),
+    }
+  }
+}
+
+impl std::str::FromStr for Role {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "manager" => Ok(Role::Manager),
+      "cashier" => Ok(Role::Cashier),
+      _ => Err(format!("Unknown role: {}", s)),
+    }
+  }
+}
+
+/// JWT claims of a caller
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Claims {
+  pub sub: String, // User ID
+  pub role: Role,
+  pub exp: u64, // Expiration as UNIX timestamp
+}
+
+/// Verified caller of a request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Caller {
+  pub uid: u32,
+  pub role: Role,
+}
+
+impl Caller {
+  /// Check the caller has any of the given roles
+  pub fn require(&self, roles: &[Role]) -> ServiceResult<()> {
+    match roles.contains(&self.role) {
+      true => Ok(()),
+      false => Err(
+        DomainError::PermissionDenied {
+          role: self.role.to_string(),
+        }
+        .into(),
+      ),
+    }
+  }
+}
+
+/// Bearer token verification with a shared HS256 key
+pub struct Auth {
+  key: DecodingKey<'static>,
+  validation: Validation,
+}
+
+impl Auth {
+  pub fn new(secret: &[u8]) -> Self {
+    Self {
+      key: DecodingKey::from_secret(secret).into_static(),
+      validation: Validation::new(Algorithm::HS256),
+    }
+  }
+
+  /// Verify a token and get its caller
+  pub fn verify(&self, token: &str) -> Result<Caller, String> {
+    let claims = jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation)
+      .map_err(|e| format!("Invalid token: {}", e))?
+      .claims;
+    let uid = claims
+      .sub
+      .parse::<u32>()
+      .map_err(|_| format!("Invalid user ID in token: {}", claims.sub))?;
+    Ok(Caller {
+      uid,
+      role: claims.role,
+    })
+  }
+
+  /// Verify the bearer token of a request,
+  /// and replace the caller metadata with the verified caller
+  #[allow(clippy::result_large_err)] // Interceptor signature of tonic
+  pub fn authenticate(&self, mut request: Request<()>) -> Result<Request<()>, Status> {
+    let metadata = request.metadata_mut();
+    metadata.remove(CALLER_UID_KEY);
+    metadata.remove(CALLER_ROLE_KEY);
+    let token = metadata
+      .get("authorization")
+      .and_then(|v| v.to_str().ok())
+      .and_then(|v| v.strip_prefix("Bearer "))
+      .ok_or_else(|| Status::unauthenticated("Hiányzó azonosító token!"))?;
+    let caller = self
+      .verify(token.trim())
+      .map_err(|_| Status::unauthenticated("Érvénytelen azonosító token!"))?;
+    metadata.insert(CALLER_UID_KEY, MetadataValue::from(caller.uid));
+    // Role names are valid ASCII
+    if let Ok(role) = MetadataValue::from_str(&caller.role.to_string()) {
+      metadata.insert(CALLER_ROLE_KEY, role);
+    }
+    Ok(request)
+  }
+}
+
+/// Interceptor authenticating every request
+/// Without auth every request is let through
+#[allow(clippy::result_large_err)]
+pub fn interceptor(
+  auth: Option<Arc<Auth>>,
+) -> impl Fn(Request<()>) -> Result<Request<()>, Status> + Send + Sync + 'static {
+  move |request| match &auth {
+    Some(auth) => auth.authenticate(request),
+    None => Ok(request),
+  }
+}
+
+/// Caller verified by the interceptor
+pub fn caller<T>(request: &Request<T>) -> ServiceResult<Caller> {
+  let unauthenticated = || ServiceError::unauthenticated("Ismeretlen hívó!");
+  let metadata = request.metadata();
+  let uid = metadata
+    .get(CALLER_UID_KEY)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.parse::<u32>().ok())
+    .ok_or_else(unauthenticated)?;
+  let role = metadata
+    .get(CALLER_ROLE_KEY)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.parse::<Role>().ok())
+    .ok_or_else(unauthenticated)?;
+  Ok(Caller { uid, role })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use jsonwebtoken::{EncodingKey, Header};
+
+  const SECRET: &[u8] = b"test secret";
+
+  fn token(secret: &[u8], sub: &str, role: Role, exp: i64) -> String {
+    let claims = Claims {
+      sub: sub.to_string(),
+      role,
+      exp: exp as u64,
+    };
+    jsonwebtoken::encode(
+      &Header::default(),
+      &claims,
+      &EncodingKey::from_secret(secret),
+    )
+    .unwrap()
+  }
+
+  fn request(token: Option<&str>) -> Request<()> {
+    let mut request = Request::new(());
+    if let Some(token) = token {
+      request.metadata_mut().insert(
+        "authorization",
+        format!("Bearer {}", token).parse().unwrap(),
+      );
+    }
+    // Forged caller is dropped
+    request
+      .metadata_mut()
+      .insert(CALLER_UID_KEY, MetadataValue::from(1u32));
+    request
+  }
+
+  #[test]
+  fn test_authenticate() {
+    let auth = Auth::new(SECRET);
+    let exp = chrono::Utc::now().timestamp() + 3600;
+
+    let r = auth
+      .authenticate(request(Some(&token(SECRET, "42", Role::Cashier, exp))))
+      .unwrap();
+    let c = caller(&r).unwrap();
+    assert_eq!(
+      c,
+      Caller {
+        uid: 42,
+        role: Role::Cashier
+      }
+    );
+    assert!(c.require(&[Role::Cashier, Role::Manager]).is_ok());
+    assert!(c.require(&[Role::Manager]).is_err());
+
+    // Missing, expired, foreign or malformed tokens
+    assert!(auth.authenticate(request(None)).is_err());
+    let expired = token(SECRET, "42", Role::Manager, exp - 7200);
+    assert!(auth.authenticate(request(Some(&expired))).is_err());
+    let foreign = token(b"other secret", "42", Role::Manager, exp);
+    assert!(auth.authenticate(request(Some(&foreign))).is_err());
+    let no_uid = token(SECRET, "admin", Role::Manager, exp);
+    assert!(auth.authenticate(request(Some(&no_uid))).is_err());
+
+    // Not authenticated by the interceptor
+    assert!(caller(&request(None)).is_err());
+  }
+}
@@ -0,0 +1,579 @@
+use crate::commitment::{CommitmentStatus, Customer, CustomerExt};
+use crate::config::Config;
+use crate::error::Lang;
+use crate::export::{self, ExportArgs};
+use crate::import;
+use crate::journal::{self, Event};
+use crate::store::{self, Store};
+use chrono::Utc;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "Usage: commitment_microservice [COMMAND]
+
+Commands:
+  serve                     Start the gRPC server (default)
+  inspect <customer_id>     Print a customer with its commitments
+  list                      List customers with their active commitment
+  verify                    Check the database and compare it to the journal
+  export [--format csv|jsonl] [--customer ID] [--from RFC3339]
+         [--till RFC3339] [--output PATH]
+                            Export customers, commitments and purchase logs
+  import --file PATH [--apply] [--replace-active]
+                            Import commitments from CSV, dry-run by default;
+                            active commitments are kept unless replaced
+  recalc-balances [--apply] Recalculate balances of legacy records,
+                            dry-run by default; the server does not start
+                            until legacy records are recalculated
+  rebuild [--apply]         Rebuild customers from the journal,
+                            dry-run by default
+
+Commands changing data (import, recalc-balances, rebuild with --apply)
+must not run while the server is running.";
+
+/// Subcommand of the service binary
+pub enum Command {
+  Serve,
+  Inspect(u32),
+  List,
+  Verify,
+  Export(ExportArgs),
+  Import {
+    path: PathBuf,
+    apply: bool,
+    replace_active: bool,
+  },
+  RecalcBalances {
+    apply: bool,
+  },
+  Rebuild {
+    apply: bool,
+  },
+}
+
+// Parse flags of the given names; returns the ones present
+fn flags(args: &[String], names: &[&str]) -> Result<Vec<String>, String> {
+  args
+    .iter()
+    .map(|arg| match names.contains(&arg.as_str()) {
+      true => Ok(arg.clone()),
+      false => Err(format!("Ismeretlen paraméter: {}", arg)),
+    })
+    .collect()
+}
+
+impl Command {
+  /// Parse command line arguments without the program name
+  /// No command means serve
+  pub fn parse(args: &[String]) -> Result<Self, String> {
+    let rest = args.get(1..).unwrap_or(&[]);
+    match args.first().map(|a| a.as_str()) {
+      None | Some("serve") => flags(rest, &[]).map(|_| Command::Serve),
+      Some("inspect") => match rest {
+        [customer_id] => customer_id
+          .parse::<u32>()
+          .map(Command::Inspect)
+          .map_err(|_| format!("Hibás ügyfél ID: {}", customer_id)),
+        _ => Err("Az inspect parancs egy ügyfél ID-t vár!".to_string()),
+      },
+      Some("list") => flags(rest, &[]).map(|_| Command::List),
+      Some("verify") => flags(rest, &[]).map(|_| Command::Verify),
+      Some("export") => ExportArgs::parse(rest).map(Command::Export),
+      Some("import") => {
+        let mut path = None;
+        let mut apply = false;
+        let mut replace_active = false;
+        let mut args = rest.iter();
+        while let Some(arg) = args.next() {
+          match arg.as_str() {
+            "--file" => path = args.next().map(PathBuf::from),
+            "--apply" => apply = true,
+            "--replace-active" => replace_active = true,
+            _ => return Err(format!("Ismeretlen paraméter: {}", arg)),
+          }
+        }
+        Ok(Command::Import {
+          path: path.ok_or("Hiányzó paraméter: --file")?,
+          apply,
+          replace_active,
+        })
+      }
+      Some("recalc-balances") => flags(rest, &["--apply"]).map(|f| Command::RecalcBalances {
+        apply: !f.is_empty(),
+      }),
+      Some("rebuild") => flags(rest, &["--apply"]).map(|f| Command::Rebuild {
+        apply: !f.is_empty(),
+      }),
+      Some(command) => Err(format!("Ismeretlen parancs: {}\n\n{}", command, USAGE)),
+    }
+  }
+}
+
+// Customer records as stored, without opening the journal
+fn load_customers(config: &Config) -> Result<Vec<Customer>, String> {
+  let db = store::load_db(&config.db_path())?;
+  Ok(db.iter().map(|c| c.unpack().clone()).collect())
+}
+
+// Customer with legacy purchase logs moved
+// into the purchase registry, in memory only
+fn migrated(mut customer: Customer) -> Customer {
+  if customer.needs_migration() {
+    customer.migrate();
+  }
+  customer
+}
+
+/// Print a customer with its commitments, balances and purchase logs
+pub fn inspect(config: &Config, customer_id: u32) -> Result<(), Box<dyn Error>> {
+  let customer = load_customers(config)?
+    .into_iter()
+    .find(|c| c.customer_id == customer_id)
+    .map(migrated)
+    .ok_or(format!("Customer not found: {}", customer_id))?;
+  let view = serde_json::json!({
+    "customer_id": customer.customer_id,
+    "commitments": customer.commitment_views(),
+  });
+  println!("{}", serde_json::to_string_pretty(&view)?);
+  Ok(())
+}
+
+/// Print one line per customer with its active commitment if any
+pub fn list(config: &Config) -> Result<(), Box<dyn Error>> {
+  let mut customers = load_customers(config)?
+    .into_iter()
+    .map(migrated)
+    .collect::<Vec<Customer>>();
+  customers.sort_by_key(|c| c.customer_id);
+  println!("customer_id\tcommitments\tactive_commitment_id\tdiscount\ttarget\tbalance");
+  for customer in &customers {
+    match customer.get_active_commitment() {
+      Some(c) => println!(
+        "{}\t{}\t{}\t{}\t{}\t{}",
+        customer.customer_id,
+        customer.commitments.len(),
+        c.commitment_id,
+        c.discount_percentage,
+        c.target,
+        c.balance
+      ),
+      None => println!(
+        "{}\t{}\t-\t-\t-\t-",
+        customer.customer_id,
+        customer.commitments.len()
+      ),
+    }
+  }
+  Ok(())
+}
+
+/// Problems of a stored customer record
+/// The record must be the same as its journal replay
+pub fn verify_customer(customer: &Customer, events: &[Event]) -> Vec<String> {
+  let mut res = Vec::new();
+  let mut customer = customer.clone();
+  if customer.needs_migration() {
+    res.push("legacy purchase logs, run recalc-balances".to_string());
+    for mismatch in customer.migrate() {
+      res.push(format!("balance mismatch: {}", mismatch));
+    }
+  }
+  for c in &customer.commitments {
+    if c.customer_id != customer.customer_id {
+      res.push(format!(
+        "commitment {} belongs to customer {}",
+        c.commitment_id, c.customer_id
+      ));
+    }
+    if let CommitmentStatus::Withdrawn { successor } = c.status {
+      if !customer.has_commitment(&successor) {
+        res.push(format!(
+          "commitment {} has unknown successor {}",
+          c.commitment_id, successor
+        ));
+      }
+    }
+  }
+  for (purchase_id, p) in &customer.purchases {
+    if *purchase_id != p.purchase_id {
+      res.push(format!(
+        "purchase {} is registered as {}",
+        p.purchase_id, purchase_id
+      ));
+    }
+    if !customer.has_commitment(&p.commitment_id) {
+      res.push(format!(
+        "purchase {} has unknown commitment {}",
+        p.purchase_id, p.commitment_id
+      ));
+    }
+    // Registry keeps removal in its states only
+    if p.removed || p.removed_at.is_some() || p.removed_by.is_some() || p.reason.is_some() {
+      res.push(format!(
+        "purchase {} has inconsistent removal",
+        p.purchase_id
+      ));
+    }
+    let chain = customer.successors(&p.commitment_id);
+    for (i, state) in p.removals.iter().enumerate() {
+      if !chain.contains(&state.commitment_id) {
+        res.push(format!(
+          "purchase {} is removed outside of its commitment chain",
+          p.purchase_id
+        ));
+      }
+      if p.removals[..i]
+        .iter()
+        .any(|s| s.commitment_id == state.commitment_id)
+      {
+        res.push(format!(
+          "purchase {} has more removal states in commitment {}",
+          p.purchase_id, state.commitment_id
+        ));
+      }
+    }
+  }
+  match journal::rebuild_customer(customer.customer_id, events) {
+    Ok(rebuilt) => {
+      if serde_json::to_string(&rebuilt).ok() != serde_json::to_string(&customer).ok() {
+        res.push("differs from its journal replay".to_string());
+      }
+    }
+    Err(e) => res.push(format!("journal replay failed: {}", e.message(Lang::En))),
+  }
+  res
+}
+
+/// Check every customer; fails if any problem is found
+pub fn verify(config: &Config) -> Result<(), Box<dyn Error>> {
+  let customers = load_customers(config)?;
+  let journal_path = config.journal_path();
+  let events = match journal_path.exists() {
+    true => journal::read_events(&journal_path)?,
+    false => Vec::new(),
+  };
+  let mut problems = 0;
+  for customer in &customers {
+    for problem in verify_customer(customer, &events) {
+      println!("customer {}: {}", customer.customer_id, problem);
+      problems += 1;
+    }
+  }
+  // Journaled customers missing from the database
+  let mut journaled = events.iter().map(|e| e.customer_id).collect::<Vec<u32>>();
+  journaled.sort_unstable();
+  journaled.dedup();
+  for customer_id in journaled {
+    if !customers.iter().any(|c| c.customer_id == customer_id) {
+      println!("customer {}: journaled but not stored", customer_id);
+      problems += 1;
+    }
+  }
+  println!("{} customers, {} problems", customers.len(), problems);
+  match problems {
+    0 => Ok(()),
+    _ => Err(format!("Verification failed with {} problems", problems).into()),
+  }
+}
+
+/// Export to the given file or to stdout
+pub fn export(config: &Config, args: ExportArgs) -> Result<(), Box<dyn Error>> {
+  let customers = load_customers(config)?
+    .into_iter()
+    .map(migrated)
+    .collect::<Vec<Customer>>();
+  match args.output {
+    Some(path) => {
+      let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+      export::write(&mut file, &customers, &args.filter, args.format, Utc::now())?;
+      file.flush()?;
+    }
+    None => {
+      let stdout = std::io::stdout();
+      let mut out = stdout.lock();
+      export::write(&mut out, &customers, &args.filter, args.format, Utc::now())?;
+    }
+  }
+  Ok(())
+}
+
+/// Import commitments from a CSV file and print the row results
+pub async fn import(
+  config: &Config,
+  path: &Path,
+  apply: bool,
+  replace_active: bool,
+) -> Result<(), Box<dyn Error>> {
+  let rows = import::parse_csv(&std::fs::read_to_string(path)?, Lang::default());
+  let store = Store::open(&config.db_path(), &config.journal_path())?;
+  let policy = config.policy()?;
+  let results = import::import(
+    &store,
+    &policy,
+    rows,
+    !apply,
+    replace_active,
+    Lang::default(),
+  )
+  .await;
+  for r in &results {
+    match &r.result {
+      Ok(Some(commitment_id)) => println!("{}\t{}\tOK\t{}", r.row, r.customer_id, commitment_id),
+      Ok(None) => println!("{}\t{}\tOK", r.row, r.customer_id),
+      Err(e) => println!(
+        "{}\t{}\tERROR\t{}\t{}",
+        r.row, r.customer_id, e.code, e.message
+      ),
+    }
+  }
+  let failed = results.iter().filter(|r| r.result.is_err()).count();
+  println!(
+    "{}: {} OK, {} ERROR",
+    match apply {
+      true => "Import",
+      false => "Dry-run",
+    },
+    results.len() - failed,
+    failed
+  );
+  Ok(())
+}
+
+/// Recalculate balances of the customers stored in the legacy format
+/// by moving their purchase logs into the purchase registry
+/// Balances of the other customers are derived from the registry
+pub fn recalc_balances(config: &Config, apply: bool) -> Result<(), Box<dyn Error>> {
+  let mut db = store::load_db(&config.db_path())?;
+  let mut recalculated = 0;
+  for customer in db.as_vec_mut().iter_mut() {
+    if !customer.unpack().needs_migration() {
+      continue;
+    }
+    let mismatches = match apply {
+      true => customer.as_mut().unpack().migrate(),
+      false => customer.unpack().clone().migrate(),
+    };
+    for mismatch in mismatches {
+      println!("Balance mismatch: {}", mismatch);
+    }
+    recalculated += 1;
+  }
+  println!(
+    "{} customers {}",
+    recalculated,
+    match apply {
+      true => "recalculated",
+      false => "to recalculate",
+    }
+  );
+  Ok(())
+}
+
+/// Rebuild customers purely from their journal events
+/// Stored customers differing from their replay are reported,
+/// and replaced by it if apply; customers missing from the
+/// journal cannot be rebuilt, so they are kept as is
+pub fn rebuild(config: &Config, apply: bool) -> Result<(), Box<dyn Error>> {
+  let mut events_by_customer: BTreeMap<u32, Vec<Event>> = BTreeMap::new();
+  for event in journal::read_events(&config.journal_path())? {
+    events_by_customer
+      .entry(event.customer_id)
+      .or_default()
+      .push(event);
+  }
+  let mut db = store::load_db(&config.db_path())?;
+  let mut rebuilt = 0;
+  let mut failed = 0;
+  for (customer_id, events) in &events_by_customer {
+    let customer = match journal::rebuild_customer(*customer_id, events) {
+      Ok(customer) => customer,
+      Err(e) => {
+        println!(
+          "customer {}: journal replay failed: {}",
+          customer_id,
+          e.message(Lang::En)
+        );
+        failed += 1;
+        continue;
+      }
+    };
+    match db.find_id_mut(customer_id) {
+      Ok(stored) => {
+        if serde_json::to_string(stored.unpack())? == serde_json::to_string(&customer)? {
+          continue;
+        }
+        println!("customer {}: differs from its journal replay", customer_id);
+        if apply {
+          *stored.as_mut().unpack() = customer;
+        }
+      }
+      Err(_) => {
+        println!("customer {}: journaled but not stored", customer_id);
+        if apply {
+          db.insert(customer)
+            .map_err(|e| format!("Error while saving customer {}: {}", customer_id, e))?;
+        }
+      }
+    }
+    rebuilt += 1;
+  }
+  for customer in db.iter() {
+    let customer_id = customer.unpack().customer_id;
+    if !events_by_customer.contains_key(&customer_id) {
+      println!("customer {}: not journaled, kept as is", customer_id);
+    }
+  }
+  println!(
+    "{} customers {}",
+    rebuilt,
+    match apply {
+      true => "rebuilt",
+      false => "to rebuild",
+    }
+  );
+  match failed {
+    0 => Ok(()),
+    _ => Err(format!("Journal replay failed for {} customers", failed).into()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::commitment::{CommitmentOptions, PurchaseInfo};
+  use crate::legacy;
+  use crate::policy::Policy;
+  use uuid::Uuid;
+
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|a| a.to_string()).collect()
+  }
+
+  #[test]
+  fn test_parse() {
+    assert!(matches!(Command::parse(&[]), Ok(Command::Serve)));
+    assert!(matches!(
+      Command::parse(&args(&["inspect", "12"])),
+      Ok(Command::Inspect(12))
+    ));
+    assert!(Command::parse(&args(&["inspect"])).is_err());
+    assert!(matches!(
+      Command::parse(&args(&["recalc-balances", "--apply"])),
+      Ok(Command::RecalcBalances { apply: true })
+    ));
+    assert!(matches!(
+      Command::parse(&args(&["rebuild"])),
+      Ok(Command::Rebuild { apply: false })
+    ));
+    assert!(matches!(
+      Command::parse(&args(&["import", "--file", "a.csv"])),
+      Ok(Command::Import { apply: false, .. })
+    ));
+    assert!(Command::parse(&args(&["import"])).is_err());
+    assert!(Command::parse(&args(&["list", "--all"])).is_err());
+    assert!(Command::parse(&args(&["drop"])).is_err());
+  }
+
+  #[test]
+  fn test_verify_customer() {
+    let mut customer = Customer::new(
+      1,
+      1000,
+      2,
+      1,
+      CommitmentOptions::default(),
+      &Policy::default(),
+    )
+    .unwrap();
+    let id = customer.commitments[0].commitment_id;
+    customer
+      .add_purchase(id, PurchaseInfo::new(Uuid::new_v4(), 100, 127, 2))
+      .unwrap();
+    let events = journal::snapshot_events(&customer);
+    assert!(verify_customer(&customer, &events).is_empty());
+
+    // Purchase missing from the journal
+    customer
+      .add_purchase(id, PurchaseInfo::new(Uuid::new_v4(), 100, 127, 2))
+      .unwrap();
+    assert_eq!(
+      verify_customer(&customer, &events),
+      vec!["differs from its journal replay".to_string()]
+    );
+
+    // Broken withdrawal chain
+    customer.commitments[0].status = CommitmentStatus::Withdrawn {
+      successor: Uuid::new_v4(),
+    };
+    let events = journal::snapshot_events(&customer);
+    assert_eq!(verify_customer(&customer, &events).len(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_rebuild() {
+    let config = Config {
+      data_dir: std::env::temp_dir().join(format!("commitment_test_{}", Uuid::new_v4())),
+      ..Config::default()
+    };
+    let store = Store::open(&config.db_path(), &config.journal_path()).unwrap();
+    store
+      .upsert(1, |customer| {
+        customer.add_commitment(1000, 2, 1, CommitmentOptions::default(), &Policy::default())?;
+        Ok(((), journal::commitment_events(customer, 0)))
+      })
+      .await
+      .unwrap();
+    let customer = store.get(1).unwrap();
+    drop(store);
+
+    // Stored customer lost its commitment
+    let target = |config: &Config| {
+      let db = store::load_db(&config.db_path()).unwrap();
+      let target = db.find_id(&1).unwrap().unpack().commitments[0].target;
+      target
+    };
+    {
+      let mut db = store::load_db(&config.db_path()).unwrap();
+      db.find_id_mut(&1).unwrap().as_mut().unpack().commitments[0].target = 1;
+    }
+    rebuild(&config, false).unwrap();
+    assert_eq!(target(&config), 1);
+    rebuild(&config, true).unwrap();
+    assert_eq!(target(&config), customer.commitments[0].target);
+  }
+
+  #[test]
+  fn test_recalc_balances_v0() {
+    let config = Config {
+      data_dir: std::env::temp_dir().join(format!("commitment_test_{}", Uuid::new_v4())),
+      ..Config::default()
+    };
+    let v0 = legacy::tests::customer_v0(1);
+    legacy::tests::save_v0(&config.db_path(), &v0);
+    // Server refuses to open legacy purchase logs
+    assert!(Store::open(&config.db_path(), &config.journal_path()).is_err());
+    recalc_balances(&config, false).unwrap();
+    assert!(load_customers(&config).unwrap()[0].needs_migration());
+
+    recalc_balances(&config, true).unwrap();
+    let customer = &load_customers(&config).unwrap()[0];
+    assert!(!customer.needs_migration());
+    // Copies are stored once, under the commitment they were added to
+    assert_eq!(customer.purchases.len(), 2);
+    let first_id = v0.commitments[0].commitment_id;
+    assert!(customer
+      .purchases
+      .values()
+      .all(|pi| pi.commitment_id == first_id));
+    let views = customer.commitment_views();
+    for (view, c) in views.iter().zip(&v0.commitments) {
+      assert_eq!(view.balance, c.balance);
+      assert_eq!(view.purchase_log.len(), 2);
+      assert!(view.purchase_log[1].removed);
+    }
+    assert!(Store::open(&config.db_path(), &config.journal_path()).is_ok());
+  }
+}
@@ -1,50 +1,106 @@
-use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use crate::discount;
+use crate::error::DomainError;
+use crate::policy::{Policy, ValidityPeriod};
+use chrono::{DateTime, Utc};
+use indexmap::IndexMap;
 use packman::*;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use uuid::Uuid;
 
+/// User ID recorded as created_by for
+/// commitments created by the service itself
+/// Out of the range of real user IDs, 0 included
+pub const SYSTEM_UID: u32 = u32::MAX;
+
 pub trait CustomerExt
 where
   Self: Sized,
 {
-  /// Create new customer commitment object
-  fn new(
-    customer_id: u32,
-    target: u32,
-    new_discount_percentage: u32,
-    created_by: u32,
-  ) -> Result<Self, String>;
   /// Add purchase to a customer commitment
+  /// Its applied discount must match the commitment discount,
+  /// or its category rates with category breakdown
   fn add_purchase(
     &mut self,
     commitment_id: Uuid,
     purchase: PurchaseInfo,
-  ) -> Result<&Commitment, String>;
-  /// Remove purchase from all customer commitment
+  ) -> Result<Commitment, DomainError>;
+  /// Register purchase under the given commitment
+  /// without checking whether it is active
+  /// Its removal states are kept as given
+  /// Should be used only to replay history
+  fn insert_purchase(
+    &mut self,
+    commitment_id: Uuid,
+    purchase: PurchaseInfo,
+  ) -> Result<Commitment, DomainError>;
+  /// Remove purchase from the given commitment and all of its successors
+  /// Successors removed on their own keep their removal
+  /// Idempotent: removing an already removed purchase
+  /// leaves balances untouched and reports AlreadyRemoved
+  /// Returns the last successor
   fn remove_purchase(
     &mut self,
     commitment_id: Uuid,
     purchase_id: &Uuid,
-  ) -> Result<&Commitment, String>;
+    removal: &RemovalInfo,
+  ) -> Result<(Commitment, PurchaseRemoval), DomainError>;
+  /// Restore a removed purchase in the given commitment
+  /// and all of its successors
+  /// Returns the last successor
+  fn restore_purchase(
+    &mut self,
+    commitment_id: Uuid,
+    purchase_id: &Uuid,
+  ) -> Result<(Commitment, PurchaseRestore), DomainError>;
   /// Add new commitment
+  /// It withdraws the active commitment, or the upcoming one
+  /// following it; the withdrawal takes effect at once
+  /// Validity period is defined by the policy if not provided
+  /// Withdrawal copies forward the category rules
+  /// and the balance basis if not provided
   fn add_commitment(
     &mut self,
     new_target: u32,
     new_discount_percentage: u32,
     created_by: u32,
-  ) -> Result<&Self, String>;
+    options: CommitmentOptions,
+    policy: &Policy,
+  ) -> Result<&Self, DomainError>;
+  /// Mark a commitment withdrawn by its successor
+  /// Should be used only to replay history
+  fn set_withdrawn(&mut self, commitment_id: &Uuid, successor: Uuid) -> Result<(), DomainError>;
   /// Check whether customer has a given commitment ID
   fn has_commitment(&self, commitment_id: &Uuid) -> bool;
-  /// Try to get commitment as mut ref
-  fn get_commitment(&self, commitment_id: &Uuid) -> Result<&Commitment, String>;
-  /// Try to get commitment as mut ref
-  fn get_commitment_mut(&mut self, commitment_id: &Uuid) -> Result<&mut Commitment, String>;
-  /// Return Some(&Self) if there is active commitment
-  fn get_active_commitment(&self) -> Option<&Commitment>;
-  /// Return Some(&mut Self) if there is active commitment
+  /// Try to get commitment with its purchase log and balance
+  fn get_commitment(&self, commitment_id: &Uuid) -> Result<Commitment, DomainError>;
+  /// Try to get commitment record as mut ref
+  fn get_commitment_mut(&mut self, commitment_id: &Uuid) -> Result<&mut Commitment, DomainError>;
+  /// Return Some(Commitment) with its purchase log and balance
+  /// if there is active commitment
+  fn get_active_commitment(&self) -> Option<Commitment>;
+  /// Return Some(&mut Commitment) record if there is active commitment
   fn get_active_commitment_mut(&mut self) -> Option<&mut Commitment>;
   /// Has active commitment
   fn has_active_commitment(&self) -> bool;
+  /// All commitments with their purchase log and balance
+  fn commitment_views(&self) -> Vec<Commitment>;
+  /// Commitment IDs of the withdrawal chain till the given one
+  /// Oldest first, including the given one
+  fn predecessors(&self, commitment_id: &Uuid) -> Vec<Uuid>;
+  /// Commitment IDs of the withdrawal chain from the given one
+  /// Including the given one, newest last
+  fn successors(&self, commitment_id: &Uuid) -> Vec<Uuid>;
+  /// Removal of a registered purchase in the last successor
+  /// of the commitment it was added to, if removed
+  fn current_removal<'a>(&self, purchase: &'a PurchaseInfo) -> Option<&'a RemovalState>;
+  /// true if there are purchase logs stored in commitments
+  /// in the legacy format
+  fn needs_migration(&self) -> bool;
+  /// Move legacy commitment purchase logs into the purchase registry
+  /// Returns the commitments whose stored balance differed
+  /// from the sum of its non-removed purchases
+  fn migrate(&mut self) -> Vec<BalanceMismatch>;
 }
 
 pub trait CommitmentExt
@@ -52,43 +108,57 @@ where
   Self: Sized,
 {
   /// Try to create new commitment
+  /// Discount is validated against the policy in force
+  /// Validity period is defined by the policy validity model
+  /// if no explicit period is provided
   fn new(
     customer_id: u32,
     target: u32,
     discount_percentage: u32,
     created_by: u32,
-  ) -> Result<Self, String>;
+    options: CommitmentOptions,
+    policy: &Policy,
+  ) -> Result<Self, DomainError>;
   /// Try withdrawn a commitment
   /// Don't forget to add new commitment to the customers commitments
+  /// The new commitment inherits the validity period,
+  /// the category rules and the balance basis if not provided
   fn withdraw(
     &mut self,
     new_target: u32,
     new_discount_percentage: u32,
     created_by: u32,
-  ) -> Result<Self, String>;
-  /// Add purchase info into commitment
-  fn add_purchase(&mut self, purchase: PurchaseInfo) -> Result<&Self, String>;
-  /// Remove purchase info from commitment
-  fn remove_purchase(&mut self, purchase_id: &Uuid) -> Result<&Self, String>;
+    options: CommitmentOptions,
+    policy: &Policy,
+  ) -> Result<Self, DomainError>;
+  /// Discount percentage of the given product category
+  fn category_discount(&self, category: &str) -> u32;
+  /// true if the given product category is excluded
+  fn is_excluded(&self, category: &str) -> bool;
+  /// Eligible net or gross amount of a purchase
+  /// depending on the balance basis
+  /// Excluded categories are not counted
+  fn eligible_amount(&self, purchase: &PurchaseInfo) -> u32;
   /// true if time and withdraw ok
   fn is_active(&self) -> bool;
+  /// true if the given time is inside the validity period
+  /// Start is inclusive, end is exclusive
+  fn is_valid_at(&self, at: DateTime<Utc>) -> bool;
+  /// Validity period of the commitment
+  fn validity_period(&self) -> ValidityPeriod;
   /// true if withdrawn
   fn is_withdrawn(&self) -> bool;
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct Customer {
   pub customer_id: u32,
   pub commitments: Vec<Commitment>,
-}
-
-impl Default for Customer {
-  fn default() -> Self {
-    Self {
-      customer_id: 0,
-      commitments: Vec::default(),
-    }
-  }
+  // Purchase registry by purchase ID, in the order of adding;
+  // each purchase is stored once and belongs to the commitment
+  // it was added to and to all of its successors
+  #[serde(default)]
+  pub purchases: IndexMap<Uuid, PurchaseInfo>,
 }
 
 impl VecPackMember for Customer {
@@ -99,13 +169,160 @@ impl VecPackMember for Customer {
   }
 }
 
-impl CustomerExt for Customer {
-  fn new(
+impl Customer {
+  // Build commitment with its purchase log and balance
+  // from the purchase registry and the balances of the chains
+  fn commitment_view(&self, commitment: &Commitment, balances: &HashMap<Uuid, u64>) -> Commitment {
+    let chain = self.predecessors(&commitment.commitment_id);
+    let mut res = commitment.clone();
+    res.purchase_log = self
+      .purchases
+      .values()
+      // Purchases added to this commitment or to any of its predecessors
+      .filter(|pi| chain.contains(&pi.commitment_id))
+      .map(|pi| {
+        let mut view = pi.clone();
+        view.removals = Vec::new();
+        // Removed in this commitment, or in the nearest
+        // predecessor having its own removal state
+        match pi.removal_in(&chain) {
+          Some(state) => view.set_removed(state),
+          None => view.set_restored(),
+        };
+        view
+      })
+      .collect();
+    let balance = balances
+      .get(&commitment.commitment_id)
+      .copied()
+      .unwrap_or(0);
+    // Mutations are checked against overflow, so only
+    // corrupted records can get here; saturated and logged
+    if balance > u64::from(u32::MAX) {
+      tracing::warn!(
+        customer_id = self.customer_id,
+        commitment_id = %commitment.commitment_id,
+        balance,
+        "Commitment balance overflow"
+      );
+    }
+    res.balance = balance.min(u64::from(u32::MAX)) as u32;
+    res
+  }
+
+  // Commitments by ID
+  fn commitment_index(&self) -> HashMap<Uuid, &Commitment> {
+    self
+      .commitments
+      .iter()
+      .map(|c| (c.commitment_id, c))
+      .collect()
+  }
+
+  // Balance of every commitment in one pass over the registry;
+  // each purchase is counted by the rules and balance basis of the
+  // commitment it was added to, in that one and in its successors
+  // while it is not removed there
+  fn balances(&self) -> HashMap<Uuid, u64> {
+    let index = self.commitment_index();
+    let mut res: HashMap<Uuid, u64> = index.keys().map(|id| (*id, 0)).collect();
+    for pi in self.purchases.values() {
+      let Some(added_to) = index.get(&pi.commitment_id) else {
+        continue;
+      };
+      let amount = u64::from(added_to.eligible_amount(pi));
+      let mut removed = false;
+      for id in successors_in(&index, &pi.commitment_id) {
+        // Own removal state of the commitment, inherited otherwise
+        if let Some(state) = pi.removals.iter().find(|s| s.commitment_id == id) {
+          removed = state.removed;
+        }
+        if !removed {
+          *res.entry(id).or_default() += amount;
+        }
+      }
+    }
+    res
+  }
+
+  // Balances of all the commitments must not overflow
+  fn check_balance_overflow(&self) -> Result<(), DomainError> {
+    match self.balances().values().all(|b| *b <= u64::from(u32::MAX)) {
+      true => Ok(()),
+      false => Err(DomainError::BalanceOverflow),
+    }
+  }
+
+  // Derived balances differing from the sum of the
+  // non-removed registry purchases of their chains
+  fn registry_mismatches(&self) -> Vec<BalanceMismatch> {
+    let index = self.commitment_index();
+    self
+      .commitment_views()
+      .into_iter()
+      .filter_map(|view| {
+        let chain = self.predecessors(&view.commitment_id);
+        let expected: u64 = self
+          .purchases
+          .values()
+          .filter(|pi| chain.contains(&pi.commitment_id) && pi.removal_in(&chain).is_none())
+          .filter_map(|pi| index.get(&pi.commitment_id).map(|c| c.eligible_amount(pi)))
+          .map(u64::from)
+          .sum();
+        match u64::from(view.balance) == expected {
+          true => None,
+          false => Some(BalanceMismatch {
+            customer_id: self.customer_id,
+            commitment_id: view.commitment_id,
+            balance: view.balance,
+            expected: expected.min(u64::from(u32::MAX)) as u32,
+          }),
+        }
+      })
+      .collect()
+  }
+
+  // Registered purchase visible from the given chain
+  fn purchase_mut(
+    &mut self,
+    chain: &[Uuid],
+    purchase_id: &Uuid,
+  ) -> Result<&mut PurchaseInfo, DomainError> {
+    self
+      .purchases
+      .get_mut(purchase_id)
+      .filter(|pi| chain.contains(&pi.commitment_id))
+      .ok_or(DomainError::PurchaseNotFound)
+  }
+
+  // Index of the active commitment if any
+  fn active_index(&self) -> Option<usize> {
+    self.commitments.iter().rposition(|c| c.is_active())
+  }
+
+  // Last successor of the given commitment with its purchase log
+  fn last_successor(&self, commitment_id: &Uuid) -> Result<Commitment, DomainError> {
+    let last = self
+      .successors(commitment_id)
+      .last()
+      .cloned()
+      .unwrap_or(*commitment_id);
+    self.get_commitment(&last)
+  }
+}
+
+// The service creates customers by upsert in the store
+#[cfg(test)]
+impl Customer {
+  /// Create new customer commitment object
+  pub fn new(
     customer_id: u32,
     target: u32,
     discount_percentage: u32,
     created_by: u32,
-  ) -> Result<Self, String> {
+    options: CommitmentOptions,
+    policy: &Policy,
+  ) -> Result<Self, DomainError> {
     Ok(Self {
       customer_id,
       commitments: vec![Commitment::new(
@@ -113,70 +330,136 @@ impl CustomerExt for Customer {
         target,
         discount_percentage,
         created_by,
+        options,
+        policy,
       )?],
+      purchases: IndexMap::new(),
     })
   }
+}
 
+impl CustomerExt for Customer {
   fn add_purchase(
     &mut self,
     commitment_id: Uuid,
     purchase: PurchaseInfo,
-  ) -> Result<&Commitment, String> {
+  ) -> Result<Commitment, DomainError> {
     // Check if commitment ID is under the customer
     if !self.has_commitment(&commitment_id) {
-      return Err("A megadott commitment ID nem szerepel a vásárlónál!".to_string());
+      return Err(DomainError::CommitmentNotFound);
     }
     // Check if the required commitment is active
     match self.get_active_commitment_mut() {
       Some(active_commitment) => match active_commitment.commitment_id == commitment_id {
         // If active_commitment is the required one
         true => {
-          let c = active_commitment.add_purchase(purchase)?;
-          return Ok(c);
+          purchase.validate_categories()?;
+          // Reported discount must be the one of the commitment
+          // and its category rules
+          let expected = discount::purchase_discount_percentage(active_commitment, &purchase);
+          if purchase.applied_discount != expected {
+            return Err(DomainError::DiscountMismatch {
+              applied: purchase.applied_discount,
+              expected,
+            });
+          }
+          self.insert_purchase(commitment_id, purchase)
         }
         // If active commitment is not the required one
-        false => return Err("A megadott commitment helyett már van újabb.".to_string()),
+        false => Err(DomainError::CommitmentSuperseded),
       },
-      None => Err(
-        "A megadott vásárlónak nincs aktív commitmentje, így a vásárlás nem adható hozzá."
-          .to_string(),
-      ),
+      None => Err(DomainError::NoActiveCommitment),
     }
   }
 
+  fn insert_purchase(
+    &mut self,
+    commitment_id: Uuid,
+    mut purchase: PurchaseInfo,
+  ) -> Result<Commitment, DomainError> {
+    if self.purchases.contains_key(&purchase.purchase_id) {
+      return Err(DomainError::PurchaseAlreadyExists);
+    }
+    if !self.has_commitment(&commitment_id) {
+      return Err(DomainError::CommitmentNotFound);
+    }
+    purchase.commitment_id = commitment_id;
+    // Registry keeps removal in its states only
+    purchase.set_restored();
+    let purchase_id = purchase.purchase_id;
+    self.purchases.insert(purchase_id, purchase);
+    // Roll back on balance overflow
+    if let Err(e) = self.check_balance_overflow() {
+      self.purchases.shift_remove(&purchase_id);
+      return Err(e);
+    }
+    self.get_commitment(&commitment_id)
+  }
+
   fn remove_purchase(
     &mut self,
     commitment_id: Uuid,
     purchase_id: &Uuid,
-  ) -> Result<&Commitment, String> {
-    // Try to get the required commitment
-    let commitment_status = self.get_commitment(&commitment_id)?.status.clone();
-    // Try to remove the required purchase
-    match commitment_status {
-      // If its a valid commitment
-      // simply remove the required purchase
-      // and return self ref
-      CommitmentStatus::Valid => {
-        // Remove purchase
-        let c = self
-          .get_commitment_mut(&commitment_id)?
-          .remove_purchase(purchase_id)?;
-        // Return self ref
-        Ok(c)
-      }
-      // If its a withdrawn commitment
-      // then remove the required purchase and recursively remove
-      // from all of its successors
-      CommitmentStatus::Withdrawn { successor } => {
-        // Remove purchase from this withdrawn commitment
-        let _ = self
-          .get_commitment_mut(&commitment_id)?
-          .remove_purchase(purchase_id);
-        // And recursively remove from all of its
-        // successors
-        self.remove_purchase(successor, purchase_id)
+    removal: &RemovalInfo,
+  ) -> Result<(Commitment, PurchaseRemoval), DomainError> {
+    let chain = self.predecessors(&commitment_id);
+    let successors = self.successors(&commitment_id);
+    let purchase = self.purchase_mut(&chain, purchase_id)?;
+    let outcome = match purchase.removal_in(&chain) {
+      // Already removed from this commitment or from a predecessor,
+      // balances must not change again
+      Some(_) => PurchaseRemoval::AlreadyRemoved,
+      // Removing from here covers all the successors
+      None => {
+        purchase.set_state(RemovalState::removed(commitment_id, removal));
+        // Successors restored on their own are removed again,
+        // the ones removed on their own keep their removal
+        for state in purchase
+          .removals
+          .iter_mut()
+          .filter(|s| !s.removed && successors.contains(&s.commitment_id))
+        {
+          *state = RemovalState::removed(state.commitment_id, removal);
+        }
+        PurchaseRemoval::Removed
       }
+    };
+    Ok((self.last_successor(&commitment_id)?, outcome))
+  }
+
+  fn restore_purchase(
+    &mut self,
+    commitment_id: Uuid,
+    purchase_id: &Uuid,
+  ) -> Result<(Commitment, PurchaseRestore), DomainError> {
+    let chain = self.predecessors(&commitment_id);
+    let successors = self.successors(&commitment_id);
+    let purchase = self.purchase_mut(&chain, purchase_id)?;
+    if purchase.removal_in(&chain).is_none() {
+      return Ok((
+        self.last_successor(&commitment_id)?,
+        PurchaseRestore::NotRemoved,
+      ));
+    }
+    let previous = purchase.removals.clone();
+    // Restored in this commitment and all of its successors,
+    // predecessors are left untouched
+    purchase
+      .removals
+      .retain(|s| !successors.contains(&s.commitment_id));
+    // Still removed by a predecessor, so it is restored here on its own
+    if purchase.removal_in(&chain).is_some() {
+      purchase.set_state(RemovalState::restored(commitment_id));
     }
+    // Roll back on balance overflow
+    if let Err(e) = self.check_balance_overflow() {
+      self.purchase_mut(&chain, purchase_id)?.removals = previous;
+      return Err(e);
+    }
+    Ok((
+      self.last_successor(&commitment_id)?,
+      PurchaseRestore::Restored,
+    ))
   }
 
   fn add_commitment(
@@ -184,17 +467,30 @@ impl CustomerExt for Customer {
     new_target: u32,
     new_discount_percentage: u32,
     created_by: u32,
-  ) -> Result<&Self, String> {
-    // Check whether we have an active to withdraw
-    // or simple create a new one
-    match self.get_active_commitment_mut() {
-      // If we have Some(active_commitment) then
+    options: CommitmentOptions,
+    policy: &Policy,
+  ) -> Result<&Self, DomainError> {
+    // Check whether we have an active or an upcoming one
+    // to withdraw or simple create a new one
+    // An upcoming one is always the last, following the active one
+    let now = Utc::now();
+    match self
+      .commitments
+      .last_mut()
+      .filter(|c| !c.is_withdrawn() && now < c.valid_till)
+    {
+      // If we have Some(current) then
       // try to withdraw it and add the new commitment
-      Some(active_commitment) => {
+      Some(current) => {
         // Try to withdraw it
-        let new_commitment =
-          active_commitment.withdraw(new_target, new_discount_percentage, created_by)?;
-        // Push new active commitment
+        let new_commitment = current.withdraw(
+          new_target,
+          new_discount_percentage,
+          created_by,
+          options,
+          policy,
+        )?;
+        // Push new commitment
         self.commitments.push(new_commitment);
         // Return self ref
         Ok(self)
@@ -207,38 +503,28 @@ impl CustomerExt for Customer {
           new_target,
           new_discount_percentage,
           created_by,
+          options,
+          policy,
         )?);
         Ok(self)
       }
     }
   }
 
-  fn get_active_commitment(&self) -> Option<&Commitment> {
-    // If there is any commitment
-    if let Some(last) = self.commitments.last() {
-      // Check if last is active
-      return match last.is_active() {
-        // Return mut ref if yes
-        true => Some(last),
-        // None if last is not active
-        false => None,
-      };
-    }
-    None
+  fn set_withdrawn(&mut self, commitment_id: &Uuid, successor: Uuid) -> Result<(), DomainError> {
+    self.get_commitment_mut(commitment_id)?.status = CommitmentStatus::Withdrawn { successor };
+    Ok(())
+  }
+
+  fn get_active_commitment(&self) -> Option<Commitment> {
+    // Return it with its purchases if any
+    let i = self.active_index()?;
+    Some(self.commitment_view(&self.commitments[i], &self.balances()))
   }
 
   fn get_active_commitment_mut(&mut self) -> Option<&mut Commitment> {
-    // If there is any commitment
-    if let Some(last) = self.commitments.last_mut() {
-      // Check if last is active
-      return match last.is_active() {
-        // Return mut ref if yes
-        true => Some(last),
-        // None if last is not active
-        false => None,
-      };
-    }
-    None
+    let i = self.active_index()?;
+    self.commitments.get_mut(i)
   }
 
   fn has_commitment(&self, commitment_id: &Uuid) -> bool {
@@ -248,60 +534,319 @@ impl CustomerExt for Customer {
       .any(|c| c.commitment_id == *commitment_id)
   }
 
-  fn get_commitment(&self, commitment_id: &Uuid) -> Result<&Commitment, String> {
+  fn get_commitment(&self, commitment_id: &Uuid) -> Result<Commitment, DomainError> {
     for c in &self.commitments {
       if c.commitment_id == *commitment_id {
-        return Ok(c);
+        return Ok(self.commitment_view(c, &self.balances()));
       }
     }
-    Err("A megadott commitment ID nem található a vásárló alatt.".to_string())
+    Err(DomainError::CommitmentNotFound)
   }
 
-  fn get_commitment_mut(&mut self, commitment_id: &Uuid) -> Result<&mut Commitment, String> {
+  fn get_commitment_mut(&mut self, commitment_id: &Uuid) -> Result<&mut Commitment, DomainError> {
     for c in &mut self.commitments {
       if c.commitment_id == *commitment_id {
         return Ok(c);
       }
     }
-    Err("A megadott commitment ID nem található a vásárló alatt.".to_string())
+    Err(DomainError::CommitmentNotFound)
   }
 
   fn has_active_commitment(&self) -> bool {
-    match self.get_active_commitment() {
-      Some(_) => true,
-      None => false,
+    self.active_index().is_some()
+  }
+
+  fn commitment_views(&self) -> Vec<Commitment> {
+    let balances = self.balances();
+    self
+      .commitments
+      .iter()
+      .map(|c| self.commitment_view(c, &balances))
+      .collect()
+  }
+
+  fn predecessors(&self, commitment_id: &Uuid) -> Vec<Uuid> {
+    // Commitments by the successor they were withdrawn by
+    let withdrawn_by: HashMap<Uuid, Uuid> = self
+      .commitments
+      .iter()
+      .filter_map(|c| match c.status {
+        CommitmentStatus::Withdrawn { successor } => Some((successor, c.commitment_id)),
+        CommitmentStatus::Valid => None,
+      })
+      .collect();
+    let mut res = vec![*commitment_id];
+    let mut current = *commitment_id;
+    while let Some(p) = withdrawn_by.get(&current) {
+      // Avoid looping on corrupted data
+      if res.contains(p) {
+        break;
+      }
+      current = *p;
+      res.push(current);
+    }
+    res.reverse();
+    res
+  }
+
+  fn successors(&self, commitment_id: &Uuid) -> Vec<Uuid> {
+    successors_in(&self.commitment_index(), commitment_id)
+  }
+
+  fn current_removal<'a>(&self, purchase: &'a PurchaseInfo) -> Option<&'a RemovalState> {
+    let last = self
+      .successors(&purchase.commitment_id)
+      .last()
+      .cloned()
+      .unwrap_or(purchase.commitment_id);
+    purchase.removal_in(&self.predecessors(&last))
+  }
+
+  fn needs_migration(&self) -> bool {
+    self
+      .commitments
+      .iter()
+      .any(|c| !c.purchase_log.is_empty() || c.balance != 0)
+  }
+
+  fn migrate(&mut self) -> Vec<BalanceMismatch> {
+    let mut stored_balances = Vec::new();
+    // Commitments are in creation order, so predecessors
+    // are processed before their successors
+    for i in 0..self.commitments.len() {
+      let commitment_id = self.commitments[i].commitment_id;
+      let chain = self.predecessors(&commitment_id);
+      let log = std::mem::take(&mut self.commitments[i].purchase_log);
+      stored_balances.push((commitment_id, self.commitments[i].balance));
+      self.commitments[i].balance = 0;
+      for pi in log {
+        // First appearance, it was added to this commitment
+        let p = self.purchases.entry(pi.purchase_id).or_insert_with(|| {
+          let mut p = pi.clone();
+          p.commitment_id = commitment_id;
+          p.set_restored();
+          p
+        });
+        // Copies have their own removal; it is stored
+        // if it differs from the one of the predecessors
+        let state = RemovalState::from_copy(commitment_id, &pi);
+        if p.removal_in(&chain).is_some() != state.removed {
+          p.set_state(state);
+        }
+      }
     }
+    // Compare stored balances to the derived ones
+    stored_balances
+      .into_iter()
+      .filter_map(|(commitment_id, balance)| {
+        let expected = self.get_commitment(&commitment_id).ok()?.balance;
+        match balance == expected {
+          true => None,
+          false => Some(BalanceMismatch {
+            customer_id: self.customer_id,
+            commitment_id,
+            balance,
+            expected,
+          }),
+        }
+      })
+      .collect()
   }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+// Withdrawal chain from the given commitment, newest last
+fn successors_in(index: &HashMap<Uuid, &Commitment>, commitment_id: &Uuid) -> Vec<Uuid> {
+  let mut res = vec![*commitment_id];
+  let mut current = *commitment_id;
+  while let Some(CommitmentStatus::Withdrawn { successor }) = index.get(&current).map(|c| &c.status)
+  {
+    // Avoid looping on corrupted data
+    if res.contains(successor) {
+      break;
+    }
+    current = *successor;
+    res.push(current);
+  }
+  res
+}
+
+/// Check balance invariant over all the given customers
+/// Should be used at startup to report historically
+/// corrupted balances; stored balances of legacy records
+/// and derived balances of the purchase registry are checked
+pub fn check_balances<'a>(customers: impl Iterator<Item = &'a Customer>) -> Vec<BalanceMismatch> {
+  customers
+    .flat_map(|c| match c.needs_migration() {
+      true => c.clone().migrate(),
+      false => c.registry_mismatches(),
+    })
+    .collect()
+}
+
+/// gRPC response metadata key of the removal outcome
+pub const REMOVAL_OUTCOME_KEY: &str = "removal-outcome";
+
+/// Outcome of a logical purchase removal
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PurchaseRemoval {
+  // Purchase has been removed now
+  Removed,
+  // Purchase was already removed before,
+  // nothing has changed
+  AlreadyRemoved,
+}
+
+impl std::fmt::Display for PurchaseRemoval {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      PurchaseRemoval::Removed => write!(f, "removed"),
+      PurchaseRemoval::AlreadyRemoved => write!(f, "already-removed"),
+    }
+  }
+}
+
+/// gRPC response metadata key of the restore outcome
+pub const RESTORE_OUTCOME_KEY: &str = "restore-outcome";
+
+/// Outcome of restoring a removed purchase
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PurchaseRestore {
+  // Purchase has been restored now
+  Restored,
+  // Purchase was not removed,
+  // nothing has changed
+  NotRemoved,
+}
+
+impl std::fmt::Display for PurchaseRestore {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      PurchaseRestore::Restored => write!(f, "restored"),
+      PurchaseRestore::NotRemoved => write!(f, "not-removed"),
+    }
+  }
+}
+
+/// Commitment whose stored balance differs from
+/// the sum of its non-removed purchases
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceMismatch {
+  pub customer_id: u32,
+  pub commitment_id: Uuid,
+  pub balance: u32,  // Stored balance
+  pub expected: u32, // Sum of non-removed purchases
+}
+
+impl std::fmt::Display for BalanceMismatch {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "customer {} commitment {}: balance {} != expected {}",
+      self.customer_id, self.commitment_id, self.balance, self.expected
+    )
+  }
+}
+
+/// Optional settings of a new commitment
+#[derive(Clone, Default)]
+pub struct CommitmentOptions {
+  pub validity: Option<ValidityPeriod>, // Explicit validity period
+  pub category_rules: Option<Vec<CategoryRule>>, // Product category exceptions
+  pub balance_basis: Option<BalanceBasis>, // Gross if not provided
+}
+
+/// Purchase value counted toward balance
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceBasis {
+  Net,
+  // Commitments created before the setting count gross
+  #[default]
+  Gross,
+}
+
+impl BalanceBasis {
+  /// Net or gross amount
+  pub fn amount(&self, net: u32, gross: u32) -> u32 {
+    match self {
+      BalanceBasis::Net => net,
+      BalanceBasis::Gross => gross,
+    }
+  }
+}
+
+impl std::fmt::Display for BalanceBasis {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      BalanceBasis::Net => write!(f, "net"),
+      BalanceBasis::Gross => write!(f, "gross"),
+    }
+  }
+}
+
+impl std::str::FromStr for BalanceBasis {
+  type Err = DomainError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "net" => Ok(BalanceBasis::Net),
+      "gross" => Ok(BalanceBasis::Gross),
+      _ => Err(DomainError::UnknownBalanceBasis(s.to_string())),
+    }
+  }
+}
+
+/// Discount exception of a product category
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CategoryRule {
+  pub category: String,
+  pub discount: CategoryDiscount,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum CategoryDiscount {
+  // No discount, and not counted toward balance
+  Excluded,
+  // Different discount percentage
+  Rate { discount_percentage: u32 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub enum CommitmentStatus {
   // Commitment should be live if date interval
   // is Ok
+  #[default]
   Valid,
   // Commitment is withdrawn, and it has
   // a successor
-  Withdrawn { successor: Uuid },
-}
-
-impl Default for CommitmentStatus {
-  fn default() -> Self {
-    Self::Valid
-  }
+  Withdrawn {
+    successor: Uuid,
+  },
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Commitment {
-  pub commitment_id: Uuid,             // Unique ID
-  pub customer_id: u32,                // Customer ID
-  pub target: u32,                     // Target total purchase value
-  pub discount_percentage: u32,        // Valid discount percentage
-  pub valid_till: DateTime<Utc>,       // Commitment is valid till
-  pub balance: u32,                    // Commitment balance
+  pub commitment_id: Uuid,      // Unique ID
+  pub customer_id: u32,         // Customer ID
+  pub target: u32,              // Target total purchase value
+  pub discount_percentage: u32, // Valid discount percentage
+  #[serde(default = "default_valid_from")]
+  pub valid_from: DateTime<Utc>, // Commitment is valid from
+  pub valid_till: DateTime<Utc>, // Commitment is valid till
+  #[serde(default)]
+  pub category_rules: Vec<CategoryRule>, // Product category exceptions
+  #[serde(default)]
+  pub balance_basis: BalanceBasis, // Balance counts net or gross value
+  // Balance and purchase log are derived from
+  // the customer purchase registry; stored only
+  // in the legacy format, empty otherwise
+  #[serde(default)]
+  pub balance: u32, // Commitment balance
+  #[serde(default)]
   pub purchase_log: Vec<PurchaseInfo>, // Purchase log
-  pub status: CommitmentStatus,        // Is withdrawn because of any reason?
-  pub created_at: DateTime<Utc>,       // Created at
-  pub created_by: u32,                 // Created by uid
+  pub status: CommitmentStatus,  // Is withdrawn because of any reason?
+  pub created_at: DateTime<Utc>, // Created at
+  pub created_by: u32,           // Created by uid
 }
 
 impl Default for Commitment {
@@ -311,7 +856,10 @@ impl Default for Commitment {
       customer_id: 0,
       target: 0,
       discount_percentage: 0,
+      valid_from: Utc::now(),
       valid_till: Utc::now(),
+      category_rules: Vec::default(),
+      balance_basis: BalanceBasis::default(),
       balance: 0,
       purchase_log: Vec::default(),
       status: CommitmentStatus::default(),
@@ -321,33 +869,77 @@ impl Default for Commitment {
   }
 }
 
+// Old commitments had no start date,
+// so they are valid from the beginning
+fn default_valid_from() -> DateTime<Utc> {
+  DateTime::<Utc>::from(std::time::UNIX_EPOCH)
+}
+
+// Category rules must be unique by category,
+// and their rates are validated against the discount policy
+fn validate_category_rules(
+  rules: &[CategoryRule],
+  target: u32,
+  at: DateTime<Utc>,
+  policy: &Policy,
+) -> Result<(), DomainError> {
+  for (i, rule) in rules.iter().enumerate() {
+    if rule.category.trim().is_empty() {
+      return Err(DomainError::EmptyCategory);
+    }
+    if rules[..i].iter().any(|r| r.category == rule.category) {
+      return Err(DomainError::DuplicateCategory(rule.category.clone()));
+    }
+    if let CategoryDiscount::Rate {
+      discount_percentage,
+    } = rule.discount
+    {
+      policy.validate_discount(discount_percentage, target, at)?;
+    }
+  }
+  Ok(())
+}
+
 impl CommitmentExt for Commitment {
   fn new(
     customer_id: u32,
     target: u32,
     discount_percentage: u32,
     created_by: u32,
-  ) -> Result<Self, String> {
-    match discount_percentage {
-      x if x <= 6 => {
-        // Define the next calendar year 1st of january.
-        let valid_till_naive = NaiveDate::from_ymd(Utc::today().year() + 1, 1, 1).and_hms(0, 0, 0);
-        // Build the new Commitment Object
-        Ok(Self {
-          commitment_id: Uuid::new_v4(),
-          customer_id,
-          target,
-          discount_percentage,
-          valid_till: DateTime::from_utc(valid_till_naive, Utc),
-          balance: 0,
-          purchase_log: Vec::new(),
-          status: CommitmentStatus::Valid,
-          created_at: Utc::now(),
-          created_by,
-        })
-      }
-      _ => Err("A kedvezmény mértéke 0-6% között lehet!".to_string()),
+    options: CommitmentOptions,
+    policy: &Policy,
+  ) -> Result<Self, DomainError> {
+    let created_at = Utc::now();
+    // Validate against the discount policy in force
+    policy.validate_discount(discount_percentage, target, created_at)?;
+    // Category rates are validated the same way
+    let category_rules = options.category_rules.unwrap_or_default();
+    validate_category_rules(&category_rules, target, created_at, policy)?;
+    // Gross by default
+    let balance_basis = options.balance_basis.unwrap_or_default();
+    // Use explicit validity period or the one defined by the policy
+    let period = options
+      .validity
+      .unwrap_or_else(|| policy.validity.period(created_at));
+    if period.valid_till <= created_at {
+      return Err(DomainError::CommitmentExpired);
     }
+    // Build the new Commitment Object
+    Ok(Self {
+      commitment_id: Uuid::new_v4(),
+      customer_id,
+      target,
+      discount_percentage,
+      valid_from: period.valid_from,
+      valid_till: period.valid_till,
+      category_rules,
+      balance_basis,
+      balance: 0,
+      purchase_log: Vec::new(),
+      status: CommitmentStatus::Valid,
+      created_at,
+      created_by,
+    })
   }
 
   fn withdraw(
@@ -355,23 +947,37 @@ impl CommitmentExt for Commitment {
     new_target: u32,
     new_discount_percentage: u32,
     created_by: u32,
-  ) -> Result<Self, String> {
+    options: CommitmentOptions,
+    policy: &Policy,
+  ) -> Result<Self, DomainError> {
     // Try create new Commitment
+    // it continues the current validity period,
+    // category rules and balance basis by default
+    let options = CommitmentOptions {
+      balance_basis: Some(options.balance_basis.unwrap_or(self.balance_basis)),
+      validity: Some(options.validity.unwrap_or_else(|| self.validity_period())),
+      category_rules: Some(
+        options
+          .category_rules
+          .unwrap_or_else(|| self.category_rules.clone()),
+      ),
+    };
     let mut new_commitment = Self::new(
       self.customer_id,
       new_target,
       new_discount_percentage,
       created_by,
+      options,
+      policy,
     )?;
     // Set its status to be Withdrawn
     self.status = CommitmentStatus::Withdrawn {
       // Set successor ID to the new commitments' one
-      successor: new_commitment.commitment_id.clone(),
+      successor: new_commitment.commitment_id,
     };
-    // Set balance
-    new_commitment.balance = self.balance.clone();
-    // Set history
-    new_commitment.purchase_log = self.purchase_log.clone();
+    // Balance and purchase log are not copied,
+    // the successor refers to the purchases
+    // of its predecessors in the registry
     // Set created_at
     new_commitment.created_at = Utc::now();
     // Set created_by
@@ -380,40 +986,54 @@ impl CommitmentExt for Commitment {
     Ok(new_commitment)
   }
 
-  fn add_purchase(&mut self, purchase: PurchaseInfo) -> Result<&Self, String> {
-    if self
-      .purchase_log
-      .iter()
-      .any(|p| p.purchase_id == purchase.purchase_id)
-    {
-      return Err("A megadott vásárlás már szerepel a vásárlási előzmények között!".to_string());
+  fn category_discount(&self, category: &str) -> u32 {
+    match self.category_rules.iter().find(|r| r.category == category) {
+      Some(rule) => match rule.discount {
+        CategoryDiscount::Excluded => 0,
+        CategoryDiscount::Rate {
+          discount_percentage,
+        } => discount_percentage,
+      },
+      None => self.discount_percentage,
     }
-    self.balance += purchase.total_gross;
-    self.purchase_log.push(purchase);
-    Ok(self)
   }
 
-  fn remove_purchase(&mut self, purchase_id: &Uuid) -> Result<&Self, String> {
-    match self
-      .purchase_log
-      .iter_mut()
-      .find(|pi| pi.purchase_id == *purchase_id)
-    {
-      Some(pi) => {
-        pi.set_removed();
-        self.balance -= pi.total_gross;
-        Ok(self)
-      }
-      None => Err("A megadott vásárlási azonosító nem szerepel a kommitmentben".to_string()),
-    }
+  fn is_excluded(&self, category: &str) -> bool {
+    self
+      .category_rules
+      .iter()
+      .any(|r| r.category == category && r.discount == CategoryDiscount::Excluded)
+  }
+
+  fn eligible_amount(&self, purchase: &PurchaseInfo) -> u32 {
+    // Without category breakdown the whole purchase is eligible
+    let excluded = purchase
+      .categories
+      .iter()
+      .filter(|c| self.is_excluded(&c.category))
+      .fold(0, |acc: u32, c| {
+        acc.saturating_add(self.balance_basis.amount(c.total_net, c.total_gross))
+      });
+    self
+      .balance_basis
+      .amount(purchase.total_net, purchase.total_gross)
+      .saturating_sub(excluded)
   }
 
   fn is_active(&self) -> bool {
-    match self.status {
-      // If valid and date is Ok; then true; otherwise false;
-      CommitmentStatus::Valid => self.valid_till >= Utc::now(),
-      // If its withdrawn, its false
-      CommitmentStatus::Withdrawn { successor: _ } => false,
+    let now = Utc::now();
+    // If not withdrawn and date is Ok; then true; otherwise false;
+    !self.is_withdrawn() && self.is_valid_at(now)
+  }
+
+  fn is_valid_at(&self, at: DateTime<Utc>) -> bool {
+    self.valid_from <= at && at < self.valid_till
+  }
+
+  fn validity_period(&self) -> ValidityPeriod {
+    ValidityPeriod {
+      valid_from: self.valid_from,
+      valid_till: self.valid_till,
     }
   }
 
@@ -433,6 +1053,68 @@ pub struct PurchaseInfo {
   pub applied_discount: u32,
   pub removed: bool,
   pub crated_at: DateTime<Utc>,
+  #[serde(default)]
+  pub removed_at: Option<DateTime<Utc>>, // Removed at
+  #[serde(default)]
+  pub removed_by: Option<u32>, // Removed by uid
+  #[serde(default)]
+  pub reason: Option<String>, // Removal reason
+  #[serde(default)]
+  pub commitment_id: Uuid, // Commitment it was added to
+  #[serde(default)]
+  pub removals: Vec<RemovalState>, // Own removal states by commitment
+  #[serde(default)]
+  pub categories: Vec<CategoryAmount>, // Breakdown by product category
+}
+
+/// Removal state of a registered purchase in a commitment
+/// Successors inherit it until they have their own
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RemovalState {
+  pub commitment_id: Uuid,
+  pub removed: bool,
+  pub removed_at: Option<DateTime<Utc>>,
+  pub removed_by: Option<u32>,
+  pub reason: Option<String>,
+}
+
+impl RemovalState {
+  pub fn removed(commitment_id: Uuid, removal: &RemovalInfo) -> Self {
+    Self {
+      commitment_id,
+      removed: true,
+      removed_at: Some(removal.removed_at),
+      removed_by: Some(removal.removed_by),
+      reason: removal.reason.clone(),
+    }
+  }
+  pub fn restored(commitment_id: Uuid) -> Self {
+    Self {
+      commitment_id,
+      removed: false,
+      removed_at: None,
+      removed_by: None,
+      reason: None,
+    }
+  }
+  // State of a legacy purchase log copy
+  fn from_copy(commitment_id: Uuid, pi: &PurchaseInfo) -> Self {
+    Self {
+      commitment_id,
+      removed: pi.removed,
+      removed_at: pi.removed_at.filter(|_| pi.removed),
+      removed_by: pi.removed_by.filter(|_| pi.removed),
+      reason: pi.reason.clone().filter(|_| pi.removed),
+    }
+  }
+}
+
+/// Purchase amount of a product category
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CategoryAmount {
+  pub category: String,
+  pub total_net: u32,
+  pub total_gross: u32,
 }
 
 impl Default for PurchaseInfo {
@@ -444,6 +1126,12 @@ impl Default for PurchaseInfo {
       applied_discount: 0,
       removed: false,
       crated_at: Utc::now(),
+      removed_at: None,
+      removed_by: None,
+      reason: None,
+      commitment_id: Uuid::default(),
+      removals: Vec::new(),
+      categories: Vec::new(),
     }
   }
 }
@@ -457,62 +1145,574 @@ impl PurchaseInfo {
       applied_discount,
       removed: false,
       crated_at: Utc::now(),
+      removed_at: None,
+      removed_by: None,
+      reason: None,
+      commitment_id: Uuid::default(),
+      removals: Vec::new(),
+      categories: Vec::new(),
+    }
+  }
+  pub fn with_categories(mut self, categories: Vec<CategoryAmount>) -> Self {
+    self.categories = categories;
+    self
+  }
+  /// Category breakdown must add up to the purchase totals
+  pub fn validate_categories(&self) -> Result<(), DomainError> {
+    if self.categories.is_empty() {
+      return Ok(());
     }
+    let (net, gross) = self.categories.iter().fold((0u64, 0u64), |(n, g), c| {
+      (n + c.total_net as u64, g + c.total_gross as u64)
+    });
+    match net == self.total_net as u64 && gross == self.total_gross as u64 {
+      true => Ok(()),
+      false => Err(DomainError::CategoryBreakdownMismatch),
+    }
+  }
+  /// Removal state in the last commitment of the given chain,
+  /// inherited from the nearest one having its own; None if not removed
+  pub fn removal_in(&self, chain: &[Uuid]) -> Option<&RemovalState> {
+    chain
+      .iter()
+      .rev()
+      .find_map(|id| self.removals.iter().find(|s| s.commitment_id == *id))
+      .filter(|s| s.removed)
+  }
+  // Own removal state of a commitment, replacing the previous one
+  fn set_state(&mut self, state: RemovalState) {
+    self
+      .removals
+      .retain(|s| s.commitment_id != state.commitment_id);
+    self.removals.push(state);
   }
-  pub fn set_removed(&mut self) -> &Self {
+  pub fn set_removed(&mut self, state: &RemovalState) -> &Self {
     self.removed = true;
+    self.removed_at = state.removed_at;
+    self.removed_by = state.removed_by;
+    self.reason = state.reason.clone();
+    self
+  }
+  pub fn set_restored(&mut self) -> &Self {
+    self.removed = false;
+    self.removed_at = None;
+    self.removed_by = None;
+    self.reason = None;
     self
   }
 }
 
+/// Who removed a purchase, when and why
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RemovalInfo {
+  pub removed_at: DateTime<Utc>,
+  pub removed_by: u32,
+  pub reason: Option<String>,
+}
+
+impl RemovalInfo {
+  pub fn new(removed_by: u32, reason: Option<String>) -> Self {
+    Self {
+      removed_at: Utc::now(),
+      removed_by,
+      reason,
+    }
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
 
+  fn validity(period: ValidityPeriod) -> CommitmentOptions {
+    CommitmentOptions {
+      validity: Some(period),
+      ..CommitmentOptions::default()
+    }
+  }
+
   #[test]
   fn test_commitment_percentage() {
-    assert!(Commitment::new(0, 1000, 0, 0).is_ok());
-    assert!(Commitment::new(0, 1000, 1, 0).is_ok());
-    assert!(Commitment::new(0, 1000, 2, 0).is_ok());
-    assert!(Commitment::new(0, 1000, 3, 0).is_ok());
-    assert!(Commitment::new(0, 1000, 4, 0).is_ok());
-    assert!(Commitment::new(0, 1000, 5, 0).is_ok());
-    assert!(Commitment::new(0, 1000, 6, 0).is_ok());
-    assert!(Commitment::new(0, 1000, 7, 0).is_err());
-    assert!(Commitment::new(0, 1000, 8, 0).is_err());
-    assert!(Commitment::new(0, 1000, 9, 0).is_err());
+    let policy = Policy::default();
+    assert!(Commitment::new(0, 1000, 0, 0, CommitmentOptions::default(), &policy).is_ok());
+    assert!(Commitment::new(0, 1000, 1, 0, CommitmentOptions::default(), &policy).is_ok());
+    assert!(Commitment::new(0, 1000, 2, 0, CommitmentOptions::default(), &policy).is_ok());
+    assert!(Commitment::new(0, 1000, 3, 0, CommitmentOptions::default(), &policy).is_ok());
+    assert!(Commitment::new(0, 1000, 4, 0, CommitmentOptions::default(), &policy).is_ok());
+    assert!(Commitment::new(0, 1000, 5, 0, CommitmentOptions::default(), &policy).is_ok());
+    assert!(Commitment::new(0, 1000, 6, 0, CommitmentOptions::default(), &policy).is_ok());
+    assert!(Commitment::new(0, 1000, 7, 0, CommitmentOptions::default(), &policy).is_err());
+    assert!(Commitment::new(0, 1000, 8, 0, CommitmentOptions::default(), &policy).is_err());
+    assert!(Commitment::new(0, 1000, 9, 0, CommitmentOptions::default(), &policy).is_err());
   }
 
   #[test]
   fn test_commitment_withdraw() {
+    let policy = Policy::default();
     // Should be ok
-    let mut c = Commitment::new(0, 1000, 2, 0).unwrap();
+    let mut customer = Customer::new(0, 1000, 2, 0, CommitmentOptions::default(), &policy).unwrap();
+    let first_id = customer.commitments[0].commitment_id;
 
     // Should be err
-    assert!(c.remove_purchase(&Uuid::default()).is_err());
+    assert!(customer
+      .remove_purchase(first_id, &Uuid::default(), &RemovalInfo::new(1, None))
+      .is_err());
 
     let id1 = Uuid::new_v4();
     let id2 = Uuid::new_v4();
     let id3 = Uuid::new_v4();
 
     // Should be ok
-    assert!(c
-      .add_purchase(PurchaseInfo::new(id1.clone(), 100, 127, 2))
+    assert!(customer
+      .add_purchase(first_id, PurchaseInfo::new(id1, 100, 127, 2))
       .is_ok());
     // Should be ok
-    assert!(c
-      .add_purchase(PurchaseInfo::new(id2.clone(), 100, 127, 2))
+    assert!(customer
+      .add_purchase(first_id, PurchaseInfo::new(id2, 100, 127, 2))
       .is_ok());
     // Should be ok
-    assert!(c
-      .add_purchase(PurchaseInfo::new(id3.clone(), 100, 127, 2))
+    assert!(customer
+      .add_purchase(first_id, PurchaseInfo::new(id3, 100, 127, 2))
       .is_ok());
+    // Should be err
+    assert!(customer
+      .add_purchase(first_id, PurchaseInfo::new(id3, 100, 127, 2))
+      .is_err());
 
     // Should be ok
-    assert!(c.remove_purchase(&id3).is_ok());
+    assert!(customer
+      .remove_purchase(first_id, &id3, &RemovalInfo::new(1, None))
+      .is_ok());
+
+    customer
+      .add_commitment(1000, 0, 0, CommitmentOptions::default(), &policy)
+      .unwrap();
+
+    let c = customer.get_commitment(&first_id).unwrap();
+    let c2 = customer.get_active_commitment().unwrap();
+    assert!(!c.is_active());
+    assert_eq!(c2.validity_period(), c.validity_period());
+    // Successor refers to the same purchases
+    assert_eq!(c2.purchase_log.len(), 3);
+    assert_eq!(c2.balance, 254);
+    // Purchases are stored once
+    assert_eq!(customer.purchases.len(), 3);
+    assert!(customer
+      .commitments
+      .iter()
+      .all(|c| c.purchase_log.is_empty()));
+  }
+
+  #[test]
+  fn test_applied_discount() {
+    let policy = Policy::default();
+    let mut customer = Customer::new(1, 1000, 2, 0, CommitmentOptions::default(), &policy).unwrap();
+    let id = customer.commitments[0].commitment_id;
+    assert_eq!(
+      customer
+        .add_purchase(id, PurchaseInfo::new(Uuid::new_v4(), 100, 127, 3))
+        .err(),
+      Some(DomainError::DiscountMismatch {
+        applied: 3,
+        expected: 2
+      })
+    );
+    assert!(customer
+      .add_purchase(id, PurchaseInfo::new(Uuid::new_v4(), 100, 127, 2))
+      .is_ok());
+    assert_eq!(customer.purchases.len(), 1);
+    assert_eq!(
+      customer
+        .add_purchase(
+          Uuid::new_v4(),
+          PurchaseInfo::new(Uuid::new_v4(), 100, 127, 2)
+        )
+        .err(),
+      Some(DomainError::CommitmentNotFound)
+    );
+  }
+
+  #[test]
+  fn test_category_rules() {
+    let policy = Policy::default();
+    let excluded = CategoryRule {
+      category: "tobacco".to_string(),
+      discount: CategoryDiscount::Excluded,
+    };
+    let options = CommitmentOptions {
+      category_rules: Some(vec![excluded.clone()]),
+      ..CommitmentOptions::default()
+    };
+    let mut customer = Customer::new(1, 1000, 2, 0, options, &policy).unwrap();
+    let id = customer.commitments[0].commitment_id;
+    let categories = vec![
+      CategoryAmount {
+        category: "food".to_string(),
+        total_net: 100,
+        total_gross: 127,
+      },
+      CategoryAmount {
+        category: "tobacco".to_string(),
+        total_net: 100,
+        total_gross: 127,
+      },
+    ];
+    // Breakdown must add up to the totals
+    assert!(customer
+      .add_purchase(
+        id,
+        PurchaseInfo::new(Uuid::new_v4(), 100, 127, 2).with_categories(categories.clone())
+      )
+      .is_err());
+    // Applied discount must follow the category rules
+    assert_eq!(
+      customer
+        .add_purchase(
+          id,
+          PurchaseInfo::new(Uuid::new_v4(), 200, 254, 2).with_categories(categories.clone())
+        )
+        .err(),
+      Some(DomainError::DiscountMismatch {
+        applied: 2,
+        expected: 1
+      })
+    );
+    // Only the eligible amount counts toward balance
+    let c = customer
+      .add_purchase(
+        id,
+        PurchaseInfo::new(Uuid::new_v4(), 200, 254, 1).with_categories(categories),
+      )
+      .unwrap();
+    assert_eq!(c.balance, 127);
+    // Rules are copied forward by withdraw
+    customer
+      .add_commitment(2000, 3, 0, CommitmentOptions::default(), &policy)
+      .unwrap();
+    let c = customer.get_active_commitment().unwrap();
+    assert_eq!(c.category_rules, vec![excluded.clone()]);
+    assert_eq!(c.balance, 127);
+    // Purchases keep counting by the rules they were added with
+    let options = CommitmentOptions {
+      category_rules: Some(Vec::new()),
+      ..CommitmentOptions::default()
+    };
+    customer
+      .add_commitment(2000, 3, 0, options, &policy)
+      .unwrap();
+    assert_eq!(customer.get_active_commitment().unwrap().balance, 127);
+    // Duplicated category
+    let options = CommitmentOptions {
+      category_rules: Some(vec![excluded.clone(), excluded]),
+      ..CommitmentOptions::default()
+    };
+    assert!(Commitment::new(1, 1000, 2, 0, options, &policy).is_err());
+  }
+
+  #[test]
+  fn test_balance_basis() {
+    let policy = Policy::default();
+    let options = CommitmentOptions {
+      balance_basis: Some(BalanceBasis::Net),
+      ..CommitmentOptions::default()
+    };
+    let mut customer = Customer::new(1, 1000, 2, 0, options, &policy).unwrap();
+    let id = customer.commitments[0].commitment_id;
+    let purchase_id = Uuid::new_v4();
+    let c = customer
+      .add_purchase(id, PurchaseInfo::new(purchase_id, 100, 127, 2))
+      .unwrap();
+    assert_eq!(c.balance, 100);
+    let (c, _) = customer
+      .remove_purchase(id, &purchase_id, &RemovalInfo::new(1, None))
+      .unwrap();
+    assert_eq!(c.balance, 0);
+    customer.restore_purchase(id, &purchase_id).unwrap();
+    // Basis is copied forward by withdraw
+    customer
+      .add_commitment(2000, 3, 0, CommitmentOptions::default(), &policy)
+      .unwrap();
+    let c = customer.get_active_commitment().unwrap();
+    assert_eq!(c.balance_basis, BalanceBasis::Net);
+    assert_eq!(c.balance, 100);
+    // Purchases keep counting by the basis they were added with
+    let options = CommitmentOptions {
+      balance_basis: Some(BalanceBasis::Gross),
+      ..CommitmentOptions::default()
+    };
+    customer
+      .add_commitment(2000, 3, 0, options, &policy)
+      .unwrap();
+    let id = customer.get_active_commitment().unwrap().commitment_id;
+    let c = customer
+      .add_purchase(id, PurchaseInfo::new(Uuid::new_v4(), 100, 127, 3))
+      .unwrap();
+    assert_eq!(c.balance, 227);
+    assert_eq!(
+      customer.get_commitment(&c.commitment_id).unwrap().balance,
+      227
+    );
+    // Gross by default
+    let c = Commitment::new(1, 1000, 2, 0, CommitmentOptions::default(), &policy).unwrap();
+    assert_eq!(c.balance_basis, BalanceBasis::Gross);
+  }
 
-    let c2 = c.withdraw(1000, 0, 0).unwrap();
+  #[test]
+  fn test_balance_overflow() {
+    let policy = Policy::default();
+    let mut customer = Customer::new(1, 1000, 2, 0, CommitmentOptions::default(), &policy).unwrap();
+    let id = customer.commitments[0].commitment_id;
+    let (id1, id2) = (Uuid::new_v4(), Uuid::new_v4());
+    customer
+      .add_purchase(id, PurchaseInfo::new(id1, u32::MAX, u32::MAX, 2))
+      .unwrap();
+    customer
+      .remove_purchase(id, &id1, &RemovalInfo::new(1, None))
+      .unwrap();
+    customer
+      .add_purchase(id, PurchaseInfo::new(id2, 100, 127, 2))
+      .unwrap();
+    assert_eq!(
+      customer
+        .add_purchase(id, PurchaseInfo::new(Uuid::new_v4(), u32::MAX, u32::MAX, 2))
+        .err(),
+      Some(DomainError::BalanceOverflow)
+    );
+    assert_eq!(customer.purchases.len(), 2);
+    // Restore is rolled back as well
+    assert_eq!(
+      customer.restore_purchase(id, &id1).err(),
+      Some(DomainError::BalanceOverflow)
+    );
+    assert_eq!(customer.get_commitment(&id).unwrap().balance, 127);
+    // Corrupted registry is saturated in views
+    customer.purchases[&id1].removals.clear();
+    assert_eq!(customer.get_commitment(&id).unwrap().balance, u32::MAX);
+  }
 
+  #[test]
+  fn test_commitment_validity() {
+    let now = Utc::now();
+    let policy = Policy::default();
+    // Explicit period starting in the future is not active yet
+    let future = ValidityPeriod::new(
+      now + chrono::Duration::days(1),
+      now + chrono::Duration::days(30),
+    )
+    .unwrap();
+    let c = Commitment::new(0, 1000, 2, 0, validity(future), &policy).unwrap();
     assert!(!c.is_active());
+    assert!(c.is_valid_at(now + chrono::Duration::days(2)));
+    // Already expired period is rejected
+    let past = ValidityPeriod::new(
+      now - chrono::Duration::days(30),
+      now - chrono::Duration::days(1),
+    )
+    .unwrap();
+    assert!(Commitment::new(0, 1000, 2, 0, validity(past), &policy).is_err());
+    // Default is the calendar year
+    let c = Commitment::new(0, 1000, 2, 0, CommitmentOptions::default(), &policy).unwrap();
+    assert!(c.is_active());
+    assert_eq!(c.validity_period(), policy.validity.period(c.created_at));
+    // End is exclusive, so consecutive periods do not overlap
+    let next = policy.validity.next_period(c.valid_till);
+    assert!(!c.is_valid_at(c.valid_till));
+    assert!(c.is_valid_at(c.valid_till - chrono::Duration::seconds(1)));
+    assert_eq!(next.valid_from, c.valid_till);
+  }
+
+  #[test]
+  fn test_upcoming_commitment() {
+    let now = Utc::now();
+    let policy = Policy::default();
+    let mut customer = Customer::new(1, 1000, 2, 0, CommitmentOptions::default(), &policy).unwrap();
+    let first_id = customer.commitments[0].commitment_id;
+    customer
+      .add_purchase(first_id, PurchaseInfo::new(Uuid::new_v4(), 100, 127, 2))
+      .unwrap();
+    let starts = now + chrono::Duration::days(10);
+    let period = ValidityPeriod::new(starts, starts + chrono::Duration::days(30)).unwrap();
+    customer
+      .add_commitment(2000, 3, 0, validity(period), &policy)
+      .unwrap();
+    let second_id = customer.commitments[1].commitment_id;
+    // The current one is withdrawn at once,
+    // the new one is not active till it starts
+    assert!(customer.commitments[0].is_withdrawn());
+    assert!(customer.get_active_commitment().is_none());
+    assert_eq!(customer.get_commitment(&second_id).unwrap().balance, 127);
+
+    // Withdrawing the upcoming one inherits its period
+    customer
+      .add_commitment(1000, 1, 0, CommitmentOptions::default(), &policy)
+      .unwrap();
+    assert!(customer.commitments[1].is_withdrawn());
+    assert_eq!(customer.commitments[2].validity_period(), period);
+    assert_eq!(
+      customer
+        .get_commitment(&customer.commitments[2].commitment_id)
+        .unwrap()
+        .balance,
+      127
+    );
+  }
+
+  #[test]
+  fn test_remove_purchase_idempotent() {
+    let policy = Policy::default();
+    let mut customer = Customer::new(1, 1000, 2, 0, CommitmentOptions::default(), &policy).unwrap();
+    let first_id = customer.commitments[0].commitment_id;
+    let id1 = Uuid::new_v4();
+    let id2 = Uuid::new_v4();
+    customer
+      .add_purchase(first_id, PurchaseInfo::new(id1, 100, 127, 2))
+      .unwrap();
+    customer
+      .add_purchase(first_id, PurchaseInfo::new(id2, 100, 127, 2))
+      .unwrap();
+
+    let removal = RemovalInfo::new(1, Some("storno".to_string()));
+    let (c, outcome) = customer.remove_purchase(first_id, &id1, &removal).unwrap();
+    assert_eq!(outcome, PurchaseRemoval::Removed);
+    assert_eq!(c.balance, 127);
+    // Retry should not change balance nor removal info
+    let retry = RemovalInfo::new(2, None);
+    let (c, outcome) = customer.remove_purchase(first_id, &id1, &retry).unwrap();
+    assert_eq!(outcome, PurchaseRemoval::AlreadyRemoved);
+    assert_eq!(c.balance, 127);
+    assert_eq!(c.purchase_log[0].removed_by, Some(1));
+    assert_eq!(c.purchase_log[0].reason, Some("storno".to_string()));
+    assert_eq!(c.purchase_log[0].removed_at, Some(removal.removed_at));
+
+    // Remove through the withdrawal chain
+    customer
+      .add_commitment(2000, 3, 0, CommitmentOptions::default(), &policy)
+      .unwrap();
+    let second_id = customer.commitments[1].commitment_id;
+    let (c, outcome) = customer.remove_purchase(first_id, &id2, &removal).unwrap();
+    assert_eq!(outcome, PurchaseRemoval::Removed);
+    assert_eq!(c.commitment_id, second_id);
+    assert_eq!(c.balance, 0);
+    let (_, outcome) = customer.remove_purchase(first_id, &id2, &removal).unwrap();
+    assert_eq!(outcome, PurchaseRemoval::AlreadyRemoved);
+    assert!(customer.commitment_views().iter().all(|c| c.balance == 0));
+  }
+
+  #[test]
+  fn test_remove_purchase_from_successor() {
+    let policy = Policy::default();
+    let mut customer = Customer::new(1, 1000, 2, 0, CommitmentOptions::default(), &policy).unwrap();
+    let first_id = customer.commitments[0].commitment_id;
+    let id = Uuid::new_v4();
+    customer
+      .add_purchase(first_id, PurchaseInfo::new(id, 100, 127, 2))
+      .unwrap();
+    customer
+      .add_commitment(2000, 3, 0, CommitmentOptions::default(), &policy)
+      .unwrap();
+    let second_id = customer.commitments[1].commitment_id;
+
+    // Removing from the successor keeps the withdrawn one untouched
+    let removal = RemovalInfo::new(1, None);
+    customer.remove_purchase(second_id, &id, &removal).unwrap();
+    assert_eq!(customer.get_commitment(&first_id).unwrap().balance, 127);
+    assert_eq!(customer.get_commitment(&second_id).unwrap().balance, 0);
+
+    // Removing from the withdrawn one covers the successor as well,
+    // which keeps its own removal record
+    let (c, outcome) = customer
+      .remove_purchase(first_id, &id, &RemovalInfo::new(2, None))
+      .unwrap();
+    assert_eq!(outcome, PurchaseRemoval::Removed);
+    assert_eq!(c.balance, 0);
+    assert_eq!(c.purchase_log[0].removed_by, Some(1));
+    assert_eq!(customer.get_commitment(&first_id).unwrap().balance, 0);
+
+    // Restoring in the successor keeps the withdrawn one removed
+    let (c, outcome) = customer.restore_purchase(second_id, &id).unwrap();
+    assert_eq!(outcome, PurchaseRestore::Restored);
+    assert_eq!(c.balance, 127);
+    let first = customer.get_commitment(&first_id).unwrap();
+    assert_eq!(first.balance, 0);
+    assert_eq!(first.purchase_log[0].removed_by, Some(2));
+    // Removing from the withdrawn one again is a no-op
+    let (c, outcome) = customer.remove_purchase(first_id, &id, &removal).unwrap();
+    assert_eq!(outcome, PurchaseRemoval::AlreadyRemoved);
+    assert_eq!(c.balance, 127);
+  }
+
+  #[test]
+  fn test_restore_purchase() {
+    let policy = Policy::default();
+    let mut customer = Customer::new(1, 1000, 2, 0, CommitmentOptions::default(), &policy).unwrap();
+    let first_id = customer.commitments[0].commitment_id;
+    let id = Uuid::new_v4();
+    customer
+      .add_purchase(first_id, PurchaseInfo::new(id, 100, 127, 2))
+      .unwrap();
+    customer
+      .add_commitment(2000, 3, 0, CommitmentOptions::default(), &policy)
+      .unwrap();
+    // Nothing to restore yet
+    let (_, outcome) = customer.restore_purchase(first_id, &id).unwrap();
+    assert_eq!(outcome, PurchaseRestore::NotRemoved);
+    customer
+      .remove_purchase(first_id, &id, &RemovalInfo::new(1, None))
+      .unwrap();
+    // Restore through the withdrawal chain
+    let (c, outcome) = customer.restore_purchase(first_id, &id).unwrap();
+    assert_eq!(outcome, PurchaseRestore::Restored);
+    assert_eq!(c.purchase_log[0].removed_by, None);
+    assert_eq!(c.balance, 127);
+    assert!(customer.commitment_views().iter().all(|c| c.balance == 127));
+    // Unknown purchase
+    assert!(customer
+      .restore_purchase(first_id, &Uuid::new_v4())
+      .is_err());
+  }
+
+  #[test]
+  fn test_migrate_legacy_purchase_logs() {
+    let policy = Policy::default();
+    let id1 = Uuid::new_v4();
+    let id2 = Uuid::new_v4();
+    let mut first = Commitment::new(1, 1000, 2, 0, CommitmentOptions::default(), &policy).unwrap();
+    let mut second = Commitment::new(1, 1000, 2, 0, CommitmentOptions::default(), &policy).unwrap();
+    first.status = CommitmentStatus::Withdrawn {
+      successor: second.commitment_id,
+    };
+    // Legacy format: successor has a copy of the purchase log
+    first.purchase_log = vec![PurchaseInfo::new(id1, 100, 127, 2)];
+    first.balance = 127;
+    second.purchase_log = first.purchase_log.clone();
+    second
+      .purchase_log
+      .push(PurchaseInfo::new(id2, 100, 127, 2));
+    second.purchase_log[0].removed = true;
+    // Corrupted balance
+    second.balance = 254;
+    let mut customer = Customer {
+      customer_id: 1,
+      commitments: vec![first.clone(), second.clone()],
+      purchases: IndexMap::new(),
+    };
+
+    assert!(customer.needs_migration());
+    assert_eq!(check_balances(std::iter::once(&customer)).len(), 1);
+    let mismatches = customer.migrate();
+    assert!(!customer.needs_migration());
+    assert_eq!(customer.purchases.len(), 2);
+    assert_eq!(mismatches.len(), 1);
+    assert_eq!(mismatches[0].commitment_id, second.commitment_id);
+    assert_eq!(mismatches[0].expected, 127);
+    let views = customer.commitment_views();
+    assert_eq!(views[0].balance, 127);
+    assert_eq!(views[0].purchase_log.len(), 1);
+    assert!(!views[0].purchase_log[0].removed);
+    assert_eq!(views[1].balance, 127);
+    assert_eq!(views[1].purchase_log.len(), 2);
+    assert!(views[1].purchase_log[0].removed);
+    // Registry balances are checked as well
+    assert!(check_balances(std::iter::once(&customer)).is_empty());
   }
 }
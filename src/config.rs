@@ -0,0 +1,413 @@
+use crate::auth::Auth;
+use crate::policy::{Policy, ValidityModel};
+use serde::Deserialize;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+/// Env key of the configuration file path
+pub const CONFIG_PATH_KEY: &str = "COMMITMENT_CONFIG_PATH";
+
+/// Service configuration
+/// Loaded from the TOML file given by COMMITMENT_CONFIG_PATH if any,
+/// then overridden by the environment
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  pub data_dir: PathBuf,                // Database and journal directory
+  pub listen_addr: SocketAddr,          // gRPC listen address
+  pub metrics_addr: Option<SocketAddr>, // Prometheus metrics address; disabled if None
+  pub policy_path: Option<PathBuf>,     // Discount policy file; default policy if None
+  pub validity: Option<ValidityModel>,  // Overrides the validity model of the policy
+  pub idempotency_ttl_secs: u64,        // Idempotency key lifetime
+  pub tls: Option<TlsConfig>,           // Plain TCP if None
+  pub auth: Option<AuthConfig>,         // No authentication if None
+  pub logging: LoggingConfig,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      data_dir: PathBuf::from("data"),
+      listen_addr: SocketAddr::from((Ipv6Addr::LOCALHOST, 50074)),
+      metrics_addr: None,
+      policy_path: None,
+      validity: None,
+      idempotency_ttl_secs: 86400,
+      tls: None,
+      auth: None,
+      logging: LoggingConfig::default(),
+    }
+  }
+}
+
+/// Server certificate, and client CA for mutual TLS
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+  pub cert_path: PathBuf, // PEM certificate chain
+  pub key_path: PathBuf,  // PEM private key
+  #[serde(default)]
+  pub client_ca_path: Option<PathBuf>, // Require client certificates signed by it
+}
+
+/// Bearer token verification
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+  pub jwt_secret_path: PathBuf, // HS256 signing key of the tokens
+}
+
+/// Log output format
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+  Text,
+  Json,
+}
+
+impl std::str::FromStr for LogFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "text" => Ok(LogFormat::Text),
+      "json" => Ok(LogFormat::Json),
+      _ => Err(format!("Unknown log format: {}", s)),
+    }
+  }
+}
+
+/// Log levels from the least verbose
+pub const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+  pub level: String,
+  pub format: LogFormat,
+}
+
+impl Default for LoggingConfig {
+  fn default() -> Self {
+    Self {
+      level: "info".to_string(),
+      format: LogFormat::Text,
+    }
+  }
+}
+
+// Parse validity model from env value:
+// calendar_year, rolling_months:N or fiscal_year:M
+fn parse_validity(value: &str) -> Result<ValidityModel, String> {
+  let err = || format!("Invalid validity model: {}", value);
+  let mut parts = value.splitn(2, ':');
+  let number = |part: Option<&str>| {
+    part
+      .and_then(|p| p.trim().parse::<u32>().ok())
+      .ok_or_else(err)
+  };
+  match parts.next().map(|p| p.trim()) {
+    Some("calendar_year") => Ok(ValidityModel::CalendarYear),
+    Some("rolling_months") => Ok(ValidityModel::RollingMonths {
+      months: number(parts.next())?,
+    }),
+    Some("fiscal_year") => Ok(ValidityModel::FiscalYear {
+      start_month: number(parts.next())?,
+    }),
+    _ => Err(err()),
+  }
+}
+
+impl Config {
+  /// Load, override from env and validate the configuration
+  pub fn load() -> Result<Self, String> {
+    let mut config = match std::env::var(CONFIG_PATH_KEY) {
+      Ok(path) => Self::from_file(Path::new(&path))?,
+      Err(_) => Self::default(),
+    };
+    config.apply_env(|key| std::env::var(key).ok())?;
+    config.validate()?;
+    Ok(config)
+  }
+
+  /// Read configuration file; missing keys get their default
+  pub fn from_file(path: &Path) -> Result<Self, String> {
+    let content = std::fs::read_to_string(path)
+      .map_err(|e| format!("Error while reading config file {}: {}", path.display(), e))?;
+    Self::parse(&content)
+      .map_err(|e| format!("Error while parsing config file {}: {}", path.display(), e))
+  }
+
+  pub fn parse(content: &str) -> Result<Self, String> {
+    toml::from_str(content).map_err(|e| e.to_string())
+  }
+
+  /// Override settings by the env values the lookup returns
+  pub fn apply_env<F>(&mut self, var: F) -> Result<(), String>
+  where
+    F: Fn(&str) -> Option<String>,
+  {
+    if let Some(v) = var("COMMITMENT_DATA_DIR") {
+      self.data_dir = PathBuf::from(v);
+    }
+    if let Some(v) = var("SERVICE_ADDR_COMMITMENT") {
+      self.listen_addr = v
+        .parse()
+        .map_err(|_| format!("Invalid SERVICE_ADDR_COMMITMENT: {}", v))?;
+    }
+    if let Some(v) = var("COMMITMENT_METRICS_ADDR") {
+      self.metrics_addr = Some(
+        v.parse()
+          .map_err(|_| format!("Invalid COMMITMENT_METRICS_ADDR: {}", v))?,
+      );
+    }
+    if let Some(v) = var("COMMITMENT_POLICY_PATH") {
+      self.policy_path = Some(PathBuf::from(v));
+    }
+    if let Some(v) = var("COMMITMENT_VALIDITY_MODEL") {
+      self.validity = Some(parse_validity(&v)?);
+    }
+    if let Some(v) = var("COMMITMENT_IDEMPOTENCY_TTL_SECS") {
+      self.idempotency_ttl_secs = v
+        .parse()
+        .map_err(|_| format!("Invalid COMMITMENT_IDEMPOTENCY_TTL_SECS: {}", v))?;
+    }
+    match (
+      var("COMMITMENT_TLS_CERT_PATH"),
+      var("COMMITMENT_TLS_KEY_PATH"),
+    ) {
+      (Some(cert_path), Some(key_path)) => {
+        self.tls = Some(TlsConfig {
+          cert_path: PathBuf::from(cert_path),
+          key_path: PathBuf::from(key_path),
+          client_ca_path: None,
+        })
+      }
+      (None, None) => (),
+      _ => {
+        return Err(
+          "COMMITMENT_TLS_CERT_PATH and COMMITMENT_TLS_KEY_PATH must be set together".to_string(),
+        )
+      }
+    }
+    if let Some(v) = var("COMMITMENT_TLS_CLIENT_CA_PATH") {
+      match &mut self.tls {
+        Some(tls) => tls.client_ca_path = Some(PathBuf::from(v)),
+        None => return Err("COMMITMENT_TLS_CLIENT_CA_PATH needs TLS to be configured".to_string()),
+      }
+    }
+    if let Some(v) = var("COMMITMENT_JWT_SECRET_PATH") {
+      self.auth = Some(AuthConfig {
+        jwt_secret_path: PathBuf::from(v),
+      });
+    }
+    if let Some(v) = var("COMMITMENT_LOG_LEVEL") {
+      self.logging.level = v;
+    }
+    if let Some(v) = var("COMMITMENT_LOG_FORMAT") {
+      self.logging.format = v.parse()?;
+    }
+    Ok(())
+  }
+
+  /// Check whether the configuration is usable
+  pub fn validate(&self) -> Result<(), String> {
+    if self.data_dir.as_os_str().is_empty() {
+      return Err("Data directory is required".to_string());
+    }
+    if let Some(validity) = &self.validity {
+      validity.validate()?;
+    }
+    if self.metrics_addr == Some(self.listen_addr) {
+      return Err("Metrics address must differ from the listen address".to_string());
+    }
+    if self.idempotency_ttl_secs == 0 {
+      return Err("Idempotency TTL must be at least 1 sec".to_string());
+    }
+    if let Some(tls) = &self.tls {
+      let files = std::iter::once(&tls.cert_path)
+        .chain(std::iter::once(&tls.key_path))
+        .chain(tls.client_ca_path.iter());
+      for path in files {
+        if !path.is_file() {
+          return Err(format!("TLS file not found: {}", path.display()));
+        }
+      }
+    }
+    if let Some(auth) = &self.auth {
+      if !auth.jwt_secret_path.is_file() {
+        return Err(format!(
+          "JWT secret file not found: {}",
+          auth.jwt_secret_path.display()
+        ));
+      }
+    }
+    if !LOG_LEVELS.contains(&self.logging.level.as_str()) {
+      return Err(format!(
+        "Unknown log level: {}, expected one of {}",
+        self.logging.level,
+        LOG_LEVELS.join(", ")
+      ));
+    }
+    Ok(())
+  }
+
+  /// Customer database path
+  pub fn db_path(&self) -> PathBuf {
+    self.data_dir.join("commitments")
+  }
+
+  /// Event journal path
+  pub fn journal_path(&self) -> PathBuf {
+    self.data_dir.join("commitment_events.jsonl")
+  }
+
+  /// Stored idempotent responses path
+  pub fn idempotency_path(&self) -> PathBuf {
+    self.data_dir.join("idempotency_keys.jsonl")
+  }
+
+  /// Discount policy from the policy file if any,
+  /// with the configured validity model
+  pub fn policy(&self) -> Result<Policy, String> {
+    let mut policy = match &self.policy_path {
+      Some(path) => Policy::load(path)?,
+      None => Policy::default(),
+    };
+    if let Some(validity) = &self.validity {
+      policy.validity = validity.clone();
+    }
+    policy.validate()?;
+    Ok(policy)
+  }
+
+  /// Token verification with the configured key if any
+  pub fn auth(&self) -> Result<Option<Auth>, String> {
+    let auth = match &self.auth {
+      Some(auth) => auth,
+      None => return Ok(None),
+    };
+    let secret = std::fs::read(&auth.jwt_secret_path).map_err(|e| {
+      format!(
+        "Error while reading JWT secret {}: {}",
+        auth.jwt_secret_path.display(),
+        e
+      )
+    })?;
+    // Trailing newline of the key file is not part of the key
+    let secret = match secret.strip_suffix(b"\n") {
+      Some(secret) => secret,
+      None => &secret,
+    };
+    if secret.is_empty() {
+      return Err("JWT secret is empty".to_string());
+    }
+    Ok(Some(Auth::new(secret)))
+  }
+
+  /// Idempotency key lifetime
+  pub fn idempotency_ttl(&self) -> chrono::Duration {
+    chrono::Duration::seconds(self.idempotency_ttl_secs as i64)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashMap;
+
+  fn env(vars: &[(&str, &str)]) -> HashMap<String, String> {
+    vars
+      .iter()
+      .map(|(k, v)| (k.to_string(), v.to_string()))
+      .collect()
+  }
+
+  #[test]
+  fn test_config_file_and_env() {
+    let mut config = Config::parse(
+      r#"
+      data_dir = "/var/lib/commitment"
+      listen_addr = "0.0.0.0:50074"
+
+      [validity]
+      type = "rolling_months"
+      months = 12
+
+      [logging]
+      format = "json"
+      "#,
+    )
+    .unwrap();
+    assert_eq!(
+      config.db_path(),
+      PathBuf::from("/var/lib/commitment/commitments")
+    );
+    assert_eq!(config.idempotency_ttl_secs, 86400);
+    assert_eq!(config.logging.level, "info");
+    assert_eq!(config.logging.format, LogFormat::Json);
+
+    // Env overrides the file
+    let vars = env(&[
+      ("SERVICE_ADDR_COMMITMENT", "127.0.0.1:6000"),
+      ("COMMITMENT_VALIDITY_MODEL", "fiscal_year:7"),
+      ("COMMITMENT_LOG_LEVEL", "debug"),
+      ("COMMITMENT_METRICS_ADDR", "127.0.0.1:9100"),
+    ]);
+    config.apply_env(|key| vars.get(key).cloned()).unwrap();
+    assert_eq!(config.listen_addr.port(), 6000);
+    assert_eq!(config.metrics_addr.map(|a| a.port()), Some(9100));
+    assert_eq!(
+      config.validity,
+      Some(ValidityModel::FiscalYear { start_month: 7 })
+    );
+    assert!(config.validate().is_ok());
+    assert_eq!(
+      config.policy().unwrap().validity,
+      ValidityModel::FiscalYear { start_month: 7 }
+    );
+  }
+
+  #[test]
+  fn test_config_errors() {
+    // Typos are not ignored
+    assert!(Config::parse("data_directory = \"data\"").is_err());
+    assert!(Config::parse("listen_addr = \"localhost\"").is_err());
+
+    let mut config = Config::default();
+    let vars = env(&[("COMMITMENT_TLS_CERT_PATH", "cert.pem")]);
+    assert!(config.apply_env(|key| vars.get(key).cloned()).is_err());
+    let vars = env(&[("COMMITMENT_VALIDITY_MODEL", "rolling_months")]);
+    assert!(config.apply_env(|key| vars.get(key).cloned()).is_err());
+
+    let config = Config {
+      validity: Some(ValidityModel::FiscalYear { start_month: 13 }),
+      ..Config::default()
+    };
+    assert!(config.validate().is_err());
+    let config = Config {
+      metrics_addr: Some(Config::default().listen_addr),
+      ..Config::default()
+    };
+    assert!(config.validate().is_err());
+    let config = Config {
+      tls: Some(TlsConfig {
+        cert_path: PathBuf::from("/nonexistent/cert.pem"),
+        key_path: PathBuf::from("/nonexistent/key.pem"),
+        client_ca_path: None,
+      }),
+      ..Config::default()
+    };
+    assert!(config.validate().is_err());
+    let config = Config {
+      logging: LoggingConfig {
+        level: "verbose".to_string(),
+        format: LogFormat::Text,
+      },
+      policy_path: Some(PathBuf::from("/nonexistent/policy.toml")),
+      ..Config::default()
+    };
+    assert!(config.validate().is_err());
+    assert!(config.policy().is_err());
+  }
+}
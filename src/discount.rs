@@ -0,0 +1,182 @@
+use crate::commitment::{Commitment, CommitmentExt, PurchaseInfo};
+use crate::error::DomainError;
+use uuid::Uuid;
+
+/// Basket line to calculate discount for
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasketLine {
+  pub sku: String,
+  pub category: String,
+  pub total_net: u32,
+  pub total_gross: u32,
+  pub eligible: bool, // SKU category is eligible for commitment discount
+}
+
+/// Discount amounts of a basket line
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineDiscount {
+  pub line: BasketLine,
+  pub discount_percentage: u32, // Applied to the line
+  pub discount_net: u32,
+  pub discount_gross: u32,
+}
+
+/// Discount calculation of a basket
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscountCalculation {
+  pub customer_id: u32,
+  pub commitment_id: Option<Uuid>, // Active commitment if any
+  pub discount_percentage: u32,
+  pub lines: Vec<LineDiscount>,
+  pub total_discount_net: u32,
+  pub total_discount_gross: u32,
+}
+
+/// Discount amount of the given value, rounded half up
+pub fn discount_amount(value: u32, discount_percentage: u32) -> u32 {
+  ((value as u64 * discount_percentage as u64 + 50) / 100) as u32
+}
+
+/// Discount percentage expected on a purchase
+/// Without category breakdown it is the commitment discount,
+/// otherwise the category rates weighted by their gross value,
+/// rounded half up
+pub fn purchase_discount_percentage(c: &Commitment, purchase: &PurchaseInfo) -> u32 {
+  if purchase.categories.is_empty() || purchase.total_gross == 0 {
+    return c.discount_percentage;
+  }
+  let weighted: u64 = purchase
+    .categories
+    .iter()
+    .map(|cat| cat.total_gross as u64 * c.category_discount(&cat.category) as u64)
+    .sum();
+  let total = purchase.total_gross as u64;
+  ((weighted + total / 2) / total) as u32
+}
+
+/// Calculate basket discount using the active commitment
+/// and its product category rules
+/// Without active commitment there is no discount
+pub fn calculate(
+  customer_id: u32,
+  active_commitment: Option<&Commitment>,
+  lines: Vec<BasketLine>,
+) -> Result<DiscountCalculation, DomainError> {
+  let discount_percentage = active_commitment
+    .map(|c| c.discount_percentage)
+    .unwrap_or(0);
+  let mut total_discount_net: u32 = 0;
+  let mut total_discount_gross: u32 = 0;
+  let mut res = Vec::new();
+  for line in lines {
+    let line_percentage = match (line.eligible, active_commitment) {
+      (true, Some(c)) => c.category_discount(&line.category),
+      _ => 0,
+    };
+    let discount_net = discount_amount(line.total_net, line_percentage);
+    let discount_gross = discount_amount(line.total_gross, line_percentage);
+    total_discount_net = total_discount_net
+      .checked_add(discount_net)
+      .ok_or(DomainError::DiscountOverflow)?;
+    total_discount_gross = total_discount_gross
+      .checked_add(discount_gross)
+      .ok_or(DomainError::DiscountOverflow)?;
+    res.push(LineDiscount {
+      line,
+      discount_percentage: line_percentage,
+      discount_net,
+      discount_gross,
+    });
+  }
+  Ok(DiscountCalculation {
+    customer_id,
+    commitment_id: active_commitment.map(|c| c.commitment_id),
+    discount_percentage,
+    lines: res,
+    total_discount_net,
+    total_discount_gross,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::commitment::{CategoryDiscount, CategoryRule, CommitmentOptions};
+  use crate::policy::Policy;
+
+  fn line(category: &str, total_net: u32, total_gross: u32, eligible: bool) -> BasketLine {
+    BasketLine {
+      sku: "sku".to_string(),
+      category: category.to_string(),
+      total_net,
+      total_gross,
+      eligible,
+    }
+  }
+
+  #[test]
+  fn test_discount_amount() {
+    assert_eq!(discount_amount(1000, 3), 30);
+    assert_eq!(discount_amount(1270, 3), 38); // 38.1
+    assert_eq!(discount_amount(1250, 2), 25);
+    assert_eq!(discount_amount(1275, 2), 26); // 25.5
+    assert_eq!(discount_amount(u32::MAX, 6), 257698038);
+  }
+
+  #[test]
+  fn test_calculate() {
+    let c = Commitment::new(
+      1,
+      1000,
+      3,
+      0,
+      CommitmentOptions::default(),
+      &Policy::default(),
+    )
+    .unwrap();
+    let lines = vec![
+      line("food", 1000, 1270, true),
+      line("food", 500, 635, false),
+    ];
+    let res = calculate(1, Some(&c), lines.clone()).unwrap();
+    assert_eq!(res.commitment_id, Some(c.commitment_id));
+    assert_eq!(res.lines[0].discount_net, 30);
+    assert_eq!(res.lines[0].discount_gross, 38);
+    assert_eq!(res.lines[1].discount_gross, 0);
+    assert_eq!(res.total_discount_gross, 38);
+    // No active commitment
+    let res = calculate(1, None, lines).unwrap();
+    assert_eq!(res.discount_percentage, 0);
+    assert_eq!(res.total_discount_gross, 0);
+  }
+
+  #[test]
+  fn test_calculate_category_rules() {
+    let options = CommitmentOptions {
+      category_rules: Some(vec![
+        CategoryRule {
+          category: "tobacco".to_string(),
+          discount: CategoryDiscount::Excluded,
+        },
+        CategoryRule {
+          category: "sale".to_string(),
+          discount: CategoryDiscount::Rate {
+            discount_percentage: 1,
+          },
+        },
+      ]),
+      ..CommitmentOptions::default()
+    };
+    let c = Commitment::new(1, 1000, 3, 0, options, &Policy::default()).unwrap();
+    let lines = vec![
+      line("food", 1000, 1000, true),
+      line("tobacco", 1000, 1000, true),
+      line("sale", 1000, 1000, true),
+    ];
+    let res = calculate(1, Some(&c), lines).unwrap();
+    assert_eq!(res.lines[0].discount_gross, 30);
+    assert_eq!(res.lines[1].discount_gross, 0);
+    assert_eq!(res.lines[2].discount_percentage, 1);
+    assert_eq!(res.total_discount_gross, 40);
+  }
+}
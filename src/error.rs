@@ -0,0 +1,448 @@
+use bytes::Bytes;
+use prost::Message;
+use std::collections::HashMap;
+use tonic::{Code, Request, Status};
+
+/// gRPC metadata key of the preferred message language
+pub const LANGUAGE_KEY: &str = "accept-language";
+
+/// Error domain reported in the status details
+pub const ERROR_DOMAIN: &str = "commitment";
+
+const ERROR_INFO_TYPE_URL: &str = "type.googleapis.com/google.rpc.ErrorInfo";
+
+/// Language of the error messages
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Lang {
+  #[default]
+  Hu,
+  En,
+}
+
+impl Lang {
+  /// Language from an accept-language value, e.g. en-US,en;q=0.9
+  /// Only the first language is considered; Hungarian by default
+  pub fn parse(value: &str) -> Self {
+    let first = value
+      .split(',')
+      .next()
+      .and_then(|l| l.split(['-', '_', ';']).next())
+      .map(|l| l.trim().to_lowercase());
+    match first.as_deref() {
+      Some("en") => Lang::En,
+      _ => Lang::Hu,
+    }
+  }
+
+  /// Language from the request metadata
+  pub fn from_request<T>(request: &Request<T>) -> Self {
+    request
+      .metadata()
+      .get(LANGUAGE_KEY)
+      .and_then(|v| v.to_str().ok())
+      .map(Lang::parse)
+      .unwrap_or_default()
+  }
+}
+
+/// Domain rule violation
+/// Codes are stable, clients should match on them
+/// instead of the messages
+#[derive(Debug, Clone, PartialEq)]
+pub enum DomainError {
+  CommitmentNotFound,
+  PurchaseNotFound,
+  PurchaseAlreadyExists,
+  NoActiveCommitment,
+  NoCommitment,
+  CommitmentSuperseded,
+  CommitmentExpired,
+  DiscountMismatch {
+    applied: u32,
+    expected: u32,
+  },
+  DiscountNotAllowed {
+    allowed: Vec<u32>,
+  },
+  TargetTooLow {
+    discount_percentage: u32,
+    min_target: u32,
+  },
+  NoDiscountPolicy,
+  NoAllowedDiscount {
+    target: u32,
+  },
+  EmptyCategory,
+  DuplicateCategory(String),
+  CategoryBreakdownMismatch,
+  BalanceOverflow,
+  InvalidValidityPeriod,
+  ActiveCommitmentExists,
+  InvalidId(String),
+  InvalidDate(String),
+  IncompleteValidityPeriod,
+  InvalidPeriod,
+  UnknownBalanceBasis(String),
+  UnknownCommitmentState(String),
+  InvalidCursor(String),
+  UnknownExportFormat(String),
+  InvalidFlag(String),
+  ImportFieldCount(usize),
+  ImportInvalidNumber(String),
+  DuplicateImportCustomer,
+  DiscountOverflow,
+  IdempotencyKeyReused,
+  InvalidIdempotencyKey,
+  PermissionDenied {
+    role: String,
+  },
+}
+
+impl DomainError {
+  /// Stable machine-readable code
+  pub fn code(&self) -> &'static str {
+    match self {
+      DomainError::CommitmentNotFound => "COMMITMENT_NOT_FOUND",
+      DomainError::PurchaseNotFound => "PURCHASE_NOT_FOUND",
+      DomainError::PurchaseAlreadyExists => "PURCHASE_ALREADY_EXISTS",
+      DomainError::NoActiveCommitment => "NO_ACTIVE_COMMITMENT",
+      DomainError::NoCommitment => "NO_COMMITMENT",
+      DomainError::CommitmentSuperseded => "COMMITMENT_SUPERSEDED",
+      DomainError::CommitmentExpired => "COMMITMENT_EXPIRED",
+      DomainError::DiscountMismatch { .. } => "DISCOUNT_MISMATCH",
+      DomainError::DiscountNotAllowed { .. } => "DISCOUNT_NOT_ALLOWED",
+      DomainError::TargetTooLow { .. } => "TARGET_TOO_LOW",
+      DomainError::NoDiscountPolicy => "NO_DISCOUNT_POLICY",
+      DomainError::NoAllowedDiscount { .. } => "NO_ALLOWED_DISCOUNT",
+      DomainError::EmptyCategory => "EMPTY_CATEGORY",
+      DomainError::DuplicateCategory(_) => "DUPLICATE_CATEGORY",
+      DomainError::CategoryBreakdownMismatch => "CATEGORY_BREAKDOWN_MISMATCH",
+      DomainError::BalanceOverflow => "BALANCE_OVERFLOW",
+      DomainError::InvalidValidityPeriod => "INVALID_VALIDITY_PERIOD",
+      DomainError::ActiveCommitmentExists => "ACTIVE_COMMITMENT_EXISTS",
+      DomainError::InvalidId(_) => "INVALID_ID",
+      DomainError::InvalidDate(_) => "INVALID_DATE",
+      DomainError::IncompleteValidityPeriod => "INCOMPLETE_VALIDITY_PERIOD",
+      DomainError::InvalidPeriod => "INVALID_PERIOD",
+      DomainError::UnknownBalanceBasis(_) => "UNKNOWN_BALANCE_BASIS",
+      DomainError::UnknownCommitmentState(_) => "UNKNOWN_COMMITMENT_STATE",
+      DomainError::InvalidCursor(_) => "INVALID_CURSOR",
+      DomainError::UnknownExportFormat(_) => "UNKNOWN_EXPORT_FORMAT",
+      DomainError::InvalidFlag(_) => "INVALID_FLAG",
+      DomainError::ImportFieldCount(_) => "IMPORT_FIELD_COUNT",
+      DomainError::ImportInvalidNumber(_) => "IMPORT_INVALID_NUMBER",
+      DomainError::DuplicateImportCustomer => "DUPLICATE_IMPORT_CUSTOMER",
+      DomainError::DiscountOverflow => "DISCOUNT_OVERFLOW",
+      DomainError::IdempotencyKeyReused => "IDEMPOTENCY_KEY_REUSED",
+      DomainError::InvalidIdempotencyKey => "INVALID_IDEMPOTENCY_KEY",
+      DomainError::PermissionDenied { .. } => "PERMISSION_DENIED",
+    }
+  }
+
+  /// gRPC status code
+  /// INVALID_ARGUMENT, as domain errors ever were,
+  /// except for the permission errors, and NOT_FOUND
+  /// for customers without any commitment
+  pub fn status_code(&self) -> Code {
+    match self {
+      DomainError::PermissionDenied { .. } => Code::PermissionDenied,
+      DomainError::NoCommitment => Code::NotFound,
+      _ => Code::InvalidArgument,
+    }
+  }
+
+  /// Message in the given language
+  pub fn message(&self, lang: Lang) -> String {
+    match lang {
+      Lang::Hu => self.message_hu(),
+      Lang::En => self.message_en(),
+    }
+  }
+
+  fn message_hu(&self) -> String {
+    match self {
+      DomainError::CommitmentNotFound => {
+        "A megadott commitment ID nem található a vásárló alatt.".to_string()
+      }
+      DomainError::PurchaseNotFound => {
+        "A megadott vásárlási azonosító nem szerepel a kommitmentben".to_string()
+      }
+      DomainError::PurchaseAlreadyExists => {
+        "A megadott vásárlás már szerepel a vásárlási előzmények között!".to_string()
+      }
+      DomainError::NoActiveCommitment => {
+        "A megadott vásárlónak nincs aktív commitmentje, így a vásárlás nem adható hozzá."
+          .to_string()
+      }
+      DomainError::NoCommitment => "A vásárlónak nincs kommitmentje".to_string(),
+      DomainError::CommitmentSuperseded => {
+        "A megadott commitment helyett már van újabb.".to_string()
+      }
+      DomainError::CommitmentExpired => "A kommitment érvényessége már lejárt!".to_string(),
+      DomainError::DiscountMismatch { applied, expected } => format!(
+        "A vásárlás kedvezménye ({}%) nem egyezik a kommitment kedvezményével ({}%)!",
+        applied, expected
+      ),
+      DomainError::DiscountNotAllowed { allowed } => format!(
+        "A kedvezmény mértéke nem megengedett! Megengedett értékek: {}",
+        percentages(allowed)
+      ),
+      DomainError::TargetTooLow {
+        discount_percentage,
+        min_target,
+      } => format!(
+        "A {}% kedvezményhez legalább {} célösszeg szükséges!",
+        discount_percentage, min_target
+      ),
+      DomainError::NoDiscountPolicy => "Nincs érvényes kedvezmény szabályzat!".to_string(),
+      DomainError::NoAllowedDiscount { target } => {
+        format!("Nincs a célösszeghez megengedett kedvezmény: {}", target)
+      }
+      DomainError::EmptyCategory => "A termékkategória nem lehet üres!".to_string(),
+      DomainError::DuplicateCategory(category) => {
+        format!("A termékkategória többször szerepel: {}", category)
+      }
+      DomainError::CategoryBreakdownMismatch => {
+        "A kategória bontás nem egyezik a vásárlás összegével!".to_string()
+      }
+      DomainError::BalanceOverflow => "A kommitment egyenlege túlcsordulna!".to_string(),
+      DomainError::InvalidValidityPeriod => {
+        "Az érvényesség kezdete nem lehet a vége után!".to_string()
+      }
+      DomainError::ActiveCommitmentExists => "A vásárlónak már van aktív kommitmentje!".to_string(),
+      DomainError::InvalidId(id) => format!("A kért ID hibás: {}", id),
+      DomainError::InvalidDate(dt) => format!("A megadott dátum hibás: {}", dt),
+      DomainError::IncompleteValidityPeriod => {
+        "Az érvényesség kezdetét és végét együtt kell megadni!".to_string()
+      }
+      DomainError::InvalidPeriod => "Az időszak kezdete nem lehet a vége után!".to_string(),
+      DomainError::UnknownBalanceBasis(s) => format!("Ismeretlen egyenleg alap: {}", s),
+      DomainError::UnknownCommitmentState(s) => format!("Ismeretlen kommitment állapot: {}", s),
+      DomainError::InvalidCursor(s) => format!("Hibás lapozási kurzor: {}", s),
+      DomainError::UnknownExportFormat(s) => format!("Ismeretlen export formátum: {}", s),
+      DomainError::InvalidFlag(key) => format!("Hibás {} érték!", key),
+      DomainError::ImportFieldCount(count) => {
+        format!("A sor 4 mezőt kell tartalmazzon, {} van benne!", count)
+      }
+      DomainError::ImportInvalidNumber(line) => format!("Hibás számérték a sorban: {}", line),
+      DomainError::DuplicateImportCustomer => "Az ügyfél már szerepel a betöltésben!".to_string(),
+      DomainError::DiscountOverflow => "A kedvezmény összege túlcsordulna!".to_string(),
+      DomainError::IdempotencyKeyReused => {
+        "Az idempotencia kulcs már egy másik kéréshez tartozik!".to_string()
+      }
+      DomainError::InvalidIdempotencyKey => "Hibás idempotencia kulcs!".to_string(),
+      DomainError::PermissionDenied { role } => {
+        format!("A művelethez nincs jogosultsága ({} szerepkör)!", role)
+      }
+    }
+  }
+
+  fn message_en(&self) -> String {
+    match self {
+      DomainError::CommitmentNotFound => {
+        "The given commitment ID is not found under the customer.".to_string()
+      }
+      DomainError::PurchaseNotFound => {
+        "The given purchase ID is not found in the commitment.".to_string()
+      }
+      DomainError::PurchaseAlreadyExists => {
+        "The given purchase is already in the purchase history!".to_string()
+      }
+      DomainError::NoActiveCommitment => {
+        "The customer has no active commitment, so the purchase cannot be added.".to_string()
+      }
+      DomainError::NoCommitment => "The customer has no commitment".to_string(),
+      DomainError::CommitmentSuperseded => {
+        "The given commitment has already been replaced by a newer one.".to_string()
+      }
+      DomainError::CommitmentExpired => "The commitment validity has already expired!".to_string(),
+      DomainError::DiscountMismatch { applied, expected } => format!(
+        "The purchase discount ({}%) does not match the commitment discount ({}%)!",
+        applied, expected
+      ),
+      DomainError::DiscountNotAllowed { allowed } => format!(
+        "The discount percentage is not allowed! Allowed values: {}",
+        percentages(allowed)
+      ),
+      DomainError::TargetTooLow {
+        discount_percentage,
+        min_target,
+      } => format!(
+        "The {}% discount requires a target of at least {}!",
+        discount_percentage, min_target
+      ),
+      DomainError::NoDiscountPolicy => "There is no discount policy in force!".to_string(),
+      DomainError::NoAllowedDiscount { target } => {
+        format!("There is no discount allowed for the target: {}", target)
+      }
+      DomainError::EmptyCategory => "The product category cannot be empty!".to_string(),
+      DomainError::DuplicateCategory(category) => {
+        format!("The product category is given more than once: {}", category)
+      }
+      DomainError::CategoryBreakdownMismatch => {
+        "The category breakdown does not match the purchase totals!".to_string()
+      }
+      DomainError::BalanceOverflow => "The commitment balance would overflow!".to_string(),
+      DomainError::InvalidValidityPeriod => {
+        "The validity start cannot be after its end!".to_string()
+      }
+      DomainError::ActiveCommitmentExists => {
+        "The customer already has an active commitment!".to_string()
+      }
+      DomainError::InvalidId(id) => format!("The requested ID is invalid: {}", id),
+      DomainError::InvalidDate(dt) => format!("The given date is invalid: {}", dt),
+      DomainError::IncompleteValidityPeriod => {
+        "The validity start and end must be given together!".to_string()
+      }
+      DomainError::InvalidPeriod => "The period start cannot be after its end!".to_string(),
+      DomainError::UnknownBalanceBasis(s) => format!("Unknown balance basis: {}", s),
+      DomainError::UnknownCommitmentState(s) => format!("Unknown commitment state: {}", s),
+      DomainError::InvalidCursor(s) => format!("Invalid paging cursor: {}", s),
+      DomainError::UnknownExportFormat(s) => format!("Unknown export format: {}", s),
+      DomainError::InvalidFlag(key) => format!("Invalid {} value!", key),
+      DomainError::ImportFieldCount(count) => {
+        format!("The row must have 4 fields, it has {}!", count)
+      }
+      DomainError::ImportInvalidNumber(line) => format!("Invalid number in the row: {}", line),
+      DomainError::DuplicateImportCustomer => "The customer is already in the import!".to_string(),
+      DomainError::DiscountOverflow => "The discount amount would overflow!".to_string(),
+      DomainError::IdempotencyKeyReused => {
+        "The idempotency key already belongs to another request!".to_string()
+      }
+      DomainError::InvalidIdempotencyKey => "Invalid idempotency key!".to_string(),
+      DomainError::PermissionDenied { role } => {
+        format!("You have no permission for the operation ({} role)!", role)
+      }
+    }
+  }
+
+  /// Status with the localized message, and the code
+  /// as google.rpc.ErrorInfo in the status details
+  pub fn to_status(&self, lang: Lang) -> Status {
+    let info = ErrorInfo {
+      reason: self.code().to_string(),
+      domain: ERROR_DOMAIN.to_string(),
+      metadata: HashMap::new(),
+    };
+    let details = RpcStatus {
+      code: self.status_code() as i32,
+      message: self.message(lang),
+      details: vec![Any {
+        type_url: ERROR_INFO_TYPE_URL.to_string(),
+        value: encode(&info),
+      }],
+    };
+    Status::with_details(
+      self.status_code(),
+      self.message(lang),
+      Bytes::from(encode(&details)),
+    )
+  }
+}
+
+// Hungarian by default, as the service always was
+impl std::fmt::Display for DomainError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.message(Lang::Hu))
+  }
+}
+
+fn percentages(values: &[u32]) -> String {
+  values
+    .iter()
+    .map(|p| format!("{}%", p))
+    .collect::<Vec<String>>()
+    .join(", ")
+}
+
+fn encode<M: Message>(message: &M) -> Vec<u8> {
+  let mut buf = Vec::with_capacity(message.encoded_len());
+  // Vec grows as needed, encoding cannot fail
+  let _ = message.encode(&mut buf);
+  buf
+}
+
+// google.rpc.Status
+#[derive(Clone, PartialEq, Message)]
+struct RpcStatus {
+  #[prost(int32, tag = "1")]
+  code: i32,
+  #[prost(string, tag = "2")]
+  message: String,
+  #[prost(message, repeated, tag = "3")]
+  details: Vec<Any>,
+}
+
+// google.protobuf.Any
+#[derive(Clone, PartialEq, Message)]
+struct Any {
+  #[prost(string, tag = "1")]
+  type_url: String,
+  #[prost(bytes, tag = "2")]
+  value: Vec<u8>,
+}
+
+// google.rpc.ErrorInfo
+#[derive(Clone, PartialEq, Message)]
+struct ErrorInfo {
+  #[prost(string, tag = "1")]
+  reason: String,
+  #[prost(string, tag = "2")]
+  domain: String,
+  #[prost(map = "string, string", tag = "3")]
+  metadata: HashMap<String, String>,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Error code from the details of a status, if any
+  fn error_code(status: &Status) -> Option<String> {
+    let details = RpcStatus::decode(status.details()).ok()?;
+    details
+      .details
+      .iter()
+      .filter(|d| d.type_url == ERROR_INFO_TYPE_URL)
+      .find_map(|d| ErrorInfo::decode(&d.value[..]).ok())
+      .map(|info| info.reason)
+  }
+
+  #[test]
+  fn test_lang() {
+    assert_eq!(Lang::parse("en-US,en;q=0.9,hu;q=0.8"), Lang::En);
+    assert_eq!(Lang::parse("EN"), Lang::En);
+    assert_eq!(Lang::parse("hu-HU"), Lang::Hu);
+    assert_eq!(Lang::parse("de"), Lang::Hu);
+    assert_eq!(Lang::from_request(&Request::new(())), Lang::Hu);
+  }
+
+  #[test]
+  fn test_to_status() {
+    let e = DomainError::DiscountMismatch {
+      applied: 3,
+      expected: 2,
+    };
+    let status = e.to_status(Lang::En);
+    assert_eq!(status.code(), Code::InvalidArgument);
+    assert_eq!(
+      status.message(),
+      "The purchase discount (3%) does not match the commitment discount (2%)!"
+    );
+    assert_eq!(error_code(&status).as_deref(), Some("DISCOUNT_MISMATCH"));
+
+    let status = e.to_status(Lang::Hu);
+    assert_eq!(status.message(), e.to_string());
+    assert_eq!(error_code(&status).as_deref(), Some("DISCOUNT_MISMATCH"));
+
+    let status = DomainError::PermissionDenied {
+      role: "cashier".to_string(),
+    }
+    .to_status(Lang::En);
+    assert_eq!(status.code(), Code::PermissionDenied);
+    assert_eq!(error_code(&status).as_deref(), Some("PERMISSION_DENIED"));
+
+    // Plain statuses have no code
+    assert_eq!(error_code(&Status::invalid_argument("x")), None);
+  }
+}
@@ -0,0 +1,244 @@
+use crate::commitment::{BalanceBasis, Commitment};
+use crate::error::DomainError;
+use crate::policy::{DiscountPolicy, Policy, RolloverRule};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+/// Target achievement status of a commitment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AchievementStatus {
+  // Target not reached yet, but projected balance reaches it
+  OnTrack,
+  // Target not reached yet, and projected balance is below it
+  AtRisk,
+  // Balance reached target
+  Achieved,
+  // Period is over and balance is below target
+  Missed,
+}
+
+impl std::fmt::Display for AchievementStatus {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      AchievementStatus::OnTrack => write!(f, "on_track"),
+      AchievementStatus::AtRisk => write!(f, "at_risk"),
+      AchievementStatus::Achieved => write!(f, "achieved"),
+      AchievementStatus::Missed => write!(f, "missed"),
+    }
+  }
+}
+
+/// Target achievement evaluation of a commitment
+#[derive(Debug, Clone, PartialEq)]
+pub struct Evaluation {
+  pub commitment_id: Uuid,
+  pub customer_id: u32,
+  pub target: u32,
+  pub balance: u32,
+  pub balance_basis: BalanceBasis, // Balance and projection count net or gross
+  pub achievement_percentage: u32, // Balance / target in percentage
+  pub projected_balance: u32,      // Projected balance at valid_till
+  pub status: AchievementStatus,
+  // Suggested discount for the next period
+  // None if no discount is allowed by the policy in force
+  pub suggested_discount_percentage: Option<u32>,
+}
+
+/// Achievement in percentage
+/// 0 target is always achieved
+pub fn achievement_percentage(balance: u32, target: u32) -> u32 {
+  match target {
+    0 => 100,
+    _ => (balance as u64 * 100 / target as u64).min(u32::MAX as u64) as u32,
+  }
+}
+
+/// Projected balance at the end of the validity period
+/// based on the run-rate since the first activity
+pub fn projected_balance(c: &Commitment, now: DateTime<Utc>) -> u32 {
+  // First activity is the creation or the first purchase
+  // of a withdrawn predecessor
+  let first_activity = c
+    .purchase_log
+    .iter()
+    .filter(|pi| !pi.removed)
+    .map(|pi| pi.crated_at)
+    .chain(std::iter::once(c.created_at))
+    .min()
+    .unwrap_or(c.created_at);
+  let start = c.valid_from.max(first_activity);
+  let end = c.valid_till;
+  let elapsed = (now.min(end) - start).num_seconds();
+  let remaining = (end - now).num_seconds();
+  if elapsed <= 0 || remaining <= 0 {
+    return c.balance;
+  }
+  let projected = c.balance as f64 + c.balance as f64 / elapsed as f64 * remaining as f64;
+  projected.min(u32::MAX as f64) as u32
+}
+
+/// Achievement status at the given time
+pub fn achievement_status(c: &Commitment, now: DateTime<Utc>) -> AchievementStatus {
+  if c.balance >= c.target {
+    return AchievementStatus::Achieved;
+  }
+  if c.valid_till <= now {
+    return AchievementStatus::Missed;
+  }
+  match projected_balance(c, now) >= c.target {
+    true => AchievementStatus::OnTrack,
+    false => AchievementStatus::AtRisk,
+  }
+}
+
+/// Discount percentage by moving between the tiers
+/// up if achieved, down otherwise
+pub fn step_discount(c: &Commitment, dp: &DiscountPolicy, promote_by: u32, demote_by: u32) -> u32 {
+  let mut tiers = dp.tiers.iter().map(|t| t.percentage).collect::<Vec<u32>>();
+  tiers.sort();
+  tiers.dedup();
+  if tiers.is_empty() {
+    return c.discount_percentage;
+  }
+  // Position of the current discount, or the closest lower tier
+  // if the current one is not allowed anymore
+  let pos = tiers
+    .iter()
+    .rposition(|p| *p <= c.discount_percentage)
+    .unwrap_or(0);
+  let next = match c.balance >= c.target {
+    true => (pos + promote_by as usize).min(tiers.len() - 1),
+    false => pos.saturating_sub(demote_by as usize),
+  };
+  tiers[next]
+}
+
+/// Highest allowed discount not above the candidate
+/// whose minimum target is reached by the target
+pub fn allowed_discount(candidate: u32, target: u32, dp: &DiscountPolicy) -> Option<u32> {
+  dp.tiers
+    .iter()
+    .filter(|t| t.percentage <= candidate && dp.validate(t.percentage, target).is_ok())
+    .map(|t| t.percentage)
+    .max()
+}
+
+/// Suggested discount percentage for the next period
+/// using the rollover rule; with carry rule it suggests
+/// one tier up or down, but rollover will not apply it
+pub fn suggest_discount(
+  c: &Commitment,
+  policy: &Policy,
+  now: DateTime<Utc>,
+) -> Result<u32, DomainError> {
+  let dp = policy
+    .discount_policy_at(now)
+    .ok_or(DomainError::NoDiscountPolicy)?;
+  let candidate = match policy.rollover.rule {
+    RolloverRule::Carry => step_discount(c, dp, 1, 1),
+    RolloverRule::PromoteDemote {
+      promote_by,
+      demote_by,
+    } => step_discount(c, dp, promote_by, demote_by),
+  };
+  allowed_discount(candidate, c.target, dp)
+    .ok_or(DomainError::NoAllowedDiscount { target: c.target })
+}
+
+/// Evaluate commitment target achievement
+/// Without a suggestion if the policy does not allow any
+pub fn evaluate(c: &Commitment, policy: &Policy, now: DateTime<Utc>) -> Evaluation {
+  Evaluation {
+    commitment_id: c.commitment_id,
+    customer_id: c.customer_id,
+    target: c.target,
+    balance: c.balance,
+    balance_basis: c.balance_basis,
+    achievement_percentage: achievement_percentage(c.balance, c.target),
+    projected_balance: projected_balance(c, now),
+    status: achievement_status(c, now),
+    suggested_discount_percentage: suggest_discount(c, policy, now).ok(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::commitment::{CommitmentOptions, Customer, CustomerExt, PurchaseInfo};
+  use crate::policy::ValidityPeriod;
+  use chrono::Duration;
+
+  // Customer with a commitment in a 100 days period, created at its start
+  fn new_customer(target: u32, elapsed_days: i64) -> (Customer, Uuid) {
+    let now = Utc::now();
+    let period = ValidityPeriod::new(
+      now - Duration::days(elapsed_days),
+      now + Duration::days(100 - elapsed_days),
+    )
+    .unwrap();
+    let options = CommitmentOptions {
+      validity: Some(period),
+      ..CommitmentOptions::default()
+    };
+    let mut customer = Customer::new(1, target, 3, 0, options, &Policy::default()).unwrap();
+    customer.commitments[0].created_at = period.valid_from;
+    let commitment_id = customer.commitments[0].commitment_id;
+    (customer, commitment_id)
+  }
+
+  #[test]
+  fn test_achievement() {
+    assert_eq!(achievement_percentage(50, 200), 25);
+    assert_eq!(achievement_percentage(300, 200), 150);
+    assert_eq!(achievement_percentage(0, 0), 100);
+  }
+
+  #[test]
+  fn test_evaluate() {
+    let now = Utc::now();
+    let policy = Policy::default();
+
+    // 25 days passed, 300 balance -> ~1200 projected
+    let (mut customer, id) = new_customer(1000, 25);
+    let c = customer
+      .add_purchase(id, PurchaseInfo::new(Uuid::new_v4(), 300, 300, 3))
+      .unwrap();
+    let e = evaluate(&c, &policy, now);
+    assert!(e.projected_balance >= 1190 && e.projected_balance <= 1210);
+    assert_eq!(e.status, AchievementStatus::OnTrack);
+    assert_eq!(e.achievement_percentage, 30);
+    assert_eq!(e.suggested_discount_percentage, Some(2));
+
+    // No policy in force, evaluation without suggestion
+    let no_policy = Policy {
+      discount_policies: Vec::new(),
+      ..Policy::default()
+    };
+    let e = evaluate(&c, &no_policy, now);
+    assert_eq!(e.achievement_percentage, 30);
+    assert_eq!(e.suggested_discount_percentage, None);
+    assert_eq!(
+      suggest_discount(&c, &no_policy, now),
+      Err(DomainError::NoDiscountPolicy)
+    );
+
+    // 50 days passed, 300 balance -> ~600 projected
+    let (mut customer, id) = new_customer(1000, 50);
+    let c = customer
+      .add_purchase(id, PurchaseInfo::new(Uuid::new_v4(), 300, 300, 3))
+      .unwrap();
+    assert_eq!(achievement_status(&c, now), AchievementStatus::AtRisk);
+    // Period is over
+    assert_eq!(
+      achievement_status(&c, now + Duration::days(60)),
+      AchievementStatus::Missed
+    );
+
+    let c = customer
+      .add_purchase(id, PurchaseInfo::new(Uuid::new_v4(), 700, 700, 3))
+      .unwrap();
+    let e = evaluate(&c, &policy, now);
+    assert_eq!(e.status, AchievementStatus::Achieved);
+    assert_eq!(e.suggested_discount_percentage, Some(4));
+  }
+}
@@ -0,0 +1,537 @@
+use crate::commitment::{Commitment, CommitmentExt, Customer, CustomerExt};
+use crate::error::{DomainError, Lang};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::io::Write;
+use uuid::Uuid;
+
+/// Size of the export chunks sent over gRPC
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// CSV columns; each record fills the ones it has
+pub const CSV_HEADER: [&str; 21] = [
+  "record",
+  "customer_id",
+  "commitment_id",
+  "target",
+  "discount_percentage",
+  "valid_from",
+  "valid_till",
+  "balance_basis",
+  "balance",
+  "status",
+  "created_at",
+  "created_by",
+  "purchase_id",
+  "added_to",
+  "total_net",
+  "total_gross",
+  "applied_discount",
+  "removed",
+  "removed_at",
+  "removed_by",
+  "reason",
+];
+
+/// Export file format
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+  Csv,
+  Jsonl, // JSON Lines
+}
+
+impl std::str::FromStr for ExportFormat {
+  type Err = DomainError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "csv" => Ok(ExportFormat::Csv),
+      "jsonl" | "json" => Ok(ExportFormat::Jsonl),
+      _ => Err(DomainError::UnknownExportFormat(s.to_string())),
+    }
+  }
+}
+
+/// Export filters; None means no filter
+#[derive(Debug, Clone, Default)]
+pub struct ExportFilter {
+  pub customer_id: Option<u32>,
+  // Commitments whose validity overlaps the period,
+  // and purchases created in it; till is exclusive
+  pub from: Option<DateTime<Utc>>,
+  pub till: Option<DateTime<Utc>>,
+}
+
+impl ExportFilter {
+  /// Filter from RFC3339 strings; empty string means no bound
+  pub fn new(customer_id: Option<u32>, from: &str, till: &str) -> Result<Self, DomainError> {
+    let parse = |dt: &str| match dt.is_empty() {
+      true => Ok(None),
+      false => DateTime::parse_from_rfc3339(dt)
+        .map(|d| Some(d.with_timezone(&Utc)))
+        .map_err(|_| DomainError::InvalidDate(dt.to_string())),
+    };
+    let res = Self {
+      customer_id,
+      from: parse(from)?,
+      till: parse(till)?,
+    };
+    if let (Some(from), Some(till)) = (res.from, res.till) {
+      if from >= till {
+        return Err(DomainError::InvalidPeriod);
+      }
+    }
+    Ok(res)
+  }
+
+  fn matches_commitment(&self, c: &Commitment) -> bool {
+    self.from.is_none_or(|from| c.valid_till > from)
+      && self.till.is_none_or(|till| c.valid_from < till)
+  }
+
+  fn matches_date(&self, dt: DateTime<Utc>) -> bool {
+    self.from.is_none_or(|from| dt >= from) && self.till.is_none_or(|till| dt < till)
+  }
+}
+
+/// One exported record
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum ExportRecord {
+  Customer {
+    customer_id: u32,
+    active_commitment_id: Option<Uuid>,
+  },
+  Commitment {
+    customer_id: u32,
+    commitment_id: Uuid,
+    target: u32,
+    discount_percentage: u32,
+    valid_from: DateTime<Utc>,
+    valid_till: DateTime<Utc>,
+    balance_basis: String,
+    balance: u32,
+    status: String, // active, withdrawn, expired or upcoming
+    created_at: DateTime<Utc>,
+    created_by: u32,
+  },
+  // Registered purchase, once
+  Purchase {
+    customer_id: u32,
+    commitment_id: Uuid, // Last successor, its removal state is exported
+    purchase_id: Uuid,
+    added_to: Uuid, // Commitment it was added to
+    total_net: u32,
+    total_gross: u32,
+    applied_discount: u32,
+    created_at: DateTime<Utc>,
+    removed: bool,
+    removed_at: Option<DateTime<Utc>>,
+    removed_by: Option<u32>,
+    reason: Option<String>,
+  },
+}
+
+// Commitment status at the given time
+fn status(c: &Commitment, now: DateTime<Utc>) -> &'static str {
+  match c {
+    c if c.is_withdrawn() => "withdrawn",
+    c if c.is_valid_at(now) => "active",
+    c if c.valid_till <= now => "expired",
+    _ => "upcoming",
+  }
+}
+
+// Quote CSV field if needed
+fn csv_field(value: String) -> String {
+  match value.contains([',', '"', '\n', '\r']) {
+    true => format!("\"{}\"", value.replace('"', "\"\"")),
+    false => value,
+  }
+}
+
+fn opt<T: ToString>(value: &Option<T>) -> String {
+  value.as_ref().map(|v| v.to_string()).unwrap_or_default()
+}
+
+impl ExportRecord {
+  /// CSV row in the order of CSV_HEADER
+  pub fn to_csv_row(&self) -> String {
+    let mut row = vec![String::new(); CSV_HEADER.len()];
+    match self {
+      ExportRecord::Customer {
+        customer_id,
+        active_commitment_id,
+      } => {
+        row[0] = "customer".to_string();
+        row[1] = customer_id.to_string();
+        row[2] = opt(active_commitment_id);
+      }
+      ExportRecord::Commitment {
+        customer_id,
+        commitment_id,
+        target,
+        discount_percentage,
+        valid_from,
+        valid_till,
+        balance_basis,
+        balance,
+        status,
+        created_at,
+        created_by,
+      } => {
+        row[0] = "commitment".to_string();
+        row[1] = customer_id.to_string();
+        row[2] = commitment_id.to_string();
+        row[3] = target.to_string();
+        row[4] = discount_percentage.to_string();
+        row[5] = valid_from.to_rfc3339();
+        row[6] = valid_till.to_rfc3339();
+        row[7] = balance_basis.clone();
+        row[8] = balance.to_string();
+        row[9] = status.clone();
+        row[10] = created_at.to_rfc3339();
+        row[11] = created_by.to_string();
+      }
+      ExportRecord::Purchase {
+        customer_id,
+        commitment_id,
+        purchase_id,
+        added_to,
+        total_net,
+        total_gross,
+        applied_discount,
+        created_at,
+        removed,
+        removed_at,
+        removed_by,
+        reason,
+      } => {
+        row[0] = "purchase".to_string();
+        row[1] = customer_id.to_string();
+        row[2] = commitment_id.to_string();
+        row[10] = created_at.to_rfc3339();
+        row[12] = purchase_id.to_string();
+        row[13] = added_to.to_string();
+        row[14] = total_net.to_string();
+        row[15] = total_gross.to_string();
+        row[16] = applied_discount.to_string();
+        row[17] = removed.to_string();
+        row[18] = removed_at.map(|d| d.to_rfc3339()).unwrap_or_default();
+        row[19] = opt(removed_by);
+        row[20] = opt(reason);
+      }
+    }
+    row
+      .into_iter()
+      .map(csv_field)
+      .collect::<Vec<String>>()
+      .join(",")
+  }
+}
+
+/// Records of a customer if it matches the filter: the customer,
+/// its matching commitments in creation order, each followed by
+/// the purchases added to it
+pub fn customer_records(
+  customer: &Customer,
+  filter: &ExportFilter,
+  now: DateTime<Utc>,
+) -> Vec<ExportRecord> {
+  if filter
+    .customer_id
+    .is_some_and(|id| customer.customer_id != id)
+  {
+    return Vec::new();
+  }
+  let commitments = customer
+    .commitment_views()
+    .into_iter()
+    .filter(|c| filter.matches_commitment(c))
+    .collect::<Vec<Commitment>>();
+  // Customers without commitments in the period are skipped
+  if commitments.is_empty() {
+    return Vec::new();
+  }
+  let mut res = vec![ExportRecord::Customer {
+    customer_id: customer.customer_id,
+    active_commitment_id: customer.get_active_commitment().map(|c| c.commitment_id),
+  }];
+  for c in commitments {
+    res.push(ExportRecord::Commitment {
+      customer_id: c.customer_id,
+      commitment_id: c.commitment_id,
+      target: c.target,
+      discount_percentage: c.discount_percentage,
+      valid_from: c.valid_from,
+      valid_till: c.valid_till,
+      balance_basis: c.balance_basis.to_string(),
+      balance: c.balance,
+      status: status(&c, now).to_string(),
+      created_at: c.created_at,
+      created_by: c.created_by,
+    });
+    let last = customer
+      .successors(&c.commitment_id)
+      .last()
+      .cloned()
+      .unwrap_or(c.commitment_id);
+    for p in customer
+      .purchases
+      .values()
+      .filter(|p| p.commitment_id == c.commitment_id && filter.matches_date(p.crated_at))
+    {
+      let removal = customer.current_removal(p);
+      res.push(ExportRecord::Purchase {
+        customer_id: c.customer_id,
+        commitment_id: last,
+        purchase_id: p.purchase_id,
+        added_to: p.commitment_id,
+        total_net: p.total_net,
+        total_gross: p.total_gross,
+        applied_discount: p.applied_discount,
+        created_at: p.crated_at,
+        removed: removal.is_some(),
+        removed_at: removal.and_then(|r| r.removed_at),
+        removed_by: removal.and_then(|r| r.removed_by),
+        reason: removal.and_then(|r| r.reason.clone()),
+      });
+    }
+  }
+  res
+}
+
+/// Records of the matching customers, commitments and purchases
+/// Ordered by customer ID, then by creation order
+pub fn records(
+  customers: &[Customer],
+  filter: &ExportFilter,
+  now: DateTime<Utc>,
+) -> Vec<ExportRecord> {
+  let mut customers = customers.iter().collect::<Vec<&Customer>>();
+  customers.sort_by_key(|c| c.customer_id);
+  customers
+    .into_iter()
+    .flat_map(|c| customer_records(c, filter, now))
+    .collect()
+}
+
+/// Header line of the export file if the format has any
+pub fn header(format: ExportFormat) -> Option<String> {
+  match format {
+    ExportFormat::Csv => Some(CSV_HEADER.join(",")),
+    ExportFormat::Jsonl => None,
+  }
+}
+
+/// Write the records in the given format, one per line
+pub fn write_records<W: Write>(
+  w: &mut W,
+  records: &[ExportRecord],
+  format: ExportFormat,
+) -> Result<(), String> {
+  for record in records {
+    let line = match format {
+      ExportFormat::Csv => record.to_csv_row(),
+      ExportFormat::Jsonl => serde_json::to_string(record).map_err(|e| e.to_string())?,
+    };
+    writeln!(w, "{}", line).map_err(io_err)?;
+  }
+  Ok(())
+}
+
+fn io_err(e: std::io::Error) -> String {
+  format!("Hiba az export írása közben: {}", e)
+}
+
+/// Write the export in the given format
+pub fn write<W: Write>(
+  w: &mut W,
+  customers: &[Customer],
+  filter: &ExportFilter,
+  format: ExportFormat,
+  now: DateTime<Utc>,
+) -> Result<(), String> {
+  if let Some(header) = header(format) {
+    writeln!(w, "{}", header).map_err(io_err)?;
+  }
+  write_records(w, &records(customers, filter, now), format)
+}
+
+/// Export CLI arguments
+/// export [--format csv|jsonl] [--customer ID] [--from RFC3339]
+///        [--till RFC3339] [--output PATH]
+pub struct ExportArgs {
+  pub format: ExportFormat,
+  pub filter: ExportFilter,
+  pub output: Option<std::path::PathBuf>, // stdout if None
+}
+
+impl ExportArgs {
+  pub fn parse(args: &[String]) -> Result<Self, String> {
+    let mut format = ExportFormat::Csv;
+    let mut customer_id = None;
+    let mut from = String::new();
+    let mut till = String::new();
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+      let mut value = || {
+        args
+          .next()
+          .cloned()
+          .ok_or_else(|| format!("Hiányzó érték: {}", arg))
+      };
+      match arg.as_str() {
+        "--format" => {
+          format = value()?
+            .parse()
+            .map_err(|e: DomainError| e.message(Lang::Hu))?
+        }
+        "--customer" => {
+          let v = value()?;
+          customer_id = Some(
+            v.parse::<u32>()
+              .map_err(|_| format!("Hibás ügyfél ID: {}", v))?,
+          );
+        }
+        "--from" => from = value()?,
+        "--till" => till = value()?,
+        "--output" => output = Some(std::path::PathBuf::from(value()?)),
+        _ => return Err(format!("Ismeretlen paraméter: {}", arg)),
+      }
+    }
+    Ok(Self {
+      format,
+      filter: ExportFilter::new(customer_id, &from, &till).map_err(|e| e.message(Lang::Hu))?,
+      output,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::commitment::{CommitmentOptions, PurchaseInfo, RemovalInfo};
+  use crate::policy::Policy;
+
+  fn customers() -> Vec<Customer> {
+    let policy = Policy::default();
+    let mut res = Vec::new();
+    for customer_id in (1..=2).rev() {
+      let mut customer = Customer::new(
+        customer_id,
+        1000,
+        2,
+        1,
+        CommitmentOptions::default(),
+        &policy,
+      )
+      .unwrap();
+      let id = customer.commitments[0].commitment_id;
+      customer
+        .add_purchase(id, PurchaseInfo::new(Uuid::new_v4(), 100, 127, 2))
+        .unwrap();
+      res.push(customer);
+    }
+    res
+  }
+
+  #[test]
+  fn test_export_csv() {
+    let mut customers = customers();
+    let purchase_id = customers[0].purchases[0].purchase_id;
+    let id = customers[0].commitments[0].commitment_id;
+    customers[0]
+      .remove_purchase(
+        id,
+        &purchase_id,
+        &RemovalInfo::new(1, Some("Hibás, \"storno\"".to_string())),
+      )
+      .unwrap();
+    let mut out = Vec::new();
+    write(
+      &mut out,
+      &customers,
+      &ExportFilter::default(),
+      ExportFormat::Csv,
+      Utc::now(),
+    )
+    .unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines = out.lines().collect::<Vec<&str>>();
+    assert_eq!(lines.len(), 7);
+    assert_eq!(lines[0], CSV_HEADER.join(","));
+    // Ordered by customer
+    assert!(lines[1].starts_with("customer,1,"));
+    assert!(lines[2].starts_with("commitment,1,"));
+    assert!(lines[2].contains(",gross,127,active,"));
+    assert!(lines[3].starts_with("purchase,1,"));
+    assert!(lines[4].starts_with("customer,2,"));
+    // Removed purchase with quoted reason
+    assert!(lines[6].contains(",true,"));
+    assert!(lines[6].ends_with(",1,\"Hibás, \"\"storno\"\"\""));
+  }
+
+  #[test]
+  fn test_export_purchases_once() {
+    let policy = Policy::default();
+    let mut customer = customers().pop().unwrap();
+    let first_id = customer.commitments[0].commitment_id;
+    let purchase_id = customer.purchases[0].purchase_id;
+    customer
+      .add_commitment(2000, 3, 1, CommitmentOptions::default(), &policy)
+      .unwrap();
+    let second_id = customer.commitments[1].commitment_id;
+    customer
+      .remove_purchase(second_id, &purchase_id, &RemovalInfo::new(1, None))
+      .unwrap();
+    let purchases = records(&[customer], &ExportFilter::default(), Utc::now())
+      .into_iter()
+      .filter(|r| matches!(r, ExportRecord::Purchase { .. }))
+      .collect::<Vec<ExportRecord>>();
+    assert_eq!(purchases.len(), 1);
+    match &purchases[0] {
+      ExportRecord::Purchase {
+        commitment_id,
+        added_to,
+        removed,
+        ..
+      } => {
+        assert_eq!(*commitment_id, second_id);
+        assert_eq!(*added_to, first_id);
+        assert!(removed);
+      }
+      _ => unreachable!(),
+    }
+  }
+
+  #[test]
+  fn test_export_jsonl_filter() {
+    let customers = customers();
+    let filter = ExportFilter::new(Some(2), "", "").unwrap();
+    let mut out = Vec::new();
+    write(
+      &mut out,
+      &customers,
+      &filter,
+      ExportFormat::Jsonl,
+      Utc::now(),
+    )
+    .unwrap();
+    let lines = String::from_utf8(out).unwrap();
+    let lines = lines
+      .lines()
+      .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+      .collect::<Vec<serde_json::Value>>();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0]["record"], "customer");
+    assert_eq!(lines[1]["customer_id"], 2);
+    assert_eq!(lines[2]["total_gross"], 127);
+    // Period without commitments
+    let filter = ExportFilter::new(None, "2000-01-01T00:00:00Z", "2000-02-01T00:00:00Z").unwrap();
+    assert!(records(&customers, &filter, Utc::now()).is_empty());
+    assert!(ExportFilter::new(None, "2000-02-01T00:00:00Z", "2000-01-01T00:00:00Z").is_err());
+    assert!("xml".parse::<ExportFormat>().is_err());
+  }
+}
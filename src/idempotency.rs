@@ -0,0 +1,361 @@
+use crate::error::DomainError;
+use crate::prelude::*;
+use chrono::{DateTime, Duration, Utc};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// gRPC metadata key of the idempotency key
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Expired responses are dropped this often
+pub const SWEEP_INTERVAL_SECS: u64 = 60;
+
+// Stored response of a completed call
+#[derive(Serialize, Deserialize, Clone)]
+struct Entry {
+  key: String,       // Method and idempotency key
+  request: Vec<u8>,  // Encoded request, to detect key reuse
+  response: Vec<u8>, // Encoded response
+  created_at: DateTime<Utc>,
+}
+
+type Slot = Arc<tokio::sync::Mutex<Option<Entry>>>;
+
+/// Store of responses by idempotency key
+/// Repeated calls with the same key return the original response
+/// instead of running again; responses expire after the TTL
+/// If opened from a file, responses are kept over restarts
+pub struct IdempotencyCache {
+  ttl: Duration,
+  // One slot per method and key; its lock serializes
+  // concurrent calls with the same key
+  slots: Mutex<HashMap<String, Slot>>,
+  file: Option<Arc<KeyFile>>,
+}
+
+// Append-only file of the stored responses, one JSON per line
+struct KeyFile {
+  path: PathBuf,
+  // Appended file and its number of lines
+  writer: Mutex<(File, usize)>,
+}
+
+impl KeyFile {
+  fn append(&self, entry: &Entry) -> Result<(), String> {
+    let mut line = serde_json::to_string(entry).map_err(|e| e.to_string())?;
+    line.push('\n');
+    let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+    writer
+      .0
+      .write_all(line.as_bytes())
+      .and_then(|_| writer.0.sync_data())
+      .map_err(|e| format!("Error while writing idempotency keys: {}", e))?;
+    writer.1 += 1;
+    Ok(())
+  }
+
+  // Rewrite the file with the unexpired entries only
+  // Appends wait meanwhile, so none of them is lost
+  fn compact(&self, ttl: Duration) -> Result<(), String> {
+    let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+    let entries = read_entries(&self.path, ttl)?;
+    let tmp = self.path.with_extension("tmp");
+    let mut file = File::create(&tmp).map_err(|e| e.to_string())?;
+    for entry in &entries {
+      let line = serde_json::to_string(entry).map_err(|e| e.to_string())?;
+      writeln!(file, "{}", line).map_err(|e| e.to_string())?;
+    }
+    file.sync_data().map_err(|e| e.to_string())?;
+    std::fs::rename(&tmp, &self.path).map_err(|e| e.to_string())?;
+    *writer = (append_file(&self.path)?, entries.len());
+    Ok(())
+  }
+}
+
+fn append_file(path: &Path) -> Result<File, String> {
+  OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(path)
+    .map_err(|e| format!("Error while opening {}: {}", path.display(), e))
+}
+
+// Unexpired entries of the file; malformed lines, e.g.
+// a partial last line left by a crash, are skipped
+fn read_entries(path: &Path, ttl: Duration) -> Result<Vec<Entry>, String> {
+  let now = Utc::now();
+  let file = match File::open(path) {
+    Ok(file) => file,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+    Err(e) => return Err(format!("Error while opening {}: {}", path.display(), e)),
+  };
+  let mut res = Vec::new();
+  for line in BufReader::new(file).lines() {
+    let line = line.map_err(|e| e.to_string())?;
+    match serde_json::from_str::<Entry>(&line) {
+      Ok(entry) if entry.created_at + ttl > now => res.push(entry),
+      Ok(_) => (),
+      Err(e) => tracing::warn!("Skipping malformed idempotency key: {}", e),
+    }
+  }
+  Ok(res)
+}
+
+impl IdempotencyCache {
+  /// Cache kept in memory only
+  #[cfg(test)]
+  pub fn new(ttl: Duration) -> Self {
+    Self {
+      ttl,
+      slots: Mutex::new(HashMap::new()),
+      file: None,
+    }
+  }
+
+  /// Open the cache with the responses stored in the given file
+  /// The file is compacted, and new responses are appended to it
+  pub fn open(ttl: Duration, path: PathBuf) -> Result<Self, String> {
+    let mut slots = HashMap::new();
+    for entry in read_entries(&path, ttl)? {
+      slots.insert(
+        entry.key.clone(),
+        Arc::new(tokio::sync::Mutex::new(Some(entry))),
+      );
+    }
+    let file = KeyFile {
+      writer: Mutex::new((append_file(&path)?, 0)),
+      path,
+    };
+    file.compact(ttl)?;
+    Ok(Self {
+      ttl,
+      slots: Mutex::new(slots),
+      file: Some(Arc::new(file)),
+    })
+  }
+
+  fn is_expired(&self, entry: &Entry, now: DateTime<Utc>) -> bool {
+    entry.created_at + self.ttl <= now
+  }
+
+  // Get or create the slot of the given key
+  fn slot(&self, key: String) -> Slot {
+    let mut slots = self.slots.lock().unwrap_or_else(|e| e.into_inner());
+    slots.entry(key).or_default().clone()
+  }
+
+  /// Drop the unused expired responses
+  /// The file is compacted if most of its lines are expired
+  pub async fn sweep(&self) {
+    let now = Utc::now();
+    let live = {
+      let mut slots = self.slots.lock().unwrap_or_else(|e| e.into_inner());
+      // Slots referenced only by the map are not in use;
+      // no new reference can be taken while the map is locked
+      slots.retain(|_, slot| {
+        if Arc::strong_count(slot) > 1 {
+          return true;
+        }
+        match slot.try_lock() {
+          Ok(entry) => match &*entry {
+            Some(entry) => !self.is_expired(entry, now),
+            None => false,
+          },
+          Err(_) => true,
+        }
+      });
+      slots.len()
+    };
+    if let Some(file) = &self.file {
+      let lines = file.writer.lock().unwrap_or_else(|e| e.into_inner()).1;
+      if lines > 2 * live {
+        let (file, ttl) = (file.clone(), self.ttl);
+        let res = tokio::task::spawn_blocking(move || file.compact(ttl))
+          .await
+          .map_err(|e| e.to_string())
+          .and_then(|res| res);
+        if let Err(e) = res {
+          tracing::error!("Error while compacting idempotency keys: {}", e);
+        }
+      }
+    }
+  }
+
+  /// Sweep periodically; never returns
+  pub async fn run_sweeper(self: Arc<Self>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(SWEEP_INTERVAL_SECS));
+    loop {
+      interval.tick().await;
+      self.sweep().await;
+    }
+  }
+
+  /// Run the call once per key
+  /// Without key the call always runs
+  pub async fn run<Req, Res, F, Fut>(
+    &self,
+    method: &str,
+    key: Option<String>,
+    request: &Req,
+    f: F,
+  ) -> ServiceResult<Res>
+  where
+    Req: Message,
+    Res: Message + Default,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = ServiceResult<Res>>,
+  {
+    let key = match key {
+      Some(key) => key,
+      None => return f().await,
+    };
+    let mut encoded_request = Vec::new();
+    request
+      .encode(&mut encoded_request)
+      .map_err(|e| ServiceError::internal_error(&e.to_string()))?;
+
+    let key = format!("{}:{}", method, key);
+    let slot = self.slot(key.clone());
+    let mut entry = slot.lock().await;
+    if let Some(e) = &*entry {
+      if !self.is_expired(e, Utc::now()) {
+        if e.request != encoded_request {
+          return Err(DomainError::IdempotencyKeyReused.into());
+        }
+        return Res::decode(&e.response[..])
+          .map_err(|e| ServiceError::internal_error(&e.to_string()));
+      }
+    }
+
+    // Only successful responses are stored,
+    // failed calls can be retried with the same key
+    let res = f().await?;
+    let mut encoded_response = Vec::new();
+    res
+      .encode(&mut encoded_response)
+      .map_err(|e| ServiceError::internal_error(&e.to_string()))?;
+    let new_entry = Entry {
+      key,
+      request: encoded_request,
+      response: encoded_response,
+      created_at: Utc::now(),
+    };
+    // The call is done already, so a write error cannot fail it;
+    // the key is kept in memory only
+    if let Some(file) = &self.file {
+      let (file, stored) = (file.clone(), new_entry.clone());
+      let res = tokio::task::spawn_blocking(move || file.append(&stored))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|res| res);
+      if let Err(e) = res {
+        tracing::error!("Error while storing idempotency key: {}", e);
+      }
+    }
+    *entry = Some(new_entry);
+    Ok(res)
+  }
+}
+
+/// Idempotency key from the request metadata if any
+pub fn idempotency_key<T>(request: &tonic::Request<T>) -> ServiceResult<Option<String>> {
+  match request.metadata().get(IDEMPOTENCY_KEY) {
+    Some(key) => {
+      let key = key
+        .to_str()
+        .map_err(|_| DomainError::InvalidIdempotencyKey)?
+        .trim();
+      match key.is_empty() {
+        true => Ok(None),
+        false => Ok(Some(key.to_string())),
+      }
+    }
+    None => Ok(None),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::proto::commitment::{CommitmentInfo, CustomerRequest};
+  use std::sync::atomic::{AtomicU32, Ordering};
+
+  async fn call(
+    cache: &IdempotencyCache,
+    key: Option<&str>,
+    customer_id: u32,
+    counter: &AtomicU32,
+  ) -> ServiceResult<CommitmentInfo> {
+    cache
+      .run(
+        "Test",
+        key.map(|k| k.to_string()),
+        &CustomerRequest { customer_id },
+        || async {
+          Ok(CommitmentInfo {
+            customer_id,
+            balance: counter.fetch_add(1, Ordering::SeqCst) + 1,
+            ..Default::default()
+          })
+        },
+      )
+      .await
+  }
+
+  #[tokio::test]
+  async fn test_idempotency() {
+    let cache = IdempotencyCache::new(Duration::hours(1));
+    let counter = AtomicU32::new(0);
+    // Repeated call returns the original response
+    let first = call(&cache, Some("k1"), 1, &counter).await.unwrap();
+    let retry = call(&cache, Some("k1"), 1, &counter).await.unwrap();
+    assert_eq!(first, retry);
+    assert_eq!(counter.load(Ordering::SeqCst), 1);
+    // Same key with another request
+    assert!(call(&cache, Some("k1"), 2, &counter).await.is_err());
+    // Without key or with new key it runs again
+    call(&cache, None, 1, &counter).await.unwrap();
+    call(&cache, Some("k2"), 1, &counter).await.unwrap();
+    assert_eq!(counter.load(Ordering::SeqCst), 3);
+  }
+
+  #[tokio::test]
+  async fn test_idempotency_ttl() {
+    let cache = IdempotencyCache::new(Duration::seconds(0));
+    let counter = AtomicU32::new(0);
+    call(&cache, Some("k1"), 1, &counter).await.unwrap();
+    call(&cache, Some("k1"), 1, &counter).await.unwrap();
+    assert_eq!(counter.load(Ordering::SeqCst), 2);
+    // Expired ones are dropped
+    cache.sweep().await;
+    assert_eq!(cache.slots.lock().unwrap().len(), 0);
+  }
+
+  #[tokio::test]
+  async fn test_idempotency_file() {
+    let path =
+      std::env::temp_dir().join(format!("idempotency_test_{}.jsonl", uuid::Uuid::new_v4()));
+    let counter = AtomicU32::new(0);
+    let cache = IdempotencyCache::open(Duration::hours(1), path.clone()).unwrap();
+    let first = call(&cache, Some("k1"), 1, &counter).await.unwrap();
+    drop(cache);
+
+    // Kept over restarts
+    let cache = IdempotencyCache::open(Duration::hours(1), path.clone()).unwrap();
+    let retry = call(&cache, Some("k1"), 1, &counter).await.unwrap();
+    assert_eq!(first, retry);
+    assert_eq!(counter.load(Ordering::SeqCst), 1);
+    assert!(call(&cache, Some("k1"), 2, &counter).await.is_err());
+
+    // Expired ones are not loaded, and compacted away
+    let cache = IdempotencyCache::open(Duration::seconds(0), path.clone()).unwrap();
+    assert_eq!(cache.slots.lock().unwrap().len(), 0);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+  }
+}
@@ -0,0 +1,342 @@
+use crate::commitment::{CommitmentExt, CommitmentOptions, CustomerExt};
+use crate::error::{DomainError, Lang};
+use crate::journal;
+use crate::policy::Policy;
+use crate::prelude::*;
+use crate::store::Store;
+use chrono::Utc;
+use std::collections::HashSet;
+use uuid::Uuid;
+
+/// gRPC metadata key of the dry-run flag
+pub const DRY_RUN_KEY: &str = "dry-run";
+
+/// gRPC metadata key of the flag allowing to withdraw
+/// active commitments by imported ones
+pub const REPLACE_ACTIVE_KEY: &str = "replace-active";
+
+/// Commitment to import
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportRow {
+  pub row: u32, // Row number in the batch, from 1
+  pub customer_id: u32,
+  pub target: u32,
+  pub discount_percentage: u32,
+  pub created_by: u32,
+}
+
+/// Result of an imported row
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportResult {
+  pub row: u32,
+  pub customer_id: u32,
+  // New commitment ID (None in dry-run) or the error
+  pub result: Result<Option<Uuid>, RowError>,
+}
+
+impl ImportResult {
+  fn error(row: u32, customer_id: u32, error: RowError) -> Self {
+    Self {
+      row,
+      customer_id,
+      result: Err(error),
+    }
+  }
+}
+
+/// Error of an imported row
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowError {
+  pub code: &'static str, // Stable code of domain errors, empty otherwise
+  pub message: String,    // In the requested language
+}
+
+impl RowError {
+  fn new(error: &ServiceError, lang: Lang) -> Self {
+    Self {
+      code: match error {
+        ServiceError::Domain(e) => e.code(),
+        _ => "",
+      },
+      message: error.message(lang),
+    }
+  }
+
+  fn domain(error: DomainError, lang: Lang) -> Self {
+    Self::new(&error.into(), lang)
+  }
+}
+
+/// Parse CSV rows of customer_id,target,discount_percentage,created_by
+/// Header line and empty lines are skipped; row numbers are line numbers
+/// Row errors are reported in the given language
+pub fn parse_csv(text: &str, lang: Lang) -> Vec<Result<ImportRow, ImportResult>> {
+  let mut res = Vec::new();
+  for (i, line) in text.lines().enumerate() {
+    let row = i as u32 + 1;
+    let line = line.trim();
+    if line.is_empty() || (row == 1 && line.starts_with("customer_id")) {
+      continue;
+    }
+    let fields = line.split(',').map(|f| f.trim()).collect::<Vec<&str>>();
+    if fields.len() != 4 {
+      res.push(Err(ImportResult::error(
+        row,
+        0,
+        RowError::domain(DomainError::ImportFieldCount(fields.len()), lang),
+      )));
+      continue;
+    }
+    let values = fields
+      .iter()
+      .map(|f| f.parse::<u32>())
+      .collect::<Result<Vec<u32>, _>>();
+    res.push(match values {
+      Ok(v) => Ok(ImportRow {
+        row,
+        customer_id: v[0],
+        target: v[1],
+        discount_percentage: v[2],
+        created_by: v[3],
+      }),
+      Err(_) => Err(ImportResult::error(
+        row,
+        fields[0].parse().unwrap_or(0),
+        RowError::domain(DomainError::ImportInvalidNumber(line.to_string()), lang),
+      )),
+    });
+  }
+  res
+}
+
+/// Import the rows one by one; a failed row does not abort the batch
+/// In dry-run each row is validated against the stored state
+/// but nothing is saved
+/// A customer can appear only once in a batch
+/// Rows of customers having an active or upcoming commitment
+/// fail, unless replace_active allows to withdraw it
+/// Domain errors are reported in the given language
+pub async fn import(
+  store: &Store,
+  policy: &Policy,
+  rows: Vec<Result<ImportRow, ImportResult>>,
+  dry_run: bool,
+  replace_active: bool,
+  lang: Lang,
+) -> Vec<ImportResult> {
+  let mut customer_ids = HashSet::new();
+  let mut res = Vec::new();
+  for row in rows {
+    let row = match row {
+      Ok(row) => row,
+      Err(e) => {
+        res.push(e);
+        continue;
+      }
+    };
+    if !customer_ids.insert(row.customer_id) {
+      res.push(ImportResult::error(
+        row.row,
+        row.customer_id,
+        RowError::domain(DomainError::DuplicateImportCustomer, lang),
+      ));
+      continue;
+    }
+    res.push(import_row(store, policy, &row, dry_run, replace_active, lang).await);
+  }
+  res
+}
+
+// Add the commitment of one row
+async fn import_row(
+  store: &Store,
+  policy: &Policy,
+  row: &ImportRow,
+  dry_run: bool,
+  replace_active: bool,
+  lang: Lang,
+) -> ImportResult {
+  let result = store
+    .upsert(row.customer_id, |customer| {
+      // Adding would withdraw it
+      let now = Utc::now();
+      let has_active = customer
+        .commitments
+        .last()
+        .is_some_and(|c| !c.is_withdrawn() && now < c.valid_till);
+      if has_active && !replace_active {
+        return Err(DomainError::ActiveCommitmentExists.into());
+      }
+      let from_index = customer.commitments.len();
+      customer.add_commitment(
+        row.target,
+        row.discount_percentage,
+        row.created_by,
+        CommitmentOptions::default(),
+        policy,
+      )?;
+      // No events, nothing is saved
+      if dry_run {
+        return Ok((None, Vec::new()));
+      }
+      let commitment_id = customer.commitments.last().map(|c| c.commitment_id);
+      Ok((
+        commitment_id,
+        journal::commitment_events(customer, from_index),
+      ))
+    })
+    .await
+    .map_err(|e| RowError::new(&e, lang));
+  ImportResult {
+    row: row.row,
+    customer_id: row.customer_id,
+    result,
+  }
+}
+
+/// Dry-run flag from the request metadata
+/// Only an explicit false disables the dry-run
+pub fn dry_run<T>(request: &tonic::Request<T>) -> ServiceResult<bool> {
+  flag(request, DRY_RUN_KEY, true)
+}
+
+/// Replace-active flag from the request metadata
+/// Only an explicit true allows to withdraw active commitments
+pub fn replace_active<T>(request: &tonic::Request<T>) -> ServiceResult<bool> {
+  flag(request, REPLACE_ACTIVE_KEY, false)
+}
+
+// Boolean metadata value, or the default if not given
+fn flag<T>(request: &tonic::Request<T>, key: &str, default: bool) -> ServiceResult<bool> {
+  match request.metadata().get(key) {
+    Some(value) => match value.to_str().map(|v| v.trim()) {
+      Ok("true") => Ok(true),
+      Ok("false") => Ok(false),
+      _ => Err(DomainError::InvalidFlag(key.to_string()).into()),
+    },
+    None => Ok(default),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::tests::temp_store;
+
+  #[test]
+  fn test_parse_csv() {
+    let rows = parse_csv(
+      "customer_id,target,discount_percentage,created_by\n1, 1000, 2, 9\n\n2,x,2,9\n3,1000\n",
+      Lang::En,
+    );
+    assert_eq!(rows.len(), 3);
+    assert_eq!(
+      rows[0],
+      Ok(ImportRow {
+        row: 2,
+        customer_id: 1,
+        target: 1000,
+        discount_percentage: 2,
+        created_by: 9,
+      })
+    );
+    assert_eq!(rows[1].as_ref().unwrap_err().row, 4);
+    assert_eq!(rows[1].as_ref().unwrap_err().customer_id, 2);
+    // Errors in the requested language, with their codes
+    assert_eq!(
+      rows[2].as_ref().unwrap_err().result,
+      Err(RowError {
+        code: "IMPORT_FIELD_COUNT",
+        message: "The row must have 4 fields, it has 2!".to_string(),
+      })
+    );
+  }
+
+  #[tokio::test]
+  async fn test_import() {
+    let store = temp_store();
+    let policy = Policy::default();
+    // Invalid discount and duplicate customer fail,
+    // the rest of the batch goes on
+    let csv = "1,1000,2,9\n2,1000,7,9\n1,2000,3,9\n3,1000,3,9\n";
+
+    let res = import(
+      &store,
+      &policy,
+      parse_csv(csv, Lang::Hu),
+      true,
+      false,
+      Lang::Hu,
+    )
+    .await;
+    assert_eq!(
+      res.iter().map(|r| r.result.is_ok()).collect::<Vec<bool>>(),
+      vec![true, false, false, true]
+    );
+    assert_eq!(res[0].result, Ok(None));
+    // Dry-run saves nothing
+    assert!(store.customer_ids().is_empty());
+
+    let res = import(
+      &store,
+      &policy,
+      parse_csv(csv, Lang::En),
+      false,
+      false,
+      Lang::En,
+    )
+    .await;
+    assert_eq!(
+      res[1].result,
+      Err(RowError {
+        code: "DISCOUNT_NOT_ALLOWED",
+        message:
+          "The discount percentage is not allowed! Allowed values: 0%, 1%, 2%, 3%, 4%, 5%, 6%"
+            .to_string(),
+      })
+    );
+    assert_eq!(
+      res[2].result.as_ref().unwrap_err().code,
+      "DUPLICATE_IMPORT_CUSTOMER"
+    );
+    assert_eq!(res.iter().filter(|r| r.result.is_ok()).count(), 2);
+    let commitment_id = res[0].result.clone().unwrap().unwrap();
+    assert!(store.get(1).unwrap().has_commitment(&commitment_id));
+    assert_eq!(store.customer_ids().len(), 2);
+    assert_eq!(store.journal().customer_events(3).unwrap().len(), 1);
+
+    // Active commitments are not withdrawn, not even in dry-run,
+    // unless explicitly allowed
+    let csv = "1,2000,3,9\n";
+    for dry_run in [true, false] {
+      let res = import(
+        &store,
+        &policy,
+        parse_csv(csv, Lang::Hu),
+        dry_run,
+        false,
+        Lang::Hu,
+      )
+      .await;
+      assert_eq!(
+        res[0].result,
+        Err(RowError::domain(
+          DomainError::ActiveCommitmentExists,
+          Lang::Hu
+        ))
+      );
+    }
+    assert_eq!(store.get(1).unwrap().commitments.len(), 1);
+    let res = import(
+      &store,
+      &policy,
+      parse_csv(csv, Lang::Hu),
+      false,
+      true,
+      Lang::Hu,
+    )
+    .await;
+    assert!(res[0].result.is_ok());
+    assert_eq!(store.get(1).unwrap().commitments.len(), 2);
+  }
+}
@@ -0,0 +1,486 @@
+use crate::commitment::{
+  Commitment, CommitmentStatus, Customer, CustomerExt, PurchaseInfo, RemovalInfo, SYSTEM_UID,
+};
+use crate::error::DomainError;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Customer commitment mutation
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum EventKind {
+  // New commitment; it refers to the purchases
+  // of its predecessor if any
+  CommitmentCreated {
+    commitment: Commitment,
+    predecessor: Option<Uuid>,
+  },
+  CommitmentWithdrawn {
+    commitment_id: Uuid,
+    successor: Uuid,
+  },
+  // Events without added_by are from before it was recorded
+  PurchaseAdded {
+    commitment_id: Uuid,
+    purchase: PurchaseInfo,
+    #[serde(default)]
+    added_by: Option<u32>,
+  },
+  // Removed from the commitment and all of its successors
+  PurchaseRemoved {
+    commitment_id: Uuid,
+    purchase_id: Uuid,
+    #[serde(default)]
+    removal: Option<RemovalInfo>,
+  },
+  // Restored in the commitment and all of its successors
+  PurchaseRestored {
+    commitment_id: Uuid,
+    purchase_id: Uuid,
+    #[serde(default)]
+    restored_by: Option<u32>,
+  },
+}
+
+impl EventKind {
+  /// Event type name
+  pub fn name(&self) -> &'static str {
+    match self {
+      EventKind::CommitmentCreated { .. } => "CommitmentCreated",
+      EventKind::CommitmentWithdrawn { .. } => "CommitmentWithdrawn",
+      EventKind::PurchaseAdded { .. } => "PurchaseAdded",
+      EventKind::PurchaseRemoved { .. } => "PurchaseRemoved",
+      EventKind::PurchaseRestored { .. } => "PurchaseRestored",
+    }
+  }
+}
+
+/// Journal entry
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Event {
+  pub event_id: Uuid,
+  pub customer_id: u32,
+  pub created_at: DateTime<Utc>,
+  pub kind: EventKind,
+}
+
+impl Event {
+  pub fn new(customer_id: u32, kind: EventKind) -> Self {
+    Self {
+      event_id: Uuid::new_v4(),
+      customer_id,
+      created_at: Utc::now(),
+      kind,
+    }
+  }
+}
+
+/// Append-only event journal stored as JSON lines
+pub struct Journal {
+  path: PathBuf,
+  writer: Mutex<Writer>,
+}
+
+// Journal file opened for append, with the
+// line offsets of the events by customer
+struct Writer {
+  file: File,
+  len: u64,
+  index: HashMap<u32, Vec<u64>>,
+}
+
+// Customer ID of an event, without parsing the rest
+#[derive(Deserialize)]
+struct EventCustomer {
+  customer_id: u32,
+}
+
+impl Journal {
+  /// Open journal file, create it if not exists
+  /// A partial last line left by a crash is truncated
+  pub fn open(path: PathBuf) -> Result<Self, String> {
+    if let Some(parent) = path.parent() {
+      std::fs::create_dir_all(parent)
+        .map_err(|e| format!("Error while creating journal dir: {}", e))?;
+    }
+    let file = OpenOptions::new()
+      .create(true)
+      .read(true)
+      .append(true)
+      .open(&path)
+      .map_err(|e| format!("Error while opening journal {}: {}", path.display(), e))?;
+    let mut index: HashMap<u32, Vec<u64>> = HashMap::new();
+    let len = for_each_line(&file, |offset, line| {
+      let event: EventCustomer = serde_json::from_slice(line).map_err(|e| e.to_string())?;
+      index.entry(event.customer_id).or_default().push(offset);
+      Ok(())
+    })
+    .map_err(|e| format!("Error while reading journal {}: {}", path.display(), e))?;
+    let file_len = file
+      .metadata()
+      .map_err(|e| format!("Error while reading journal: {}", e))?
+      .len();
+    if file_len > len {
+      tracing::warn!(
+        "Truncating partial last line of journal {} at {} bytes",
+        path.display(),
+        len
+      );
+      file
+        .set_len(len)
+        .map_err(|e| format!("Error while truncating journal: {}", e))?;
+    }
+    Ok(Self {
+      path,
+      writer: Mutex::new(Writer { file, len, index }),
+    })
+  }
+
+  /// Append events and sync them to disk
+  /// A failed write is truncated, so no partial line is left
+  pub fn append(&self, events: Vec<Event>) -> Result<(), String> {
+    let mut buf = String::new();
+    let mut offsets = Vec::new();
+    for event in &events {
+      offsets.push((event.customer_id, buf.len() as u64));
+      buf.push_str(&serde_json::to_string(event).map_err(|e| e.to_string())?);
+      buf.push('\n');
+    }
+    let mut writer = self
+      .writer
+      .lock()
+      .map_err(|_| "Journal lock poisoned".to_string())?;
+    let len = writer.len;
+    let res = writer
+      .file
+      .write_all(buf.as_bytes())
+      .and_then(|_| writer.file.sync_data());
+    if let Err(e) = res {
+      let _ = writer.file.set_len(len);
+      return Err(format!("Error while writing journal: {}", e));
+    }
+    for (customer_id, offset) in offsets {
+      writer
+        .index
+        .entry(customer_id)
+        .or_default()
+        .push(len + offset);
+    }
+    writer.len += buf.len() as u64;
+    Ok(())
+  }
+
+  /// true if no event has been written yet
+  pub fn is_empty(&self) -> Result<bool, String> {
+    let writer = self
+      .writer
+      .lock()
+      .map_err(|_| "Journal lock poisoned".to_string())?;
+    Ok(writer.len == 0)
+  }
+
+  /// Read all events of the given customer in order
+  /// Only the lines of the customer are read
+  pub fn customer_events(&self, customer_id: u32) -> Result<Vec<Event>, String> {
+    let offsets = match self.writer.lock() {
+      Ok(writer) => writer.index.get(&customer_id).cloned().unwrap_or_default(),
+      Err(_) => return Err("Journal lock poisoned".to_string()),
+    };
+    let file = File::open(&self.path)
+      .map_err(|e| format!("Error while opening journal {}: {}", self.path.display(), e))?;
+    let mut reader = BufReader::new(file);
+    let mut res = Vec::with_capacity(offsets.len());
+    let mut line = String::new();
+    for offset in offsets {
+      line.clear();
+      reader
+        .seek(SeekFrom::Start(offset))
+        .and_then(|_| reader.read_line(&mut line))
+        .map_err(|e| format!("Error while reading journal: {}", e))?;
+      res.push(
+        serde_json::from_str(&line)
+          .map_err(|e| format!("Error in journal at byte {}: {}", offset, e))?,
+      );
+    }
+    Ok(res)
+  }
+}
+
+// Visit the complete lines of a journal file with their offset
+// Empty lines are skipped, a partial last line is ignored
+// Returns the length of the complete lines
+fn for_each_line<F>(file: &File, mut f: F) -> Result<u64, String>
+where
+  F: FnMut(u64, &[u8]) -> Result<(), String>,
+{
+  let mut reader = BufReader::new(file);
+  let mut buf = Vec::new();
+  let mut offset = 0;
+  for i in 1.. {
+    buf.clear();
+    let n = reader
+      .read_until(b'\n', &mut buf)
+      .map_err(|e| e.to_string())?;
+    // End of file, or a partial last line
+    if n == 0 || buf.last() != Some(&b'\n') {
+      break;
+    }
+    let line = &buf[..n - 1];
+    if !line.iter().all(|b| b.is_ascii_whitespace()) {
+      f(offset, line).map_err(|e| format!("line {}: {}", i, e))?;
+    }
+    offset += n as u64;
+  }
+  Ok(offset)
+}
+
+/// Read all events from a journal file
+/// A partial last line, e.g. one being written, is ignored
+pub fn read_events(path: &Path) -> Result<Vec<Event>, String> {
+  let file = File::open(path)
+    .map_err(|e| format!("Error while opening journal {}: {}", path.display(), e))?;
+  let mut res = Vec::new();
+  for_each_line(&file, |_, line| {
+    res.push(serde_json::from_slice(line).map_err(|e| e.to_string())?);
+    Ok(())
+  })
+  .map_err(|e| format!("Error in journal {}", e))?;
+  Ok(res)
+}
+
+/// Events of the commitments added to the customer
+/// since it had the given number of commitments
+pub fn commitment_events(customer: &Customer, from_index: usize) -> Vec<Event> {
+  let mut res = Vec::new();
+  for (i, c) in customer.commitments.iter().enumerate().skip(from_index) {
+    // Check if it is the successor of the previous one
+    let predecessor = match i.checked_sub(1).map(|p| &customer.commitments[p]) {
+      Some(prev) => match prev.status {
+        CommitmentStatus::Withdrawn { successor } if successor == c.commitment_id => {
+          Some(prev.commitment_id)
+        }
+        _ => None,
+      },
+      None => None,
+    };
+    if let Some(predecessor) = predecessor {
+      res.push(Event::new(
+        customer.customer_id,
+        EventKind::CommitmentWithdrawn {
+          commitment_id: predecessor,
+          successor: c.commitment_id,
+        },
+      ));
+    }
+    res.push(Event::new(
+      customer.customer_id,
+      EventKind::CommitmentCreated {
+        commitment: c.clone(),
+        predecessor,
+      },
+    ));
+  }
+  res
+}
+
+/// Events recreating the current customer state as is
+/// Used to seed the journal with data existing before it
+pub fn snapshot_events(customer: &Customer) -> Vec<Event> {
+  let mut res = customer
+    .commitments
+    .iter()
+    .map(|c| {
+      Event::new(
+        customer.customer_id,
+        EventKind::CommitmentCreated {
+          commitment: c.clone(),
+          predecessor: None,
+        },
+      )
+    })
+    .collect::<Vec<Event>>();
+  // Registered purchases with their removal states
+  for pi in customer.purchases.values() {
+    res.push(Event::new(
+      customer.customer_id,
+      EventKind::PurchaseAdded {
+        commitment_id: pi.commitment_id,
+        purchase: pi.clone(),
+        added_by: None,
+      },
+    ));
+  }
+  res
+}
+
+/// Rebuild customer state purely from its events
+pub fn rebuild_customer(customer_id: u32, events: &[Event]) -> Result<Customer, DomainError> {
+  let mut customer = Customer {
+    customer_id,
+    ..Customer::default()
+  };
+  for event in events.iter().filter(|e| e.customer_id == customer_id) {
+    match &event.kind {
+      EventKind::CommitmentCreated {
+        commitment,
+        predecessor,
+      } => {
+        if let Some(predecessor) = predecessor {
+          // Check the predecessor exists
+          customer.get_commitment_mut(predecessor)?;
+        }
+        customer.commitments.push(commitment.clone());
+        // Snapshots seeded before the purchase registry
+        // have their purchase log in the commitment
+        if customer.needs_migration() {
+          customer.migrate();
+        }
+      }
+      EventKind::CommitmentWithdrawn {
+        commitment_id,
+        successor,
+      } => {
+        customer.set_withdrawn(commitment_id, *successor)?;
+      }
+      // Replay does not depend on the current time,
+      // so we register it without checking the active commitment
+      EventKind::PurchaseAdded {
+        commitment_id,
+        purchase,
+        ..
+      } => {
+        customer.insert_purchase(*commitment_id, purchase.clone())?;
+      }
+      EventKind::PurchaseRemoved {
+        commitment_id,
+        purchase_id,
+        removal,
+      } => {
+        // Events without removal info are from the system
+        let removal = removal.clone().unwrap_or(RemovalInfo {
+          removed_at: event.created_at,
+          removed_by: SYSTEM_UID,
+          reason: None,
+        });
+        customer.remove_purchase(*commitment_id, purchase_id, &removal)?;
+      }
+      EventKind::PurchaseRestored {
+        commitment_id,
+        purchase_id,
+        ..
+      } => {
+        customer.restore_purchase(*commitment_id, purchase_id)?;
+      }
+    }
+  }
+  Ok(customer)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::commitment::CommitmentOptions;
+  use crate::policy::{Policy, ValidityPeriod};
+
+  #[test]
+  fn test_rebuild_customer() {
+    let policy = Policy::default();
+    let mut events = Vec::new();
+
+    let mut customer = Customer::new(7, 1000, 2, 1, CommitmentOptions::default(), &policy).unwrap();
+    events.extend(commitment_events(&customer, 0));
+
+    let first_id = customer.commitments[0].commitment_id;
+    let purchase = PurchaseInfo::new(Uuid::new_v4(), 100, 127, 2);
+    let purchase_id = purchase.purchase_id;
+    customer.add_purchase(first_id, purchase.clone()).unwrap();
+    events.push(Event::new(
+      7,
+      EventKind::PurchaseAdded {
+        commitment_id: first_id,
+        purchase,
+        added_by: Some(1),
+      },
+    ));
+
+    customer
+      .add_commitment(2000, 3, 1, CommitmentOptions::default(), &policy)
+      .unwrap();
+    events.extend(commitment_events(&customer, 1));
+
+    let removal = RemovalInfo::new(1, Some("storno".to_string()));
+    customer
+      .remove_purchase(first_id, &purchase_id, &removal)
+      .unwrap();
+    events.push(Event::new(
+      7,
+      EventKind::PurchaseRemoved {
+        commitment_id: first_id,
+        purchase_id,
+        removal: Some(removal),
+      },
+    ));
+
+    // Successor starting later, with an explicit validity period
+    let starts = Utc::now() + chrono::Duration::days(10);
+    let options = CommitmentOptions {
+      validity: Some(ValidityPeriod::new(starts, starts + chrono::Duration::days(30)).unwrap()),
+      ..CommitmentOptions::default()
+    };
+    customer
+      .add_commitment(2000, 3, 1, options, &policy)
+      .unwrap();
+    events.extend(commitment_events(&customer, 2));
+
+    // Roundtrip through JSON
+    let events = events
+      .iter()
+      .map(|e| serde_json::from_str(&serde_json::to_string(e).unwrap()).unwrap())
+      .collect::<Vec<Event>>();
+
+    let rebuilt = rebuild_customer(7, &events).unwrap();
+    assert_eq!(
+      serde_json::to_string(&rebuilt).unwrap(),
+      serde_json::to_string(&customer).unwrap()
+    );
+
+    // Snapshot gives the same state
+    let rebuilt = rebuild_customer(7, &snapshot_events(&customer)).unwrap();
+    assert_eq!(
+      serde_json::to_string(&rebuilt).unwrap(),
+      serde_json::to_string(&customer).unwrap()
+    );
+  }
+
+  #[test]
+  fn test_partial_last_line() {
+    let policy = Policy::default();
+    let path = std::env::temp_dir().join(format!("commitment_journal_{}.jsonl", Uuid::new_v4()));
+    let first = Customer::new(1, 1000, 2, 1, CommitmentOptions::default(), &policy).unwrap();
+    let second = Customer::new(2, 1000, 2, 1, CommitmentOptions::default(), &policy).unwrap();
+    let journal = Journal::open(path.clone()).unwrap();
+    journal.append(snapshot_events(&first)).unwrap();
+    journal.append(snapshot_events(&second)).unwrap();
+    drop(journal);
+
+    // Crash in the middle of a write
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(b"{\"event_id\":").unwrap();
+    assert_eq!(read_events(&path).unwrap().len(), 2);
+
+    // Truncated at open, so appends start on a new line
+    let journal = Journal::open(path.clone()).unwrap();
+    journal.append(snapshot_events(&first)).unwrap();
+    assert_eq!(read_events(&path).unwrap().len(), 3);
+    // Read through the customer index
+    assert_eq!(journal.customer_events(1).unwrap().len(), 2);
+    assert_eq!(journal.customer_events(2).unwrap().len(), 1);
+    assert!(journal.customer_events(3).unwrap().is_empty());
+  }
+}
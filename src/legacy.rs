@@ -0,0 +1,256 @@
+use crate::commitment::{BalanceBasis, Commitment, CommitmentStatus, Customer, PurchaseInfo};
+use chrono::{DateTime, Utc};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// Records are stored by bincode, which has no field defaults,
+// so a record of an older format cannot be read as the current one.
+// Older formats are frozen here, and records failing to load
+// are decoded by them and converted by store::load_db;
+// the converted record is saved in the current format.
+// Never change these structs, add a new version instead.
+
+impl packman::TryFrom for Customer {
+  type TryFrom = CustomerV0;
+}
+
+/// Customer format of the first release
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct CustomerV0 {
+  pub customer_id: u32,
+  pub commitments: Vec<CommitmentV0>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum CommitmentStatusV0 {
+  Valid,
+  Withdrawn { successor: Uuid },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CommitmentV0 {
+  pub commitment_id: Uuid,
+  pub customer_id: u32,
+  pub target: u32,
+  pub discount_percentage: u32,
+  pub valid_till: DateTime<Utc>,
+  pub balance: u32,
+  pub purchase_log: Vec<PurchaseInfoV0>,
+  pub status: CommitmentStatusV0,
+  pub created_at: DateTime<Utc>,
+  pub created_by: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PurchaseInfoV0 {
+  pub purchase_id: Uuid,
+  pub total_net: u32,
+  pub total_gross: u32,
+  pub applied_discount: u32,
+  pub removed: bool,
+  pub crated_at: DateTime<Utc>,
+}
+
+impl From<CustomerV0> for Customer {
+  fn from(customer: CustomerV0) -> Self {
+    Self {
+      customer_id: customer.customer_id,
+      commitments: customer.commitments.into_iter().map(Into::into).collect(),
+      // Purchase logs are kept in the commitments,
+      // recalc-balances --apply moves them into the registry
+      purchases: IndexMap::new(),
+    }
+  }
+}
+
+impl From<CommitmentStatusV0> for CommitmentStatus {
+  fn from(status: CommitmentStatusV0) -> Self {
+    match status {
+      CommitmentStatusV0::Valid => CommitmentStatus::Valid,
+      // Withdrawals took effect at once
+      CommitmentStatusV0::Withdrawn { successor } => CommitmentStatus::Withdrawn { successor },
+    }
+  }
+}
+
+impl From<CommitmentV0> for Commitment {
+  fn from(c: CommitmentV0) -> Self {
+    Self {
+      commitment_id: c.commitment_id,
+      customer_id: c.customer_id,
+      target: c.target,
+      discount_percentage: c.discount_percentage,
+      // No start date, valid from the beginning
+      valid_from: DateTime::<Utc>::from(std::time::UNIX_EPOCH),
+      valid_till: c.valid_till,
+      // Discount applied to every product category
+      category_rules: Vec::new(),
+      // Balances counted gross value
+      balance_basis: BalanceBasis::Gross,
+      // Legacy format, stored balance and purchase log copy
+      balance: c.balance,
+      purchase_log: c.purchase_log.into_iter().map(Into::into).collect(),
+      status: c.status.into(),
+      created_at: c.created_at,
+      created_by: c.created_by,
+    }
+  }
+}
+
+impl From<PurchaseInfoV0> for PurchaseInfo {
+  fn from(pi: PurchaseInfoV0) -> Self {
+    Self {
+      purchase_id: pi.purchase_id,
+      total_net: pi.total_net,
+      total_gross: pi.total_gross,
+      applied_discount: pi.applied_discount,
+      removed: pi.removed,
+      crated_at: pi.crated_at,
+      // Removals were not recorded
+      removed_at: None,
+      removed_by: None,
+      reason: None,
+      // Not registered yet
+      commitment_id: Uuid::default(),
+      removals: Vec::new(),
+      // No category breakdown, the whole purchase is eligible
+      categories: Vec::new(),
+    }
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use crate::commitment::{CommitmentExt, CustomerExt};
+  use crate::discount;
+  use chrono::TimeZone;
+  use std::path::{Path, PathBuf};
+
+  // Write a customer as the first release did
+  pub fn save_v0(dir: &Path, customer: &CustomerV0) {
+    std::fs::create_dir_all(dir).unwrap();
+    packman::fs::PackFile::open_or_init(
+      &dir.join(customer.customer_id.to_string()),
+      0,
+      None,
+      None,
+      None,
+    )
+    .unwrap()
+    .write_data(&bincode::serialize(customer).unwrap())
+    .unwrap();
+  }
+
+  pub fn purchase_v0(total_gross: u32, removed: bool) -> PurchaseInfoV0 {
+    PurchaseInfoV0 {
+      purchase_id: Uuid::new_v4(),
+      total_net: 100,
+      total_gross,
+      applied_discount: 2,
+      removed,
+      crated_at: Utc.with_ymd_and_hms(2021, 3, 1, 10, 0, 0).unwrap(),
+    }
+  }
+
+  // Withdrawn commitment and its successor
+  // with a copy of the purchase log, as the first release stored them
+  pub fn customer_v0(customer_id: u32) -> CustomerV0 {
+    let (first_id, second_id) = (Uuid::new_v4(), Uuid::new_v4());
+    let log = vec![purchase_v0(127, false), purchase_v0(254, true)];
+    let commitment = |commitment_id, status, purchase_log: Vec<PurchaseInfoV0>| CommitmentV0 {
+      commitment_id,
+      customer_id,
+      target: 1000,
+      discount_percentage: 2,
+      valid_till: Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+      balance: 127,
+      purchase_log,
+      status,
+      created_at: Utc.with_ymd_and_hms(2021, 2, 1, 10, 0, 0).unwrap(),
+      created_by: 7,
+    };
+    CustomerV0 {
+      customer_id,
+      commitments: vec![
+        commitment(
+          first_id,
+          CommitmentStatusV0::Withdrawn {
+            successor: second_id,
+          },
+          log.clone(),
+        ),
+        commitment(second_id, CommitmentStatusV0::Valid, log),
+      ],
+    }
+  }
+
+  // Temporary directory, removed when dropped,
+  // also when an assert fails
+  struct TempDir(PathBuf);
+
+  impl Drop for TempDir {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.0);
+    }
+  }
+
+  #[test]
+  fn test_load_v0() {
+    let dir = TempDir(std::env::temp_dir().join(format!("commitment_legacy_{}", Uuid::new_v4())));
+    let v0 = customer_v0(1);
+    save_v0(&dir.0, &v0);
+    // Current format cannot read it
+    assert!(packman::Pack::<Customer>::load_from_path(dir.0.join("1")).is_err());
+
+    let db = crate::store::load_db(&dir.0).unwrap();
+    let mut customer = db.find_id(&1).unwrap().unpack().clone();
+    assert_eq!(customer.commitments.len(), 2);
+    let (first, second) = (&customer.commitments[0], &customer.commitments[1]);
+    assert_eq!(first.commitment_id, v0.commitments[0].commitment_id);
+    assert_eq!(
+      first.valid_from,
+      DateTime::<Utc>::from(std::time::UNIX_EPOCH)
+    );
+    assert_eq!(first.valid_till, v0.commitments[0].valid_till);
+    assert_eq!(first.created_at, v0.commitments[0].created_at);
+    assert_eq!(first.created_by, 7);
+    assert!(first.is_withdrawn());
+    assert!(
+      matches!(first.status, CommitmentStatus::Withdrawn { successor }
+      if successor == second.commitment_id)
+    );
+    assert!(!second.is_withdrawn());
+    assert_eq!(second.balance, 127);
+    assert_eq!(second.purchase_log.len(), 2);
+
+    // Removal: flag kept, who, when and why is unknown
+    let (kept, removed) = (&second.purchase_log[0], &second.purchase_log[1]);
+    assert!(!kept.removed);
+    assert!(removed.removed);
+    assert_eq!(removed.total_gross, 254);
+    assert_eq!(removed.removed_at, None);
+    assert_eq!(removed.removed_by, None);
+    assert_eq!(removed.reason, None);
+
+    // Categories: no rules, the whole purchase is eligible
+    assert!(second.category_rules.is_empty());
+    assert_eq!(second.category_discount("books"), 2);
+    assert!(!second.is_excluded("books"));
+    assert!(kept.categories.is_empty());
+    assert_eq!(discount::purchase_discount_percentage(second, kept), 2);
+
+    // Balance basis: derived balances count the gross value,
+    // as the stored ones did
+    assert_eq!(second.balance_basis, BalanceBasis::Gross);
+    assert_eq!(second.eligible_amount(kept), 127);
+    assert!(customer.migrate().is_empty());
+    for (c, v0) in customer.commitment_views().iter().zip(&v0.commitments) {
+      assert_eq!(c.balance, v0.balance);
+    }
+
+    // Saved in the current format
+    assert!(packman::Pack::<Customer>::load_from_path(dir.0.join("1")).is_ok());
+  }
+}
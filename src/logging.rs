@@ -0,0 +1,177 @@
+use crate::config::{LogFormat, LoggingConfig};
+use std::future::Future;
+use std::time::Instant;
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::{Code, Response, Status};
+use tracing::{field, Instrument, Span};
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+/// gRPC metadata key of the request ID
+/// Echoed in the response, so callers can correlate logs
+pub const REQUEST_ID_KEY: &str = "x-request-id";
+
+/// W3C trace context metadata key
+pub const TRACEPARENT_KEY: &str = "traceparent";
+
+/// Set up the global logger; logs are written to stderr
+pub fn init(config: &LoggingConfig) -> Result<(), String> {
+  let filter = EnvFilter::try_new(&config.level).map_err(|e| e.to_string())?;
+  let builder = tracing_subscriber::fmt()
+    .with_env_filter(filter)
+    .with_writer(std::io::stderr);
+  let res = match config.format {
+    LogFormat::Text => builder.try_init(),
+    LogFormat::Json => builder.json().with_current_span(true).try_init(),
+  };
+  res.map_err(|e| format!("Error while setting up logging: {}", e))
+}
+
+/// Request ID of an incoming call
+/// The x-request-id metadata if any, otherwise the trace ID
+/// of the traceparent metadata, otherwise a new one
+pub fn request_id(metadata: &MetadataMap) -> String {
+  let value = |key: &str| {
+    metadata
+      .get(key)
+      .and_then(|v| v.to_str().ok())
+      .map(|v| v.trim())
+      .filter(|v| !v.is_empty())
+  };
+  if let Some(id) = value(REQUEST_ID_KEY) {
+    return id.to_string();
+  }
+  // version-traceid-parentid-flags
+  if let Some(trace_id) = value(TRACEPARENT_KEY).and_then(|v| v.split('-').nth(1)) {
+    if trace_id.len() == 32 && trace_id.chars().all(|c| c.is_ascii_hexdigit()) {
+      return trace_id.to_string();
+    }
+  }
+  Uuid::new_v4().to_string()
+}
+
+/// Run a gRPC call in a request span, and log its outcome
+/// The response or the error status carries the request ID
+pub async fn traced<T, F>(method: &str, request_id: String, call: F) -> Result<Response<T>, Status>
+where
+  F: Future<Output = Result<Response<T>, Status>>,
+{
+  let span = tracing::info_span!(
+    "rpc",
+    method,
+    request_id = request_id.as_str(),
+    customer_id = field::Empty,
+    commitment_id = field::Empty,
+  );
+  let start = Instant::now();
+  let res = call.instrument(span.clone()).await;
+  let elapsed_ms = start.elapsed().as_millis() as u64;
+  span.in_scope(|| match &res {
+    Ok(_) => tracing::info!(elapsed_ms, outcome = "ok"),
+    Err(status) => match status.code() {
+      Code::Internal | Code::Unknown | Code::DataLoss => {
+        tracing::error!(elapsed_ms, outcome = ?status.code(), error = status.message())
+      }
+      _ => tracing::warn!(elapsed_ms, outcome = ?status.code(), error = status.message()),
+    },
+  });
+  // Errors carry it as well
+  let value = MetadataValue::from_str(&request_id).ok();
+  match res {
+    Ok(mut response) => {
+      if let Some(value) = value {
+        response.metadata_mut().insert(REQUEST_ID_KEY, value);
+      }
+      Ok(response)
+    }
+    Err(mut status) => {
+      if let Some(value) = value {
+        status.metadata_mut().insert(REQUEST_ID_KEY, value);
+      }
+      Err(status)
+    }
+  }
+}
+
+/// Add the customer ID to the current request span
+pub fn record_customer(customer_id: u32) {
+  Span::current().record("customer_id", customer_id);
+}
+
+/// Add the commitment ID to the current request span
+pub fn record_commitment(commitment_id: &str) {
+  Span::current().record("commitment_id", commitment_id);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn metadata(values: &[(&'static str, &str)]) -> MetadataMap {
+    let mut res = MetadataMap::new();
+    for (key, value) in values {
+      res.insert(*key, value.parse().unwrap());
+    }
+    res
+  }
+
+  #[test]
+  fn test_request_id() {
+    let trace_id = "4bf92f3577b34a0a9af7f0ddd3c8b2c1";
+    let traceparent = format!("00-{}-00f067aa0ba902b7-01", trace_id);
+    assert_eq!(
+      request_id(&metadata(&[(REQUEST_ID_KEY, "pos-42")])),
+      "pos-42"
+    );
+    // Request ID takes precedence over the trace context
+    assert_eq!(
+      request_id(&metadata(&[
+        (REQUEST_ID_KEY, "pos-42"),
+        (TRACEPARENT_KEY, &traceparent)
+      ])),
+      "pos-42"
+    );
+    assert_eq!(
+      request_id(&metadata(&[(TRACEPARENT_KEY, &traceparent)])),
+      trace_id
+    );
+    // Malformed trace context and no ID get a new one
+    let id = request_id(&metadata(&[(TRACEPARENT_KEY, "garbage")]));
+    assert!(Uuid::parse_str(&id).is_ok());
+    assert_ne!(request_id(&MetadataMap::new()), id);
+  }
+
+  #[tokio::test]
+  async fn test_traced() {
+    let res = traced("GetCustomer", "pos-42".to_string(), async {
+      record_customer(1);
+      Ok(Response::new(()))
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+      res
+        .metadata()
+        .get(REQUEST_ID_KEY)
+        .unwrap()
+        .to_str()
+        .unwrap(),
+      "pos-42"
+    );
+    let res: Result<Response<()>, Status> = traced("GetCustomer", "pos-42".to_string(), async {
+      Err(Status::not_found("x"))
+    })
+    .await;
+    let status = res.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+    assert_eq!(
+      status
+        .metadata()
+        .get(REQUEST_ID_KEY)
+        .unwrap()
+        .to_str()
+        .unwrap(),
+      "pos-42"
+    );
+  }
+}
@@ -1,79 +1,146 @@
-use commitment::{CommitmentExt, CustomerExt};
-use gzlib::proto::{
-  self,
-  commitment::{
-    commitment_server::{Commitment, CommitmentServer},
-    AddCommitmentRequest, AddPurchaseRequest, CommitmentInfo, CustomerBulkRequest, CustomerRequest,
-    RemovePurchaseRequest,
-  },
+use auth::Role;
+use chrono::{DateTime, Utc};
+use cli::Command;
+use commitment::{
+  BalanceBasis, CommitmentOptions, CustomerExt, PurchaseRemoval, PurchaseRestore, RemovalInfo,
 };
-use packman::VecPack;
-use packman::*;
+use config::Config;
+use error::{DomainError, Lang};
+use idempotency::{idempotency_key, IdempotencyCache};
+use journal::{Event, EventKind};
+use policy::{Policy, ValidityPeriod};
 use prelude::*;
-use proto::commitment::{CommitmentInfoResponse, CustomerIds, CustomerObj};
+use proto::commitment::{
+  commitment_server::{Commitment, CommitmentServer},
+  AddCommitmentRequest, AddPurchaseRequest, CalculateDiscountRequest, CalculateDiscountResponse,
+  CommitmentEvaluation, CommitmentEvent, CommitmentInfo, CommitmentInfoResponse,
+  CommitmentQueryItem, CustomerBulkRequest, CustomerIds, CustomerObj, CustomerRequest, ExportChunk,
+  ExportRequest, ImportCommitmentRow, ImportCommitmentsResponse, ImportRowResult, PortfolioReport,
+  PortfolioReportRequest, QueryCommitmentsRequest, RemovePurchaseRequest, RestorePurchaseRequest,
+};
+use query::{CommitmentQuery, CommitmentState};
 use std::error::Error;
-use std::path::PathBuf;
+use std::future::Future;
+use std::ops::Bound;
+use std::sync::Arc;
 use std::{env, str::FromStr};
-use tokio::sync::{oneshot, Mutex};
+use store::Store;
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::{transport::Server, Request, Response, Status};
+use tonic::{metadata::MetadataValue, transport::Server, Request, Response, Status};
 use uuid::Uuid;
 
+mod auth;
+mod cli;
 mod commitment;
+mod config;
+mod discount;
+mod error;
+mod evaluation;
+mod export;
+mod idempotency;
+mod import;
+mod journal;
+mod legacy;
+mod logging;
+mod metrics;
+mod policy;
 mod prelude;
+mod proto;
+mod query;
+mod report;
+mod rollover;
+mod store;
+mod tls;
 
 struct CommitmentService {
-  commitments: Mutex<VecPack<commitment::Customer>>,
+  store: Arc<Store>,
+  policy: Policy,
+  idempotency: Arc<IdempotencyCache>,
+  // Requests carry the caller verified by the auth interceptor
+  authenticated: bool,
 }
 
 impl CommitmentService {
-  fn init(commitments: VecPack<commitment::Customer>) -> Self {
+  fn init(
+    store: Arc<Store>,
+    policy: Policy,
+    idempotency: Arc<IdempotencyCache>,
+    authenticated: bool,
+  ) -> Self {
     Self {
-      commitments: Mutex::new(commitments),
+      store,
+      policy,
+      idempotency,
+      authenticated,
+    }
+  }
+
+  // Run a gRPC call in a request span, counted in the metrics
+  async fn call<T, F>(
+    &self,
+    method: &str,
+    request_id: String,
+    call: F,
+  ) -> Result<Response<T>, Status>
+  where
+    F: Future<Output = Result<Response<T>, Status>>,
+  {
+    logging::traced(method, request_id, self.store.metrics().rpc(method, call)).await
+  }
+
+  /// Acting user ID of a request
+  /// With authentication it is the verified caller, who must have
+  /// any of the given roles; otherwise the user ID given in the request
+  fn acting_user<T>(&self, request: &Request<T>, roles: &[Role], uid: u32) -> ServiceResult<u32> {
+    if !self.authenticated {
+      return Ok(uid);
     }
+    let caller = auth::caller(request)?;
+    caller.require(roles)?;
+    Ok(caller.uid)
   }
 
   /// Get all customer IDs
   async fn get_customer_ids(&self) -> ServiceResult<Vec<u32>> {
-    let res = self
-      .commitments
-      .lock()
-      .await
-      .iter()
-      .map(|c| c.unpack().customer_id)
-      .collect::<Vec<u32>>();
-    Ok(res)
+    Ok(self.store.customer_ids())
   }
 
   /// Add commitment
+  /// Creates the customer if not exists
   async fn add_commitment(&self, r: AddCommitmentRequest) -> ServiceResult<CustomerObj> {
-    // If we have a related customer object
-    if let Ok(customer) = self.commitments.lock().await.find_id_mut(&r.customer_id) {
-      let res = customer
-        .as_mut()
-        .unpack()
-        .add_commitment(r.target, r.discount_percentage, r.created_by)
-        .map_err(|e| ServiceError::bad_request(&e))?
-        .clone();
-      return Ok(res.into());
-    }
-
-    // Otherwise create a new customer
-    let new_customer =
-      commitment::Customer::new(r.customer_id, r.target, r.discount_percentage, r.created_by)
-        .map_err(|e| ServiceError::bad_request(&e))?;
-
-    // Insert to customer commitments DB
-    self.commitments.lock().await.insert(new_customer)?;
+    let options = CommitmentOptions {
+      // Explicit validity period if provided
+      validity: string_to_validity(&r.valid_from, &r.valid_till)?,
+      // Withdrawal copies forward the current rules if not provided
+      category_rules: match r.category_rules.is_empty() {
+        true => None,
+        false => Some(r.category_rules.iter().map(|c| c.clone().into()).collect()),
+      },
+      // Withdrawal copies forward the current basis if not provided
+      balance_basis: match r.balance_basis.is_empty() {
+        true => None,
+        false => Some(r.balance_basis.parse::<BalanceBasis>()?),
+      },
+    };
 
-    // Re-query it and return
     let res = self
-      .commitments
-      .lock()
-      .await
-      .find_id(&r.customer_id)?
-      .unpack()
-      .clone();
+      .store
+      .upsert(r.customer_id, |customer| {
+        let from_index = customer.commitments.len();
+        customer.add_commitment(
+          r.target,
+          r.discount_percentage,
+          r.created_by,
+          options,
+          &self.policy,
+        )?;
+        // Log withdrawal and the new commitment
+        Ok((
+          customer.clone(),
+          journal::commitment_events(customer, from_index),
+        ))
+      })
+      .await?;
 
     // Return res
     Ok(res.into())
@@ -81,13 +148,7 @@ impl CommitmentService {
 
   /// Get customer object
   async fn get_customer(&self, r: CustomerRequest) -> ServiceResult<CustomerObj> {
-    let res = self
-      .commitments
-      .lock()
-      .await
-      .find_id(&r.customer_id)?
-      .unpack()
-      .clone();
+    let res = self.store.get(r.customer_id)?;
     Ok(res.into())
   }
 
@@ -95,83 +156,376 @@ impl CommitmentService {
     &self,
     r: CustomerRequest,
   ) -> ServiceResult<CommitmentInfoResponse> {
-    let mut customer = self
-      .commitments
-      .lock()
-      .await
-      .find_id(&r.customer_id)?
-      .unpack()
-      .clone();
+    let customer = self.store.get(r.customer_id)?;
     Ok(CommitmentInfoResponse {
-      active_commitment: customer.get_active_commitment().map(|ac| ac.clone().into()),
+      active_commitment: customer.get_active_commitment().map(|ac| ac.into()),
       has_active_commitment: customer.has_active_commitment(),
     })
   }
 
+  /// Evaluate target achievement of the active commitment,
+  /// or the last one if there is no active commitment
+  async fn evaluate_commitment(&self, r: CustomerRequest) -> ServiceResult<CommitmentEvaluation> {
+    let customer = self.store.get(r.customer_id)?;
+    let commitment = match customer.get_active_commitment() {
+      Some(c) => c,
+      None => customer
+        .commitment_views()
+        .pop()
+        .ok_or(DomainError::NoCommitment)?,
+    };
+    let res = evaluation::evaluate(&commitment, &self.policy, Utc::now());
+    Ok(res.into())
+  }
+
+  /// Calculate basket discount using the active commitment
+  /// Unknown customers and customers without active commitment
+  /// get no discount
+  async fn calculate_discount(
+    &self,
+    r: CalculateDiscountRequest,
+  ) -> ServiceResult<CalculateDiscountResponse> {
+    let active_commitment = match self.store.get(r.customer_id) {
+      Ok(customer) => customer.get_active_commitment(),
+      Err(ServiceError::NotFound(_)) => None,
+      Err(e) => return Err(e),
+    };
+    let res = discount::calculate(
+      r.customer_id,
+      active_commitment.as_ref(),
+      r.lines.into_iter().map(|l| l.into()).collect(),
+    )?;
+    Ok(res.into())
+  }
+
   async fn has_active_commitment_bulk(
     &self,
     r: CustomerBulkRequest,
   ) -> ServiceResult<Vec<CommitmentInfo>> {
-    let mut res: Vec<CommitmentInfo> = Vec::new();
-    for customer in self
-      .commitments
-      .lock()
-      .await
-      .as_vec_mut()
-      .iter_mut()
-      .filter(|c| r.customer_ids.contains(&c.unpack().customer_id))
-    {
-      match customer.as_mut().unpack().get_active_commitment() {
-        Some(c) => res.push(c.clone().into()),
-        None => (),
-      }
-    }
+    let mut customer_ids = r.customer_ids;
+    customer_ids.sort_unstable();
+    customer_ids.dedup();
+    let customers = self.store.customers();
+    let res = customer_ids
+      .into_iter()
+      .filter_map(|id| customers.get(id))
+      .filter_map(|c| c.get_active_commitment())
+      .map(|c| c.into())
+      .collect::<Vec<CommitmentInfo>>();
     Ok(res)
   }
 
   async fn add_purchase(&self, r: AddPurchaseRequest) -> ServiceResult<CommitmentInfo> {
+    let customer_id = r.customer_id;
+    let added_by = r.added_by;
+    let commitment_id = string_to_uuid(r.commitment_id)?;
+    let purchase = commitment::PurchaseInfo::new(
+      string_to_uuid(r.purchase_id)?,
+      r.total_net,
+      r.total_gross,
+      r.applied_discount,
+    )
+    .with_categories(r.categories.iter().map(|c| c.clone().into()).collect());
     let res = self
-      .commitments
-      .lock()
-      .await
-      .find_id_mut(&r.customer_id)?
-      .as_mut()
-      .unpack()
-      .add_purchase(
-        string_to_uuid(r.commitment_id)?,
-        commitment::PurchaseInfo::new(
-          string_to_uuid(r.purchase_id)?,
-          r.total_net,
-          r.total_gross,
-          r.applied_discount,
-        ),
-      )
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
+      .store
+      .update(customer_id, |customer| {
+        let res = customer.add_purchase(commitment_id, purchase.clone())?;
+        let event = Event::new(
+          customer_id,
+          EventKind::PurchaseAdded {
+            commitment_id,
+            purchase,
+            added_by: Some(added_by),
+          },
+        );
+        Ok((res, vec![event]))
+      })
+      .await?;
     Ok(res.into())
   }
 
-  async fn remove_purchase(&self, r: RemovePurchaseRequest) -> ServiceResult<CommitmentInfo> {
+  async fn remove_purchase(
+    &self,
+    r: RemovePurchaseRequest,
+  ) -> ServiceResult<(CommitmentInfo, PurchaseRemoval)> {
+    let customer_id = r.customer_id;
+    let commitment_id = string_to_uuid(r.commitment_id)?;
+    let purchase_id = string_to_uuid(r.purchase_id)?;
+    // Empty reason means no reason
+    let reason = match r.reason.trim().is_empty() {
+      true => None,
+      false => Some(r.reason),
+    };
+    let removal = RemovalInfo::new(r.removed_by, reason);
     let res = self
-      .commitments
-      .lock()
-      .await
-      .find_id_mut(&r.customer_id)?
-      .as_mut()
-      .unpack()
-      .remove_purchase(
-        string_to_uuid(r.commitment_id)?,
-        &string_to_uuid(r.purchase_id)?,
-      )
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
-    Ok(res.into())
+      .store
+      .update(customer_id, |customer| {
+        // Removal is idempotent, so already removed purchases
+        // return the current commitment info as well
+        let (commitment, outcome) =
+          customer.remove_purchase(commitment_id, &purchase_id, &removal)?;
+        // Log only real changes
+        let events = match outcome {
+          PurchaseRemoval::Removed => vec![Event::new(
+            customer_id,
+            EventKind::PurchaseRemoved {
+              commitment_id,
+              purchase_id,
+              removal: Some(removal.clone()),
+            },
+          )],
+          PurchaseRemoval::AlreadyRemoved => Vec::new(),
+        };
+        Ok(((commitment, outcome), events))
+      })
+      .await?;
+    Ok((res.0.into(), res.1))
+  }
+
+  async fn restore_purchase(
+    &self,
+    r: RestorePurchaseRequest,
+  ) -> ServiceResult<(CommitmentInfo, PurchaseRestore)> {
+    let customer_id = r.customer_id;
+    let restored_by = r.restored_by;
+    let commitment_id = string_to_uuid(r.commitment_id)?;
+    let purchase_id = string_to_uuid(r.purchase_id)?;
+    let res = self
+      .store
+      .update(customer_id, |customer| {
+        // Restore is idempotent as well, not removed purchases
+        // return the current commitment info
+        let (commitment, outcome) = customer.restore_purchase(commitment_id, &purchase_id)?;
+        // Log only real changes
+        let events = match outcome {
+          PurchaseRestore::Restored => vec![Event::new(
+            customer_id,
+            EventKind::PurchaseRestored {
+              commitment_id,
+              purchase_id,
+              restored_by: Some(restored_by),
+            },
+          )],
+          PurchaseRestore::NotRemoved => Vec::new(),
+        };
+        Ok(((commitment, outcome), events))
+      })
+      .await?;
+    Ok((res.0.into(), res.1))
+  }
+
+  /// Commitment portfolio statistics of a period
+  async fn get_portfolio_report(
+    &self,
+    r: PortfolioReportRequest,
+  ) -> ServiceResult<PortfolioReport> {
+    let from = string_to_datetime(&r.from)?;
+    let till = string_to_datetime(&r.till)?;
+    let mut report = report::PortfolioBuilder::new(from, till)?;
+    // Customers are counted without copying them
+    self.store.scan(|customer| report.add(customer))?;
+    Ok(report.build().into())
+  }
+
+  /// Query commitments in stable order
+  async fn query_commitments(
+    &self,
+    r: QueryCommitmentsRequest,
+  ) -> ServiceResult<Vec<CommitmentQueryItem>> {
+    let q = string_to_query(r)?;
+    let now = Utc::now();
+    // Customers are read one by one under the storage lock,
+    // only the matching commitments are copied out
+    let res = query::query(self.store.customer_ids(), &q, |customer_id| {
+      self
+        .store
+        .read(customer_id, |customer| {
+          query::customer_matches(customer, &q, now)
+        })
+        .unwrap_or_default()
+    })
+    .into_iter()
+    .map(|(cursor, c)| CommitmentQueryItem {
+      commitment: Some(c.into()),
+      cursor: cursor.to_string(),
+    })
+    .collect::<Vec<CommitmentQueryItem>>();
+    Ok(res)
+  }
+
+  /// Export customers, commitments and purchases
+  /// in chunks of the export file, sent while it is written
+  /// Customers are read a batch at a time, so the storage is never copied
+  fn export_commitments(
+    &self,
+    r: ExportRequest,
+  ) -> ServiceResult<ReceiverStream<Result<ExportChunk, Status>>> {
+    let format = r.format.parse::<export::ExportFormat>()?;
+    let filter = export::ExportFilter::new(r.customer_id, &r.from, &r.till)?;
+    let store = self.store.clone();
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    tokio::spawn(async move {
+      let now = Utc::now();
+      let (mut from, till) = match filter.customer_id {
+        Some(id) => (Bound::Included(id), Bound::Included(id)),
+        None => (Bound::Unbounded, Bound::Unbounded),
+      };
+      let mut data = Vec::new();
+      if let Some(header) = export::header(format) {
+        data.extend(header.into_bytes());
+        data.push(b'\n');
+      }
+      loop {
+        // A batch of customers is written under the storage lock,
+        // the lock is released before sending
+        let last = {
+          let customers = store.customers();
+          let mut last = Ok(None);
+          for c in customers.range((from, till)).take(store::SCAN_BATCH) {
+            let records = export::customer_records(c, &filter, now);
+            if let Err(e) = export::write_records(&mut data, &records, format) {
+              last = Err(e);
+              break;
+            }
+            last = Ok(Some(c.customer_id));
+          }
+          last
+        };
+        match last {
+          Ok(Some(customer_id)) => from = Bound::Excluded(customer_id),
+          Ok(None) => break,
+          Err(e) => {
+            let _ = tx.send(Err(ServiceError::internal_error(&e).into())).await;
+            return;
+          }
+        }
+        // Full chunks are sent at once
+        while data.len() >= export::CHUNK_SIZE {
+          let rest = data.split_off(export::CHUNK_SIZE);
+          if tx.send(Ok(ExportChunk { data })).await.is_err() {
+            return;
+          }
+          data = rest;
+        }
+      }
+      if !data.is_empty() {
+        let _ = tx.send(Ok(ExportChunk { data })).await;
+      }
+    });
+    Ok(ReceiverStream::new(rx))
+  }
+
+  /// Import commitments row by row
+  async fn import_commitments(
+    &self,
+    rows: Vec<ImportCommitmentRow>,
+    dry_run: bool,
+    replace_active: bool,
+    lang: Lang,
+  ) -> ServiceResult<ImportCommitmentsResponse> {
+    let rows = rows
+      .into_iter()
+      .enumerate()
+      .map(|(i, r)| {
+        Ok(import::ImportRow {
+          row: i as u32 + 1,
+          customer_id: r.customer_id,
+          target: r.target,
+          discount_percentage: r.discount_percentage,
+          created_by: r.created_by,
+        })
+      })
+      .collect();
+    let results = import::import(
+      &self.store,
+      &self.policy,
+      rows,
+      dry_run,
+      replace_active,
+      lang,
+    )
+    .await;
+    let failed = results.iter().filter(|r| r.result.is_err()).count() as u32;
+    Ok(ImportCommitmentsResponse {
+      dry_run,
+      succeeded: results.len() as u32 - failed,
+      failed,
+      results: results
+        .into_iter()
+        .map(|r| r.into())
+        .collect::<Vec<ImportRowResult>>(),
+    })
+  }
+
+  /// Get customer events in order
+  async fn get_customer_events(&self, r: CustomerRequest) -> ServiceResult<Vec<CommitmentEvent>> {
+    let res = self
+      .store
+      .journal()
+      .customer_events(r.customer_id)
+      .map_err(|e| ServiceError::internal_error(&e))?
+      .into_iter()
+      .map(|e| e.into())
+      .collect::<Vec<CommitmentEvent>>();
+    Ok(res)
   }
 }
 
 // Helper to try convert string to UUID
 fn string_to_uuid(id: String) -> ServiceResult<Uuid> {
-  Uuid::from_str(&id).map_err(|_| ServiceError::BadRequest(format!("A kért ID hibás: {}", id)))
+  Uuid::from_str(&id).map_err(|_| DomainError::InvalidId(id).into())
+}
+
+// Helper to try convert RFC3339 strings to explicit validity period
+// Both empty means no explicit period
+fn string_to_validity(valid_from: &str, valid_till: &str) -> ServiceResult<Option<ValidityPeriod>> {
+  match (valid_from.is_empty(), valid_till.is_empty()) {
+    (true, true) => Ok(None),
+    (false, false) => {
+      let from = string_to_datetime(valid_from)?;
+      let till = string_to_datetime(valid_till)?;
+      Ok(Some(ValidityPeriod::new(from, till)?))
+    }
+    _ => Err(DomainError::IncompleteValidityPeriod.into()),
+  }
+}
+
+// Helper to try convert query request to query
+// Empty strings mean no filter
+fn string_to_query(r: QueryCommitmentsRequest) -> ServiceResult<CommitmentQuery> {
+  let optional_datetime = |dt: &str| match dt.is_empty() {
+    true => Ok(None),
+    false => string_to_datetime(dt).map(Some),
+  };
+  Ok(CommitmentQuery {
+    states: r
+      .states
+      .iter()
+      .map(|s| s.parse::<CommitmentState>())
+      .collect::<Result<Vec<CommitmentState>, DomainError>>()?,
+    discount_percentage: r.discount_percentage,
+    target_min: r.target_min,
+    target_max: r.target_max,
+    balance_min: r.balance_min,
+    balance_max: r.balance_max,
+    achievement_min: r.achievement_min,
+    achievement_max: r.achievement_max,
+    created_from: optional_datetime(&r.created_from)?,
+    created_till: optional_datetime(&r.created_till)?,
+    created_by: r.created_by,
+    after: match r.cursor.is_empty() {
+      true => None,
+      false => Some(r.cursor.parse::<query::Cursor>()?),
+    },
+    limit: r.limit as usize,
+  })
+}
+
+// Helper to try convert RFC3339 string to DateTime
+fn string_to_datetime(dt: &str) -> ServiceResult<DateTime<Utc>> {
+  DateTime::parse_from_rfc3339(dt)
+    .map(|d| d.with_timezone(&Utc))
+    .map_err(|_| DomainError::InvalidDate(dt.to_string()).into())
 }
 
 #[tonic::async_trait]
@@ -180,32 +534,116 @@ impl Commitment for CommitmentService {
     &self,
     request: Request<()>,
   ) -> Result<Response<proto::commitment::CustomerIds>, Status> {
-    let customer_ids = self.get_customer_ids().await?;
-    Ok(Response::new(CustomerIds { customer_ids }))
+    let request_id = logging::request_id(request.metadata());
+    self
+      .call("GetCustomerIds", request_id, async move {
+        let customer_ids = self.get_customer_ids().await?;
+        Ok(Response::new(CustomerIds { customer_ids }))
+      })
+      .await
   }
 
   async fn add_commitment(
     &self,
     request: Request<proto::commitment::AddCommitmentRequest>,
   ) -> Result<Response<proto::commitment::CustomerObj>, Status> {
-    let res = self.add_commitment(request.into_inner()).await?;
-    Ok(Response::new(res))
+    let request_id = logging::request_id(request.metadata());
+    self
+      .call("AddCommitment", request_id, async move {
+        logging::record_customer(request.get_ref().customer_id);
+        let lang = Lang::from_request(&request);
+        // Only managers can create and withdraw commitments
+        let created_by = self
+          .acting_user(&request, &[Role::Manager], request.get_ref().created_by)
+          .localize(lang)?;
+        // Retries with the same key return the original response
+        let key = idempotency_key(&request).localize(lang)?;
+        let mut r = request.into_inner();
+        r.created_by = created_by;
+        let res = self
+          .idempotency
+          .run("AddCommitment", key, &r, || self.add_commitment(r.clone()))
+          .await
+          .localize(lang)?;
+        // The new commitment is the last one
+        if let Some(c) = res.commitments.last() {
+          logging::record_commitment(&c.commitment_id);
+        }
+        Ok(Response::new(res))
+      })
+      .await
   }
 
   async fn get_customer(
     &self,
     request: Request<proto::commitment::CustomerRequest>,
   ) -> Result<Response<proto::commitment::CustomerObj>, Status> {
-    let res = self.get_customer(request.into_inner()).await?;
-    Ok(Response::new(res))
+    let request_id = logging::request_id(request.metadata());
+    self
+      .call("GetCustomer", request_id, async move {
+        logging::record_customer(request.get_ref().customer_id);
+        let lang = Lang::from_request(&request);
+        let res = self
+          .get_customer(request.into_inner())
+          .await
+          .localize(lang)?;
+        Ok(Response::new(res))
+      })
+      .await
   }
 
   async fn has_active_commitment(
     &self,
     request: Request<proto::commitment::CustomerRequest>,
   ) -> Result<Response<proto::commitment::CommitmentInfoResponse>, Status> {
-    let res = self.has_active_commitment(request.into_inner()).await?;
-    Ok(Response::new(res))
+    let request_id = logging::request_id(request.metadata());
+    self
+      .call("HasActiveCommitment", request_id, async move {
+        logging::record_customer(request.get_ref().customer_id);
+        let lang = Lang::from_request(&request);
+        let res = self
+          .has_active_commitment(request.into_inner())
+          .await
+          .localize(lang)?;
+        Ok(Response::new(res))
+      })
+      .await
+  }
+
+  async fn evaluate_commitment(
+    &self,
+    request: Request<proto::commitment::CustomerRequest>,
+  ) -> Result<Response<proto::commitment::CommitmentEvaluation>, Status> {
+    let request_id = logging::request_id(request.metadata());
+    self
+      .call("EvaluateCommitment", request_id, async move {
+        logging::record_customer(request.get_ref().customer_id);
+        let lang = Lang::from_request(&request);
+        let res = self
+          .evaluate_commitment(request.into_inner())
+          .await
+          .localize(lang)?;
+        Ok(Response::new(res))
+      })
+      .await
+  }
+
+  async fn calculate_discount(
+    &self,
+    request: Request<proto::commitment::CalculateDiscountRequest>,
+  ) -> Result<Response<proto::commitment::CalculateDiscountResponse>, Status> {
+    let request_id = logging::request_id(request.metadata());
+    self
+      .call("CalculateDiscount", request_id, async move {
+        logging::record_customer(request.get_ref().customer_id);
+        let lang = Lang::from_request(&request);
+        let res = self
+          .calculate_discount(request.into_inner())
+          .await
+          .localize(lang)?;
+        Ok(Response::new(res))
+      })
+      .await
   }
 
   type HasActiveCommitmentBulkStream = ReceiverStream<Result<CommitmentInfo, Status>>;
@@ -214,76 +652,737 @@ impl Commitment for CommitmentService {
     &self,
     request: Request<proto::commitment::CustomerBulkRequest>,
   ) -> Result<Response<Self::HasActiveCommitmentBulkStream>, Status> {
-    // Create channel for stream response
-    let (mut tx, rx) = tokio::sync::mpsc::channel(100);
+    let request_id = logging::request_id(request.metadata());
+    self
+      .call("HasActiveCommitmentBulk", request_id, async move {
+        let lang = Lang::from_request(&request);
+        // Create channel for stream response
+        let (tx, rx) = tokio::sync::mpsc::channel(100);
 
-    // Get resources as Vec<SourceObject>
-    let res = self
-      .has_active_commitment_bulk(request.into_inner())
-      .await?;
+        // Get resources as Vec<SourceObject>
+        let res = self
+          .has_active_commitment_bulk(request.into_inner())
+          .await
+          .localize(lang)?;
 
-    // Send the result items through the channel
-    tokio::spawn(async move {
-      for ots in res.into_iter() {
-        tx.send(Ok(ots)).await.unwrap();
-      }
-    });
+        // Send the result items through the channel
+        tokio::spawn(async move {
+          for ots in res.into_iter() {
+            if tx.send(Ok(ots)).await.is_err() {
+              break;
+            }
+          }
+        });
+
+        // Send back the receiver
+        Ok(Response::new(ReceiverStream::new(rx)))
+      })
+      .await
+  }
 
-    // Send back the receiver
-    Ok(Response::new(ReceiverStream::new(rx)))
+  async fn get_portfolio_report(
+    &self,
+    request: Request<PortfolioReportRequest>,
+  ) -> Result<Response<PortfolioReport>, Status> {
+    let request_id = logging::request_id(request.metadata());
+    self
+      .call("GetPortfolioReport", request_id, async move {
+        let lang = Lang::from_request(&request);
+        // Portfolio data is for managers only
+        self
+          .acting_user(&request, &[Role::Manager], 0)
+          .localize(lang)?;
+        let res = self
+          .get_portfolio_report(request.into_inner())
+          .await
+          .localize(lang)?;
+        Ok(Response::new(res))
+      })
+      .await
+  }
+
+  type QueryCommitmentsStream = ReceiverStream<Result<CommitmentQueryItem, Status>>;
+
+  async fn query_commitments(
+    &self,
+    request: Request<proto::commitment::QueryCommitmentsRequest>,
+  ) -> Result<Response<Self::QueryCommitmentsStream>, Status> {
+    let request_id = logging::request_id(request.metadata());
+    self
+      .call("QueryCommitments", request_id, async move {
+        let lang = Lang::from_request(&request);
+        self
+          .acting_user(&request, &[Role::Manager], 0)
+          .localize(lang)?;
+        // Create channel for stream response
+        let (tx, rx) = tokio::sync::mpsc::channel(100);
+
+        // Get one page of commitments
+        let res = self
+          .query_commitments(request.into_inner())
+          .await
+          .localize(lang)?;
+
+        // Send the result items through the channel
+        tokio::spawn(async move {
+          for item in res.into_iter() {
+            if tx.send(Ok(item)).await.is_err() {
+              break;
+            }
+          }
+        });
+
+        // Send back the receiver
+        Ok(Response::new(ReceiverStream::new(rx)))
+      })
+      .await
+  }
+
+  type ExportCommitmentsStream = ReceiverStream<Result<ExportChunk, Status>>;
+
+  async fn export_commitments(
+    &self,
+    request: Request<proto::commitment::ExportRequest>,
+  ) -> Result<Response<Self::ExportCommitmentsStream>, Status> {
+    let request_id = logging::request_id(request.metadata());
+    self
+      .call("ExportCommitments", request_id, async move {
+        let lang = Lang::from_request(&request);
+        self
+          .acting_user(&request, &[Role::Manager], 0)
+          .localize(lang)?;
+        // Chunks are streamed while the export is written
+        let stream = self
+          .export_commitments(request.into_inner())
+          .localize(lang)?;
+        Ok(Response::new(stream))
+      })
+      .await
+  }
+
+  async fn import_commitments(
+    &self,
+    request: Request<tonic::Streaming<ImportCommitmentRow>>,
+  ) -> Result<Response<ImportCommitmentsResponse>, Status> {
+    let request_id = logging::request_id(request.metadata());
+    self
+      .call("ImportCommitments", request_id, async move {
+        // Row errors are localized as well
+        let lang = Lang::from_request(&request);
+        // Only managers can create commitments;
+        // without authentication each row has its own creator
+        let created_by = match self.authenticated {
+          true => Some(
+            self
+              .acting_user(&request, &[Role::Manager], 0)
+              .localize(lang)?,
+          ),
+          false => None,
+        };
+        // Dry-run unless explicitly disabled
+        let dry_run = import::dry_run(&request).localize(lang)?;
+        // Active commitments are kept unless explicitly replaced
+        let replace_active = import::replace_active(&request).localize(lang)?;
+        let mut stream = request.into_inner();
+        let mut rows = Vec::new();
+        while let Some(mut row) = stream.message().await? {
+          if let Some(created_by) = created_by {
+            row.created_by = created_by;
+          }
+          rows.push(row);
+        }
+        let res = self
+          .import_commitments(rows, dry_run, replace_active, lang)
+          .await
+          .localize(lang)?;
+        Ok(Response::new(res))
+      })
+      .await
+  }
+
+  type GetCustomerEventsStream = ReceiverStream<Result<CommitmentEvent, Status>>;
+
+  async fn get_customer_events(
+    &self,
+    request: Request<proto::commitment::CustomerRequest>,
+  ) -> Result<Response<Self::GetCustomerEventsStream>, Status> {
+    let request_id = logging::request_id(request.metadata());
+    self
+      .call("GetCustomerEvents", request_id, async move {
+        logging::record_customer(request.get_ref().customer_id);
+        let lang = Lang::from_request(&request);
+        self
+          .acting_user(&request, &[Role::Manager], 0)
+          .localize(lang)?;
+        // Create channel for stream response
+        let (tx, rx) = tokio::sync::mpsc::channel(100);
+
+        // Get customer events
+        let res = self
+          .get_customer_events(request.into_inner())
+          .await
+          .localize(lang)?;
+
+        // Send the result items through the channel
+        tokio::spawn(async move {
+          for event in res.into_iter() {
+            if tx.send(Ok(event)).await.is_err() {
+              break;
+            }
+          }
+        });
+
+        // Send back the receiver
+        Ok(Response::new(ReceiverStream::new(rx)))
+      })
+      .await
   }
 
   async fn add_purchase(
     &self,
     request: Request<proto::commitment::AddPurchaseRequest>,
   ) -> Result<Response<proto::commitment::CommitmentInfo>, Status> {
-    let res = self.add_purchase(request.into_inner()).await?;
-    Ok(Response::new(res))
+    let request_id = logging::request_id(request.metadata());
+    self
+      .call("AddPurchase", request_id, async move {
+        logging::record_customer(request.get_ref().customer_id);
+        logging::record_commitment(&request.get_ref().commitment_id);
+        let lang = Lang::from_request(&request);
+        let added_by = self
+          .acting_user(
+            &request,
+            &[Role::Cashier, Role::Manager],
+            request.get_ref().added_by,
+          )
+          .localize(lang)?;
+        // Retries with the same key return the original response
+        let key = idempotency_key(&request).localize(lang)?;
+        let mut r = request.into_inner();
+        r.added_by = added_by;
+        let res = self
+          .idempotency
+          .run("AddPurchase", key, &r, || self.add_purchase(r.clone()))
+          .await
+          .localize(lang)?;
+        Ok(Response::new(res))
+      })
+      .await
   }
 
   async fn remove_purchase(
     &self,
     request: Request<proto::commitment::RemovePurchaseRequest>,
   ) -> Result<Response<proto::commitment::CommitmentInfo>, Status> {
-    let res = self.remove_purchase(request.into_inner()).await?;
-    Ok(Response::new(res))
+    let request_id = logging::request_id(request.metadata());
+    self
+      .call("RemovePurchase", request_id, async move {
+        logging::record_customer(request.get_ref().customer_id);
+        logging::record_commitment(&request.get_ref().commitment_id);
+        let lang = Lang::from_request(&request);
+        let removed_by = self
+          .acting_user(
+            &request,
+            &[Role::Cashier, Role::Manager],
+            request.get_ref().removed_by,
+          )
+          .localize(lang)?;
+        let mut r = request.into_inner();
+        r.removed_by = removed_by;
+        let (res, outcome) = self.remove_purchase(r).await.localize(lang)?;
+        let mut response = Response::new(res);
+        // Callers can tell whether the removal has changed anything
+        if let Ok(value) = MetadataValue::from_str(&outcome.to_string()) {
+          response
+            .metadata_mut()
+            .insert(commitment::REMOVAL_OUTCOME_KEY, value);
+        }
+        Ok(response)
+      })
+      .await
+  }
+
+  async fn restore_purchase(
+    &self,
+    request: Request<proto::commitment::RestorePurchaseRequest>,
+  ) -> Result<Response<proto::commitment::CommitmentInfo>, Status> {
+    let request_id = logging::request_id(request.metadata());
+    self
+      .call("RestorePurchase", request_id, async move {
+        logging::record_customer(request.get_ref().customer_id);
+        logging::record_commitment(&request.get_ref().commitment_id);
+        let lang = Lang::from_request(&request);
+        let restored_by = self
+          .acting_user(
+            &request,
+            &[Role::Cashier, Role::Manager],
+            request.get_ref().restored_by,
+          )
+          .localize(lang)?;
+        let mut r = request.into_inner();
+        r.restored_by = restored_by;
+        let (res, outcome) = self.restore_purchase(r).await.localize(lang)?;
+        let mut response = Response::new(res);
+        // Callers can tell whether the restore has changed anything
+        if let Ok(value) = MetadataValue::from_str(&outcome.to_string()) {
+          response
+            .metadata_mut()
+            .insert(commitment::RESTORE_OUTCOME_KEY, value);
+        }
+        Ok(response)
+      })
+      .await
   }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-  // Init commitments database
-  let customer_commitments: VecPack<commitment::Customer> =
-    VecPack::load_or_init(PathBuf::from("data/commitments"))
-      .expect("Error while loading commitments db");
-
-  let addr = env::var("SERVICE_ADDR_COMMITMENT")
-    .unwrap_or("[::1]:50074".into())
-    .parse()
-    .unwrap();
+async fn main() {
+  let args = env::args().skip(1).collect::<Vec<String>>();
+  let command = match Command::parse(&args) {
+    Ok(command) => command,
+    Err(e) => {
+      eprintln!("{}\n\n{}", e, cli::USAGE);
+      std::process::exit(2);
+    }
+  };
+  if let Err(e) = run(command).await {
+    eprintln!("Error: {}", e);
+    std::process::exit(1);
+  }
+}
 
-  // Create shutdown channel
-  let (tx, rx) = oneshot::channel();
-
-  // Spawn the server into a runtime
-  tokio::task::spawn(async move {
-    Server::builder()
-      .add_service(CommitmentServer::new(CommitmentService::init(
-        customer_commitments,
-      )))
-      .serve_with_shutdown(addr, async {
-        let _ = rx.await;
-      })
+// Run the given command with the loaded configuration
+async fn run(command: Command) -> Result<(), Box<dyn Error>> {
+  let config = Config::load()?;
+  logging::init(&config.logging)?;
+  match command {
+    Command::Serve => serve(&config).await,
+    Command::Inspect(customer_id) => cli::inspect(&config, customer_id),
+    Command::List => cli::list(&config),
+    Command::Verify => cli::verify(&config),
+    Command::Export(args) => cli::export(&config, args),
+    Command::Import {
+      path,
+      apply,
+      replace_active,
+    } => cli::import(&config, &path, apply, replace_active).await,
+    Command::RecalcBalances { apply } => cli::recalc_balances(&config, apply),
+    Command::Rebuild { apply } => cli::rebuild(&config, apply),
+  }
+}
+
+// Start the gRPC server and run till SIGINT
+async fn serve(config: &Config) -> Result<(), Box<dyn Error>> {
+  let policy = config.policy()?;
+
+  // Shared with the rollover job
+  let store = Arc::new(Store::open(&config.db_path(), &config.journal_path())?);
+
+  // Spawn the rollover job if enabled
+  if policy.rollover.enabled {
+    tokio::task::spawn(rollover::run(store.clone(), policy.clone()));
+  }
+
+  // Metrics endpoint if enabled
+  if let Some(metrics_addr) = config.metrics_addr {
+    let server = metrics::server(metrics_addr, store.clone())?;
+    tokio::task::spawn(async move {
+      if let Err(e) = server.await {
+        tracing::error!("Metrics server error: {}", e);
+      }
+    });
+  }
+
+  // Stored responses are kept over restarts,
+  // and the expired ones are dropped periodically
+  let idempotency = Arc::new(IdempotencyCache::open(
+    config.idempotency_ttl(),
+    config.idempotency_path(),
+  )?);
+  tokio::task::spawn(idempotency.clone().run_sweeper());
+
+  // Bearer token verification if configured
+  let auth = config.auth()?.map(Arc::new);
+  let service = CommitmentService::init(store, policy, idempotency, auth.is_some());
+
+  // TLS, and client certificate verification if configured
+  let mut server = Server::builder();
+  if let Some(tls) = &config.tls {
+    server = server.tls_config(tls::server_tls_config(tls)?)?;
+  }
+
+  // Serve till SIGINT
+  tracing::info!(
+    addr = %config.listen_addr,
+    tls = config.tls.is_some(),
+    auth = config.auth.is_some(),
+    "Serving"
+  );
+  server
+    .add_service(CommitmentServer::with_interceptor(
+      service,
+      auth::interceptor(auth),
+    ))
+    .serve_with_shutdown(config.listen_addr, async {
+      let _ = tokio::signal::ctrl_c().await;
+      tracing::info!("SIGINT, shutting down");
+    })
+    .await?;
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Load test of concurrent AddPurchase calls
+  // Calls must not wait for a customer being updated meanwhile
+  #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+  async fn test_add_purchase_load() {
+    const CUSTOMERS: u32 = 20;
+    const PURCHASES: u32 = 10;
+    // Its mutation is kept in progress during the load
+    const BLOCKED: u32 = CUSTOMERS + 1;
+    let service = Arc::new(CommitmentService::init(
+      Arc::new(store::tests::temp_store()),
+      Policy::default(),
+      Arc::new(IdempotencyCache::new(chrono::Duration::hours(1))),
+      false,
+    ));
+    let mut commitment_ids = Vec::new();
+    for customer_id in 1..=BLOCKED {
+      let customer = service
+        .add_commitment(AddCommitmentRequest {
+          customer_id,
+          target: 1_000_000,
+          discount_percentage: 2,
+          created_by: 1,
+          ..Default::default()
+        })
+        .await
+        .unwrap();
+      commitment_ids.push((customer_id, customer.commitments[0].commitment_id.clone()));
+    }
+    commitment_ids.pop();
+
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let store = service.store.clone();
+    let blocked = tokio::spawn(async move {
+      store
+        .update(BLOCKED, move |_| {
+          started_tx.send(()).unwrap();
+          release_rx.recv().unwrap();
+          Ok(((), Vec::new()))
+        })
+        .await
+    });
+    tokio::task::spawn_blocking(move || started_rx.recv())
       .await
       .unwrap()
-  });
+      .unwrap();
 
-  tokio::signal::ctrl_c().await?;
+    let mut handles = Vec::new();
+    for (customer_id, commitment_id) in commitment_ids {
+      for _ in 0..PURCHASES {
+        let service = service.clone();
+        let commitment_id = commitment_id.clone();
+        handles.push(tokio::spawn(async move {
+          service
+            .add_purchase(AddPurchaseRequest {
+              customer_id,
+              commitment_id,
+              purchase_id: Uuid::new_v4().to_string(),
+              total_net: 100,
+              total_gross: 127,
+              applied_discount: 2,
+              ..Default::default()
+            })
+            .await
+        }));
+      }
+    }
+    // Other customers do not wait for the blocked one
+    for handle in handles {
+      let res = tokio::time::timeout(std::time::Duration::from_secs(30), handle)
+        .await
+        .expect("AddPurchase waited for another customer");
+      assert!(res.unwrap().is_ok());
+    }
+    release_tx.send(()).unwrap();
+    assert!(blocked.await.unwrap().is_ok());
 
-  println!("SIGINT");
+    // No lost updates
+    for customer_id in 1..=CUSTOMERS {
+      let c = service
+        .store
+        .get(customer_id)
+        .unwrap()
+        .get_active_commitment()
+        .unwrap();
+      assert_eq!(c.purchase_log.len(), PURCHASES as usize);
+      assert_eq!(c.balance, 127 * PURCHASES);
+    }
+  }
+
+  // Request of an authenticated caller
+  fn as_caller<T>(message: T, uid: u32, role: Role) -> Request<T> {
+    let mut request = Request::new(message);
+    let metadata = request.metadata_mut();
+    metadata.insert(auth::CALLER_UID_KEY, uid.to_string().parse().unwrap());
+    metadata.insert(auth::CALLER_ROLE_KEY, role.to_string().parse().unwrap());
+    request
+  }
 
-  // Send shutdown signal after SIGINT received
-  let _ = tx.send(());
+  #[tokio::test]
+  async fn test_roles() {
+    let service = CommitmentService::init(
+      Arc::new(store::tests::temp_store()),
+      Policy::default(),
+      Arc::new(IdempotencyCache::new(chrono::Duration::hours(1))),
+      true,
+    );
+    let customer = Commitment::add_commitment(
+      &service,
+      as_caller(
+        AddCommitmentRequest {
+          customer_id: 1,
+          target: 1000,
+          discount_percentage: 2,
+          ..Default::default()
+        },
+        10,
+        Role::Manager,
+      ),
+    )
+    .await
+    .unwrap()
+    .into_inner();
+    let commitment_id = customer.commitments[0].commitment_id.clone();
 
-  Ok(())
+    // Callers are recorded, whatever the request contains
+    let purchase_id = Uuid::new_v4().to_string();
+    Commitment::add_purchase(
+      &service,
+      as_caller(
+        AddPurchaseRequest {
+          customer_id: 1,
+          commitment_id: commitment_id.clone(),
+          purchase_id: purchase_id.clone(),
+          total_net: 100,
+          total_gross: 127,
+          applied_discount: 2,
+          added_by: 99,
+          ..Default::default()
+        },
+        20,
+        Role::Cashier,
+      ),
+    )
+    .await
+    .unwrap();
+    Commitment::remove_purchase(
+      &service,
+      as_caller(
+        RemovePurchaseRequest {
+          customer_id: 1,
+          commitment_id: commitment_id.clone(),
+          purchase_id: purchase_id.clone(),
+          ..Default::default()
+        },
+        20,
+        Role::Cashier,
+      ),
+    )
+    .await
+    .unwrap();
+    Commitment::restore_purchase(
+      &service,
+      as_caller(
+        RestorePurchaseRequest {
+          customer_id: 1,
+          commitment_id,
+          purchase_id,
+          restored_by: 99,
+        },
+        21,
+        Role::Cashier,
+      ),
+    )
+    .await
+    .unwrap();
+    let events = service.store.journal().customer_events(1).unwrap();
+    assert!(events.iter().any(|e| matches!(
+      e.kind,
+      EventKind::PurchaseAdded {
+        added_by: Some(20),
+        ..
+      }
+    )));
+    assert!(events.iter().any(|e| matches!(
+      e.kind,
+      EventKind::PurchaseRestored {
+        restored_by: Some(21),
+        ..
+      }
+    )));
+
+    // Reporting RPCs are for managers only
+    let request = CustomerRequest { customer_id: 1 };
+    let res =
+      Commitment::get_customer_events(&service, as_caller(request.clone(), 20, Role::Cashier))
+        .await;
+    assert_eq!(res.err().unwrap().code(), tonic::Code::PermissionDenied);
+    // Denials are localized as well
+    let mut english = as_caller(request.clone(), 20, Role::Cashier);
+    english
+      .metadata_mut()
+      .insert(error::LANGUAGE_KEY, "en".parse().unwrap());
+    let res = Commitment::get_customer_events(&service, english).await;
+    assert_eq!(
+      res.err().unwrap().message(),
+      "You have no permission for the operation (cashier role)!"
+    );
+    assert!(
+      Commitment::get_customer_events(&service, as_caller(request, 10, Role::Manager))
+        .await
+        .is_ok()
+    );
+    let request = PortfolioReportRequest::default();
+    let res =
+      Commitment::get_portfolio_report(&service, as_caller(request, 20, Role::Cashier)).await;
+    assert_eq!(res.err().unwrap().code(), tonic::Code::PermissionDenied);
+    let request = proto::commitment::ExportRequest::default();
+    let res = Commitment::export_commitments(&service, as_caller(request, 20, Role::Cashier)).await;
+    assert_eq!(res.err().unwrap().code(), tonic::Code::PermissionDenied);
+    let request = proto::commitment::QueryCommitmentsRequest::default();
+    let res = Commitment::query_commitments(&service, as_caller(request, 20, Role::Cashier)).await;
+    assert_eq!(res.err().unwrap().code(), tonic::Code::PermissionDenied);
+  }
+
+  // Service without authentication, with a customer
+  // having one purchase; returns the commitment and purchase IDs
+  async fn service_with_purchase() -> (CommitmentService, String, String) {
+    let service = CommitmentService::init(
+      Arc::new(store::tests::temp_store()),
+      Policy::default(),
+      Arc::new(IdempotencyCache::new(chrono::Duration::hours(1))),
+      false,
+    );
+    let customer = service
+      .add_commitment(AddCommitmentRequest {
+        customer_id: 1,
+        target: 1000,
+        discount_percentage: 2,
+        created_by: 1,
+        ..Default::default()
+      })
+      .await
+      .unwrap();
+    let commitment_id = customer.commitments[0].commitment_id.clone();
+    let purchase_id = Uuid::new_v4().to_string();
+    service
+      .add_purchase(AddPurchaseRequest {
+        customer_id: 1,
+        commitment_id: commitment_id.clone(),
+        purchase_id: purchase_id.clone(),
+        total_net: 100,
+        total_gross: 127,
+        applied_discount: 2,
+        ..Default::default()
+      })
+      .await
+      .unwrap();
+    (service, commitment_id, purchase_id)
+  }
+
+  #[tokio::test]
+  async fn test_remove_purchase_outcome() {
+    let (service, commitment_id, purchase_id) = service_with_purchase().await;
+    let request = RemovePurchaseRequest {
+      customer_id: 1,
+      commitment_id,
+      purchase_id,
+      ..Default::default()
+    };
+    let outcome = |response: &Response<CommitmentInfo>| {
+      response
+        .metadata()
+        .get(commitment::REMOVAL_OUTCOME_KEY)
+        .map(|v| v.to_str().unwrap().to_string())
+    };
+    let first = Commitment::remove_purchase(&service, Request::new(request.clone()))
+      .await
+      .unwrap();
+    assert_eq!(outcome(&first).as_deref(), Some("removed"));
+    // Repeated removal changes nothing, and it is reported so
+    let second = Commitment::remove_purchase(&service, Request::new(request))
+      .await
+      .unwrap();
+    assert_eq!(outcome(&second).as_deref(), Some("already-removed"));
+    assert_eq!(first.get_ref().balance, 0);
+    assert_eq!(second.get_ref().balance, 0);
+  }
+
+  #[tokio::test]
+  async fn test_restore_purchase_outcome() {
+    let (service, commitment_id, purchase_id) = service_with_purchase().await;
+    let request = RestorePurchaseRequest {
+      customer_id: 1,
+      commitment_id: commitment_id.clone(),
+      purchase_id: purchase_id.clone(),
+      ..Default::default()
+    };
+    let outcome = |response: &Response<CommitmentInfo>| {
+      response
+        .metadata()
+        .get(commitment::RESTORE_OUTCOME_KEY)
+        .map(|v| v.to_str().unwrap().to_string())
+    };
+    // Not removed yet, nothing to restore
+    let res = Commitment::restore_purchase(&service, Request::new(request.clone()))
+      .await
+      .unwrap();
+    assert_eq!(outcome(&res).as_deref(), Some("not-removed"));
+    assert_eq!(res.get_ref().balance, 127);
+    service
+      .remove_purchase(RemovePurchaseRequest {
+        customer_id: 1,
+        commitment_id,
+        purchase_id,
+        ..Default::default()
+      })
+      .await
+      .unwrap();
+    let res = Commitment::restore_purchase(&service, Request::new(request))
+      .await
+      .unwrap();
+    assert_eq!(outcome(&res).as_deref(), Some("restored"));
+    assert_eq!(res.get_ref().balance, 127);
+  }
+
+  #[tokio::test]
+  async fn test_get_portfolio_report() {
+    let (service, _, _) = service_with_purchase().await;
+    let now = Utc::now();
+    let r = service
+      .get_portfolio_report(PortfolioReportRequest {
+        from: (now - chrono::Duration::days(1)).to_rfc3339(),
+        till: (now + chrono::Duration::days(1)).to_rfc3339(),
+      })
+      .await
+      .unwrap();
+    assert_eq!(r.tiers.len(), 1);
+    assert_eq!(r.tiers[0].customers, 1);
+    assert_eq!(r.total_target, 1000);
+    assert_eq!(r.total_balance, 127);
+    // Invalid period is rejected
+    let res = service
+      .get_portfolio_report(PortfolioReportRequest {
+        from: now.to_rfc3339(),
+        till: now.to_rfc3339(),
+      })
+      .await;
+    assert!(res.is_err());
+  }
 }
@@ -0,0 +1,384 @@
+use crate::commitment::{CommitmentExt, Customer};
+use crate::store::Store;
+use chrono::{DateTime, Utc};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use prometheus::{
+  exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+  Opts, Registry, TextEncoder,
+};
+use std::collections::{BTreeSet, HashMap};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tonic::{Code, Status};
+
+/// Service metrics in Prometheus format
+/// Clones share the same metrics
+#[derive(Clone)]
+pub struct Metrics {
+  registry: Registry,
+  rpc_requests: IntCounterVec,     // By method and status code
+  rpc_duration: HistogramVec,      // By method and status code
+  lock_wait: HistogramVec,         // By lock: db or customer
+  persistence_write: HistogramVec, // By target: db or journal
+  customers: IntGauge,
+  active_commitments: IntGaugeVec, // By discount percentage
+  tiers: Arc<Mutex<TierState>>,    // Source of the customer gauges
+}
+
+// Active periods of a commitment tier: discount percentage,
+// start inclusive, end exclusive
+type TierPeriod = (u32, DateTime<Utc>, DateTime<Utc>);
+
+// Tier periods of a customer, with its counted tier
+// and the next time it may change
+#[derive(Default)]
+struct CustomerTiers {
+  periods: Vec<TierPeriod>,
+  active: Option<u32>,
+  next_change: Option<DateTime<Utc>>,
+}
+
+// Customer gauge state, kept up to date by the writes,
+// so scrapes only re-check the customers due to change
+#[derive(Default)]
+struct TierState {
+  customers: HashMap<u32, CustomerTiers>,
+  changes: BTreeSet<(DateTime<Utc>, u32)>, // By time, then customer ID
+}
+
+// Active periods of the commitments, in creation order
+// A withdrawn commitment is not active any more
+fn tier_periods(customer: &Customer) -> Vec<TierPeriod> {
+  customer
+    .commitments
+    .iter()
+    .filter(|c| !c.is_withdrawn())
+    .map(|c| (c.discount_percentage, c.valid_from, c.valid_till))
+    .collect()
+}
+
+// Register a metric; definitions are static, so it cannot fail
+// unless the same name is registered twice
+fn register<M>(registry: &Registry, metric: prometheus::Result<M>) -> M
+where
+  M: prometheus::core::Collector + Clone + 'static,
+{
+  let metric = metric.expect("Invalid metric definition");
+  registry
+    .register(Box::new(metric.clone()))
+    .expect("Metric registered twice");
+  metric
+}
+
+impl Default for Metrics {
+  fn default() -> Self {
+    let registry = Registry::new();
+    // 10μs - 2.6s
+    let lock_buckets = exponential_buckets(0.00001, 4.0, 10).unwrap_or_default();
+    Self {
+      rpc_requests: register(
+        &registry,
+        IntCounterVec::new(
+          Opts::new("commitment_rpc_requests_total", "gRPC calls"),
+          &["method", "code"],
+        ),
+      ),
+      rpc_duration: register(
+        &registry,
+        HistogramVec::new(
+          HistogramOpts::new("commitment_rpc_duration_seconds", "gRPC call latency"),
+          &["method", "code"],
+        ),
+      ),
+      lock_wait: register(
+        &registry,
+        HistogramVec::new(
+          HistogramOpts::new("commitment_lock_wait_seconds", "Lock wait time")
+            .buckets(lock_buckets.clone()),
+          &["lock"],
+        ),
+      ),
+      persistence_write: register(
+        &registry,
+        HistogramVec::new(
+          HistogramOpts::new(
+            "commitment_persistence_write_seconds",
+            "Database and journal write latency",
+          )
+          .buckets(lock_buckets),
+          &["target"],
+        ),
+      ),
+      customers: register(
+        &registry,
+        IntGauge::new("commitment_customers", "Number of customers"),
+      ),
+      active_commitments: register(
+        &registry,
+        IntGaugeVec::new(
+          Opts::new(
+            "commitment_active_commitments",
+            "Active commitments by discount tier",
+          ),
+          &["discount_percentage"],
+        ),
+      ),
+      tiers: Arc::new(Mutex::new(TierState::default())),
+      registry,
+    }
+  }
+}
+
+impl Metrics {
+  /// Run a gRPC call, and count it with its latency
+  /// by method and status code
+  /// Streaming calls are measured till the stream is set up
+  pub async fn rpc<T, F>(&self, method: &str, call: F) -> Result<T, Status>
+  where
+    F: Future<Output = Result<T, Status>>,
+  {
+    let start = Instant::now();
+    let res = call.await;
+    let code = match &res {
+      Ok(_) => Code::Ok,
+      Err(status) => status.code(),
+    };
+    let code = format!("{:?}", code);
+    self.rpc_requests.with_label_values(&[method, &code]).inc();
+    self
+      .rpc_duration
+      .with_label_values(&[method, &code])
+      .observe(start.elapsed().as_secs_f64());
+    res
+  }
+
+  /// Record the time spent waiting for a lock since start
+  pub fn lock_wait(&self, lock: &str, start: Instant) {
+    self
+      .lock_wait
+      .with_label_values(&[lock])
+      .observe(start.elapsed().as_secs_f64());
+  }
+
+  /// Record the write latency of a persistence target since start
+  pub fn persistence_write(&self, target: &str, start: Instant) {
+    self
+      .persistence_write
+      .with_label_values(&[target])
+      .observe(start.elapsed().as_secs_f64());
+  }
+
+  /// Update the customer gauges with a saved customer
+  /// Called on every write, after the customer is saved,
+  /// so scrapes never need to read the storage
+  pub fn customer_saved(&self, customer: &Customer) {
+    let mut tiers = self.lock_tiers();
+    let entry = tiers.customers.entry(customer.customer_id).or_default();
+    entry.periods = tier_periods(customer);
+    let customers = tiers.customers.len();
+    self.customers.set(customers as i64);
+    self.refresh_customer(&mut tiers, customer.customer_id, Utc::now());
+  }
+
+  // Tier state lock; held only while the gauges are updated
+  fn lock_tiers(&self) -> std::sync::MutexGuard<'_, TierState> {
+    self.tiers.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  // Count the customer in its tier active at the given time,
+  // and schedule its next change
+  fn refresh_customer(&self, tiers: &mut TierState, customer_id: u32, now: DateTime<Utc>) {
+    let customer = match tiers.customers.get_mut(&customer_id) {
+      Some(customer) => customer,
+      None => return,
+    };
+    // The last one active counts, as the service sees it
+    let active = customer
+      .periods
+      .iter()
+      .rev()
+      .find(|(_, from, till)| *from <= now && now < *till)
+      .map(|(discount_percentage, _, _)| *discount_percentage);
+    let next_change = customer
+      .periods
+      .iter()
+      .flat_map(|(_, from, till)| [*from, *till])
+      .filter(|t| *t > now)
+      .min();
+    if active != customer.active {
+      if let Some(old) = customer.active {
+        let label = old.to_string();
+        let gauge = self.active_commitments.with_label_values(&[&label]);
+        gauge.dec();
+        // Tiers without active commitments disappear
+        if gauge.get() <= 0 {
+          let _ = self.active_commitments.remove_label_values(&[&label]);
+        }
+      }
+      if let Some(new) = active {
+        self
+          .active_commitments
+          .with_label_values(&[&new.to_string()])
+          .inc();
+      }
+      customer.active = active;
+    }
+    let old_change = std::mem::replace(&mut customer.next_change, next_change);
+    if let Some(t) = old_change {
+      tiers.changes.remove(&(t, customer_id));
+    }
+    if let Some(t) = next_change {
+      tiers.changes.insert((t, customer_id));
+    }
+  }
+
+  // Move the gauges to the current time by re-checking
+  // only the customers whose tier may have changed since
+  fn update_tiers(&self) {
+    let now = Utc::now();
+    let mut tiers = self.lock_tiers();
+    while let Some((t, customer_id)) = tiers.changes.iter().next().copied() {
+      if t > now {
+        break;
+      }
+      tiers.changes.remove(&(t, customer_id));
+      self.refresh_customer(&mut tiers, customer_id, now);
+    }
+  }
+
+  /// Metrics in Prometheus text format
+  pub fn render(&self) -> String {
+    self.update_tiers();
+    let mut buf = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+      tracing::error!("Metrics error: {}", e);
+    }
+    String::from_utf8(buf).unwrap_or_default()
+  }
+}
+
+// Serve GET /metrics only
+fn handle(store: &Store, request: Request<Body>) -> Response<Body> {
+  let mut response = Response::default();
+  match (request.method(), request.uri().path()) {
+    (&Method::GET, "/metrics") => {
+      if let Ok(content_type) = TextEncoder::new().format_type().parse() {
+        response
+          .headers_mut()
+          .insert(hyper::header::CONTENT_TYPE, content_type);
+      }
+      *response.body_mut() = Body::from(store.metrics().render());
+    }
+    _ => *response.status_mut() = StatusCode::NOT_FOUND,
+  }
+  response
+}
+
+/// HTTP server of the metrics endpoint
+/// Binding errors are returned at once, serving errors by the future
+pub fn server(
+  addr: SocketAddr,
+  store: Arc<Store>,
+) -> Result<impl Future<Output = Result<(), hyper::Error>>, String> {
+  let make_service = make_service_fn(move |_| {
+    let store = store.clone();
+    async move {
+      Ok::<_, Infallible>(service_fn(move |request| {
+        let store = store.clone();
+        async move { Ok::<_, Infallible>(handle(&store, request)) }
+      }))
+    }
+  });
+  let builder = hyper::Server::try_bind(&addr)
+    .map_err(|e| format!("Error while binding metrics address {}: {}", addr, e))?;
+  Ok(builder.serve(make_service))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::commitment::{CommitmentOptions, CustomerExt};
+  use crate::journal;
+  use crate::policy::{Policy, ValidityPeriod};
+  use crate::store::tests::temp_store;
+
+  #[tokio::test]
+  async fn test_metrics() {
+    let store = temp_store();
+    let policy = Policy::default();
+    for (customer_id, discount_percentage) in &[(1, 2), (2, 2), (3, 5)] {
+      store
+        .upsert(*customer_id, |customer| {
+          customer.add_commitment(
+            1000,
+            *discount_percentage,
+            1,
+            CommitmentOptions::default(),
+            &policy,
+          )?;
+          Ok(((), journal::commitment_events(customer, 0)))
+        })
+        .await
+        .unwrap();
+    }
+    let metrics = store.metrics();
+    let _ = metrics.rpc("GetCustomer", async { Ok(()) }).await;
+    let _: Result<(), Status> = metrics
+      .rpc("AddPurchase", async { Err(Status::invalid_argument("x")) })
+      .await;
+
+    let text = metrics.render();
+    assert!(text.contains("commitment_customers 3"));
+    assert!(text.contains("commitment_active_commitments{discount_percentage=\"2\"} 2"));
+    assert!(text.contains("commitment_active_commitments{discount_percentage=\"5\"} 1"));
+    assert!(text.contains("commitment_rpc_requests_total{code=\"Ok\",method=\"GetCustomer\"} 1"));
+    assert!(text.contains(
+      "commitment_rpc_requests_total{code=\"InvalidArgument\",method=\"AddPurchase\"} 1"
+    ));
+    assert!(text.contains("commitment_lock_wait_seconds_count{lock=\"customer\"} 3"));
+    assert!(text.contains("commitment_persistence_write_seconds_count{target=\"journal\"} 3"));
+
+    // Withdrawn commitments are not counted
+    store
+      .update(1, |customer| {
+        customer.add_commitment(1000, 5, 1, CommitmentOptions::default(), &policy)?;
+        Ok(((), journal::commitment_events(customer, 1)))
+      })
+      .await
+      .unwrap();
+    let text = metrics.render();
+    assert!(text.contains("commitment_active_commitments{discount_percentage=\"2\"} 1"));
+    assert!(text.contains("commitment_active_commitments{discount_percentage=\"5\"} 2"));
+
+    // Expired commitments are not counted, without any write
+    let now = Utc::now();
+    let options = CommitmentOptions {
+      validity: Some(
+        ValidityPeriod::new(
+          now - chrono::Duration::days(1),
+          now + chrono::Duration::milliseconds(200),
+        )
+        .unwrap(),
+      ),
+      ..CommitmentOptions::default()
+    };
+    store
+      .upsert(4, |customer| {
+        customer.add_commitment(1000, 3, 1, options, &policy)?;
+        Ok(((), journal::commitment_events(customer, 0)))
+      })
+      .await
+      .unwrap();
+    assert!(metrics
+      .render()
+      .contains("commitment_active_commitments{discount_percentage=\"3\"} 1"));
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    let text = metrics.render();
+    assert!(text.contains("commitment_customers 4"));
+    assert!(!text.contains("discount_percentage=\"3\""));
+  }
+}
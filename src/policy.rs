@@ -0,0 +1,390 @@
+use crate::error::DomainError;
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Business rules applied when creating new commitments
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Policy {
+  pub discount_policies: Vec<DiscountPolicy>,
+  #[serde(default)]
+  pub validity: ValidityModel,
+  #[serde(default)]
+  pub rollover: RolloverPolicy,
+}
+
+impl Default for Policy {
+  fn default() -> Self {
+    Self {
+      discount_policies: vec![DiscountPolicy::default()],
+      validity: ValidityModel::default(),
+      rollover: RolloverPolicy::default(),
+    }
+  }
+}
+
+impl Policy {
+  /// Try to load policy from a TOML file
+  pub fn load(path: &Path) -> Result<Self, String> {
+    let content = std::fs::read_to_string(path)
+      .map_err(|e| format!("Error while reading policy file {}: {}", path.display(), e))?;
+    let policy: Policy = toml::from_str(&content)
+      .map_err(|e| format!("Error while parsing policy file {}: {}", path.display(), e))?;
+    policy.validate()?;
+    Ok(policy)
+  }
+
+  /// Check whether the policy is usable
+  pub fn validate(&self) -> Result<(), String> {
+    if self.discount_policies.is_empty() {
+      return Err("At least one discount policy is required".to_string());
+    }
+    self.validity.validate()?;
+    if self.rollover.check_interval_secs == 0 {
+      return Err("Rollover check interval must be at least 1 sec".to_string());
+    }
+    for dp in &self.discount_policies {
+      if dp.tiers.is_empty() {
+        return Err(format!(
+          "Discount policy effective from {} has no tiers",
+          dp.effective_from.to_rfc3339()
+        ));
+      }
+    }
+    Ok(())
+  }
+
+  /// Get the discount policy in force at the given time
+  /// The latest policy whose effective_from is not after the given time
+  pub fn discount_policy_at(&self, at: DateTime<Utc>) -> Option<&DiscountPolicy> {
+    self
+      .discount_policies
+      .iter()
+      .filter(|dp| dp.effective_from <= at)
+      .max_by_key(|dp| dp.effective_from)
+  }
+
+  /// Validate discount percentage and target against the
+  /// discount policy in force at the given time
+  pub fn validate_discount(
+    &self,
+    discount_percentage: u32,
+    target: u32,
+    at: DateTime<Utc>,
+  ) -> Result<(), DomainError> {
+    match self.discount_policy_at(at) {
+      Some(dp) => dp.validate(discount_percentage, target),
+      None => Err(DomainError::NoDiscountPolicy),
+    }
+  }
+}
+
+/// Commitment validity interval
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ValidityPeriod {
+  pub valid_from: DateTime<Utc>, // Valid from (inclusive)
+  pub valid_till: DateTime<Utc>, // Valid till
+}
+
+impl ValidityPeriod {
+  /// Try to create explicit validity period
+  pub fn new(valid_from: DateTime<Utc>, valid_till: DateTime<Utc>) -> Result<Self, DomainError> {
+    if valid_from >= valid_till {
+      return Err(DomainError::InvalidValidityPeriod);
+    }
+    Ok(Self {
+      valid_from,
+      valid_till,
+    })
+  }
+}
+
+/// How the validity period of a new commitment is defined
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ValidityModel {
+  // Valid till next January 1st
+  #[default]
+  CalendarYear,
+  // Valid for N months from creation
+  RollingMonths {
+    months: u32,
+  },
+  // Valid till the end of the fiscal year
+  // starting at the given month (1-12)
+  FiscalYear {
+    start_month: u32,
+  },
+}
+
+impl ValidityModel {
+  /// Check model parameters
+  pub fn validate(&self) -> Result<(), String> {
+    match self {
+      ValidityModel::CalendarYear => Ok(()),
+      ValidityModel::RollingMonths { months } if *months > 0 => Ok(()),
+      ValidityModel::RollingMonths { .. } => {
+        Err("Rolling validity needs at least 1 month".to_string())
+      }
+      ValidityModel::FiscalYear { start_month } if (1..=12).contains(start_month) => Ok(()),
+      ValidityModel::FiscalYear { .. } => {
+        Err("Fiscal year start month must be between 1 and 12".to_string())
+      }
+    }
+  }
+
+  /// Validity period of a commitment created at the given time
+  pub fn period(&self, created_at: DateTime<Utc>) -> ValidityPeriod {
+    match self {
+      ValidityModel::CalendarYear => fiscal_year(created_at, 1),
+      ValidityModel::RollingMonths { months } => ValidityPeriod {
+        valid_from: created_at,
+        valid_till: add_months(created_at, *months),
+      },
+      ValidityModel::FiscalYear { start_month } => fiscal_year(created_at, *start_month),
+    }
+  }
+
+  /// Validity period starting at the end of a previous period
+  pub fn next_period(&self, previous_valid_till: DateTime<Utc>) -> ValidityPeriod {
+    match self {
+      // Rolling period starts right at the previous end
+      ValidityModel::RollingMonths { months } => ValidityPeriod {
+        valid_from: previous_valid_till,
+        valid_till: add_months(previous_valid_till, *months),
+      },
+      // Otherwise previous end is the first moment of the next period
+      _ => self.period(previous_valid_till),
+    }
+  }
+}
+
+/// Automatic rollover of expired commitments
+/// Opt-in, disabled by default
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RolloverPolicy {
+  #[serde(default)]
+  pub enabled: bool,
+  #[serde(default)]
+  pub rule: RolloverRule,
+  #[serde(default = "default_check_interval")]
+  pub check_interval_secs: u64,
+}
+
+impl Default for RolloverPolicy {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      rule: RolloverRule::default(),
+      check_interval_secs: default_check_interval(),
+    }
+  }
+}
+
+fn default_check_interval() -> u64 {
+  3600
+}
+
+/// How the discount of the rolled over commitment is defined
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RolloverRule {
+  // Keep target and discount
+  #[default]
+  Carry,
+  // Move up N tiers if balance reached target,
+  // otherwise move down M tiers
+  PromoteDemote {
+    promote_by: u32,
+    demote_by: u32,
+  },
+}
+
+// Fiscal year period containing the given time
+fn fiscal_year(at: DateTime<Utc>, start_month: u32) -> ValidityPeriod {
+  let year = match at.month() >= start_month {
+    true => at.year(),
+    false => at.year() - 1,
+  };
+  // Start month is validated
+  let valid_from = Utc
+    .with_ymd_and_hms(year, start_month, 1, 0, 0, 0)
+    .single()
+    .expect("Invalid fiscal year start month");
+  ValidityPeriod {
+    valid_from,
+    valid_till: add_months(valid_from, 12),
+  }
+}
+
+// Add months to the given time
+// Day of month is clamped to the last day of the target month
+pub fn add_months(at: DateTime<Utc>, months: u32) -> DateTime<Utc> {
+  let total = at.year() * 12 + at.month0() as i32 + months as i32;
+  let (year, month) = (total / 12, total as u32 % 12 + 1);
+  let day = at.day().min(days_in_month(year, month));
+  // Day is clamped, so the date exists
+  let date = NaiveDate::from_ymd_opt(year, month, day).expect("Invalid date");
+  Utc.from_utc_datetime(&date.and_time(at.time()))
+}
+
+// Number of days in the given month
+fn days_in_month(year: i32, month: u32) -> u32 {
+  let (next_year, next_month) = match month {
+    12 => (year + 1, 1),
+    _ => (year, month + 1),
+  };
+  NaiveDate::from_ymd_opt(next_year, next_month, 1)
+    .and_then(|d| d.pred_opt())
+    .expect("Invalid date")
+    .day()
+}
+
+/// Set of allowed discount tiers, effective from a given date
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DiscountPolicy {
+  pub effective_from: DateTime<Utc>, // Policy is in force from
+  pub tiers: Vec<DiscountTier>,      // Allowed discount tiers
+}
+
+impl Default for DiscountPolicy {
+  fn default() -> Self {
+    // Legacy rule: 0-6% without any minimum target
+    Self {
+      effective_from: DateTime::<Utc>::from(std::time::UNIX_EPOCH),
+      tiers: (0..=6)
+        .map(|percentage| DiscountTier {
+          percentage,
+          min_target: None,
+        })
+        .collect(),
+    }
+  }
+}
+
+impl DiscountPolicy {
+  /// Check if discount percentage is allowed,
+  /// and target reaches its minimum target if any
+  pub fn validate(&self, discount_percentage: u32, target: u32) -> Result<(), DomainError> {
+    match self
+      .tiers
+      .iter()
+      .find(|t| t.percentage == discount_percentage)
+    {
+      Some(tier) => match tier.min_target {
+        Some(min_target) if target < min_target => Err(DomainError::TargetTooLow {
+          discount_percentage,
+          min_target,
+        }),
+        _ => Ok(()),
+      },
+      None => Err(DomainError::DiscountNotAllowed {
+        allowed: self.tiers.iter().map(|t| t.percentage).collect(),
+      }),
+    }
+  }
+}
+
+/// Allowed discount percentage with optional minimum target
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DiscountTier {
+  pub percentage: u32,
+  #[serde(default)]
+  pub min_target: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_policy_in_force() {
+    let policy: Policy = toml::from_str(
+      r#"
+      [[discount_policies]]
+      effective_from = "2020-01-01T00:00:00Z"
+      tiers = [{ percentage = 0 }, { percentage = 5 }]
+
+      [[discount_policies]]
+      effective_from = "2021-01-01T00:00:00Z"
+      tiers = [{ percentage = 0 }, { percentage = 3, min_target = 1000 }]
+      "#,
+    )
+    .unwrap();
+    assert!(policy.validate().is_ok());
+    // Rollover is opt-in
+    assert!(!policy.rollover.enabled);
+
+    let in_2020 = Utc.with_ymd_and_hms(2020, 6, 1, 0, 0, 0).unwrap();
+    let in_2021 = Utc.with_ymd_and_hms(2021, 6, 1, 0, 0, 0).unwrap();
+    let in_2019 = Utc.with_ymd_and_hms(2019, 6, 1, 0, 0, 0).unwrap();
+
+    assert!(policy.validate_discount(5, 0, in_2020).is_ok());
+    assert!(policy.validate_discount(3, 0, in_2020).is_err());
+    assert!(policy.validate_discount(5, 0, in_2021).is_err());
+    assert!(policy.validate_discount(3, 999, in_2021).is_err());
+    assert!(policy.validate_discount(3, 1000, in_2021).is_ok());
+    assert!(policy.validate_discount(0, 0, in_2019).is_err());
+  }
+
+  #[test]
+  fn test_validity_models() {
+    let dec_30 = Utc.with_ymd_and_hms(2020, 12, 30, 10, 0, 0).unwrap();
+
+    let p = ValidityModel::CalendarYear.period(dec_30);
+    assert_eq!(
+      p.valid_from,
+      Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap()
+    );
+    assert_eq!(
+      p.valid_till,
+      Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap()
+    );
+
+    let p = ValidityModel::RollingMonths { months: 2 }.period(dec_30);
+    assert_eq!(p.valid_from, dec_30);
+    assert_eq!(
+      p.valid_till,
+      Utc.with_ymd_and_hms(2021, 2, 28, 10, 0, 0).unwrap()
+    );
+
+    let p = ValidityModel::FiscalYear { start_month: 7 }.period(dec_30);
+    assert_eq!(
+      p.valid_from,
+      Utc.with_ymd_and_hms(2020, 7, 1, 0, 0, 0).unwrap()
+    );
+    assert_eq!(
+      p.valid_till,
+      Utc.with_ymd_and_hms(2021, 7, 1, 0, 0, 0).unwrap()
+    );
+
+    let p = ValidityModel::FiscalYear { start_month: 7 }
+      .period(Utc.with_ymd_and_hms(2021, 3, 1, 0, 0, 0).unwrap());
+    assert_eq!(
+      p.valid_from,
+      Utc.with_ymd_and_hms(2020, 7, 1, 0, 0, 0).unwrap()
+    );
+
+    assert!(ValidityModel::FiscalYear { start_month: 13 }
+      .validate()
+      .is_err());
+    assert!(ValidityModel::RollingMonths { months: 0 }
+      .validate()
+      .is_err());
+    assert!(ValidityPeriod::new(dec_30, dec_30).is_err());
+
+    let jan_1 = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+    let p = ValidityModel::CalendarYear.next_period(jan_1);
+    assert_eq!(p.valid_from, jan_1);
+    assert_eq!(
+      p.valid_till,
+      Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap()
+    );
+    let p = ValidityModel::RollingMonths { months: 3 }.next_period(dec_30);
+    assert_eq!(p.valid_from, dec_30);
+    assert_eq!(
+      p.valid_till,
+      Utc.with_ymd_and_hms(2021, 3, 30, 10, 0, 0).unwrap()
+    );
+  }
+}
@@ -1,13 +1,19 @@
-use chrono::Utc;
-use gzlib::proto::commitment::{CommitmentInfo, CommitmentObj, CustomerObj, PurchaseInfo};
+use crate::proto::commitment::{
+  BasketLine, CalculateDiscountResponse, CategoryAmount, CategoryRule, CommitmentEvaluation,
+  CommitmentEvent, CommitmentInfo, CommitmentObj, CustomerObj, ImportRowResult, LineDiscount,
+  MonthStats, PortfolioReport, PurchaseInfo, TierStats,
+};
 
-use crate::commitment::CommitmentExt;
+use crate::commitment::{CommitmentExt, CustomerExt};
+use crate::error::{DomainError, Lang};
 
 pub enum ServiceError {
   InternalError(String),
   NotFound(String),
   AlreadyExists(String),
   BadRequest(String),
+  Unauthenticated(String),
+  Domain(DomainError),
 }
 
 impl ServiceError {
@@ -23,6 +29,24 @@ impl ServiceError {
   pub fn bad_request(msg: &str) -> Self {
     ServiceError::BadRequest(msg.to_string())
   }
+  pub fn unauthenticated(msg: &str) -> Self {
+    ServiceError::Unauthenticated(msg.to_string())
+  }
+  /// Message in the given language
+  /// Only domain errors are localized
+  pub fn message(&self, lang: Lang) -> String {
+    match self {
+      ServiceError::Domain(e) => e.message(lang),
+      _ => self.to_string(),
+    }
+  }
+  /// Status with the message in the given language
+  pub fn localize(self, lang: Lang) -> ::tonic::Status {
+    match self {
+      ServiceError::Domain(e) => e.to_status(lang),
+      _ => self.into(),
+    }
+  }
 }
 
 impl std::fmt::Display for ServiceError {
@@ -32,6 +56,8 @@ impl std::fmt::Display for ServiceError {
       ServiceError::NotFound(msg) => write!(f, "{}", msg),
       ServiceError::AlreadyExists(msg) => write!(f, "{}", msg),
       ServiceError::BadRequest(msg) => write!(f, "{}", msg),
+      ServiceError::Unauthenticated(msg) => write!(f, "{}", msg),
+      ServiceError::Domain(e) => write!(f, "{}", e),
     }
   }
 }
@@ -52,10 +78,30 @@ impl From<ServiceError> for ::tonic::Status {
       ServiceError::NotFound(msg) => ::tonic::Status::not_found(msg),
       ServiceError::AlreadyExists(msg) => ::tonic::Status::already_exists(msg),
       ServiceError::BadRequest(msg) => ::tonic::Status::invalid_argument(msg),
+      ServiceError::Unauthenticated(msg) => ::tonic::Status::unauthenticated(msg),
+      ServiceError::Domain(e) => e.to_status(Lang::default()),
     }
   }
 }
 
+impl From<DomainError> for ServiceError {
+  fn from(error: DomainError) -> Self {
+    ServiceError::Domain(error)
+  }
+}
+
+/// Localize the error of a service result
+pub trait LocalizeExt<T> {
+  #[allow(clippy::result_large_err)] // Handlers return tonic::Status
+  fn localize(self, lang: Lang) -> Result<T, ::tonic::Status>;
+}
+
+impl<T> LocalizeExt<T> for ServiceResult<T> {
+  fn localize(self, lang: Lang) -> Result<T, ::tonic::Status> {
+    self.map_err(|e| e.localize(lang))
+  }
+}
+
 impl From<::packman::PackError> for ServiceError {
   fn from(error: ::packman::PackError) -> Self {
     match error {
@@ -82,6 +128,67 @@ impl From<crate::commitment::PurchaseInfo> for PurchaseInfo {
       applied_discount: f.applied_discount,
       removed: f.removed,
       created_at: f.crated_at.to_rfc3339(),
+      removed_at: f.removed_at.map(|d| d.to_rfc3339()).unwrap_or_default(),
+      removed_by: f.removed_by.unwrap_or_default(),
+      reason: f.reason.unwrap_or_default(),
+      categories: f
+        .categories
+        .into_iter()
+        .map(|c| c.into())
+        .collect::<Vec<CategoryAmount>>(),
+    }
+  }
+}
+
+impl From<crate::commitment::CategoryAmount> for CategoryAmount {
+  fn from(f: crate::commitment::CategoryAmount) -> Self {
+    Self {
+      category: f.category,
+      total_net: f.total_net,
+      total_gross: f.total_gross,
+    }
+  }
+}
+
+impl From<CategoryAmount> for crate::commitment::CategoryAmount {
+  fn from(f: CategoryAmount) -> Self {
+    Self {
+      category: f.category,
+      total_net: f.total_net,
+      total_gross: f.total_gross,
+    }
+  }
+}
+
+impl From<crate::commitment::CategoryRule> for CategoryRule {
+  fn from(f: crate::commitment::CategoryRule) -> Self {
+    match f.discount {
+      crate::commitment::CategoryDiscount::Excluded => Self {
+        category: f.category,
+        excluded: true,
+        discount_percentage: 0,
+      },
+      crate::commitment::CategoryDiscount::Rate {
+        discount_percentage,
+      } => Self {
+        category: f.category,
+        excluded: false,
+        discount_percentage,
+      },
+    }
+  }
+}
+
+impl From<CategoryRule> for crate::commitment::CategoryRule {
+  fn from(f: CategoryRule) -> Self {
+    Self {
+      category: f.category,
+      discount: match f.excluded {
+        true => crate::commitment::CategoryDiscount::Excluded,
+        false => crate::commitment::CategoryDiscount::Rate {
+          discount_percentage: f.discount_percentage,
+        },
+      },
     }
   }
 }
@@ -93,7 +200,14 @@ impl From<crate::commitment::Commitment> for CommitmentObj {
       customer_id: f.customer_id,
       target: f.target,
       discount_percentage: f.discount_percentage,
+      valid_from: f.valid_from.to_rfc3339(),
       valid_till: f.valid_till.to_rfc3339(),
+      category_rules: f
+        .category_rules
+        .iter()
+        .map(|r| r.clone().into())
+        .collect::<Vec<CategoryRule>>(),
+      balance_basis: f.balance_basis.to_string(),
       balance: f.balance,
       purchase_log: f
         .purchase_log
@@ -126,10 +240,150 @@ impl From<crate::commitment::Customer> for CustomerObj {
     Self {
       customer_id: f.customer_id,
       commitments: f
-        .commitments
-        .iter()
-        .map(|c| c.clone().into())
+        .commitment_views()
+        .into_iter()
+        .map(|c| c.into())
         .collect::<Vec<CommitmentObj>>(),
     }
   }
 }
+
+impl From<crate::evaluation::Evaluation> for CommitmentEvaluation {
+  fn from(f: crate::evaluation::Evaluation) -> Self {
+    Self {
+      commitment_id: f.commitment_id.to_string(),
+      customer_id: f.customer_id,
+      target: f.target,
+      balance: f.balance,
+      balance_basis: f.balance_basis.to_string(),
+      achievement_percentage: f.achievement_percentage,
+      projected_balance: f.projected_balance,
+      status: f.status.to_string(),
+      suggested_discount_percentage: f.suggested_discount_percentage,
+    }
+  }
+}
+
+impl From<crate::journal::Event> for CommitmentEvent {
+  fn from(f: crate::journal::Event) -> Self {
+    Self {
+      event_id: f.event_id.to_string(),
+      customer_id: f.customer_id,
+      created_at: f.created_at.to_rfc3339(),
+      event_type: f.kind.name().to_string(),
+      // Event details as JSON
+      payload: serde_json::to_string(&f.kind).unwrap_or_default(),
+    }
+  }
+}
+
+impl From<BasketLine> for crate::discount::BasketLine {
+  fn from(f: BasketLine) -> Self {
+    Self {
+      sku: f.sku,
+      category: f.category,
+      total_net: f.total_net,
+      total_gross: f.total_gross,
+      eligible: f.eligible,
+    }
+  }
+}
+
+impl From<crate::discount::LineDiscount> for LineDiscount {
+  fn from(f: crate::discount::LineDiscount) -> Self {
+    Self {
+      sku: f.line.sku,
+      category: f.line.category,
+      total_net: f.line.total_net,
+      total_gross: f.line.total_gross,
+      eligible: f.line.eligible,
+      discount_percentage: f.discount_percentage,
+      discount_net: f.discount_net,
+      discount_gross: f.discount_gross,
+    }
+  }
+}
+
+impl From<crate::discount::DiscountCalculation> for CalculateDiscountResponse {
+  fn from(f: crate::discount::DiscountCalculation) -> Self {
+    Self {
+      customer_id: f.customer_id,
+      // Empty if there is no active commitment
+      commitment_id: f.commitment_id.map(|id| id.to_string()).unwrap_or_default(),
+      discount_percentage: f.discount_percentage,
+      lines: f
+        .lines
+        .into_iter()
+        .map(|l| l.into())
+        .collect::<Vec<LineDiscount>>(),
+      total_discount_net: f.total_discount_net,
+      total_discount_gross: f.total_discount_gross,
+    }
+  }
+}
+
+impl From<crate::report::TierStats> for TierStats {
+  fn from(f: crate::report::TierStats) -> Self {
+    Self {
+      discount_percentage: f.discount_percentage,
+      customers: f.customers,
+      total_target: f.total_target,
+      total_balance: f.total_balance,
+      total_discount: f.total_discount,
+    }
+  }
+}
+
+impl From<crate::report::MonthStats> for MonthStats {
+  fn from(f: crate::report::MonthStats) -> Self {
+    Self {
+      month: f.month,
+      purchases: f.purchases,
+      total_net: f.total_net,
+      total_gross: f.total_gross,
+      total_discount: f.total_discount,
+    }
+  }
+}
+
+impl From<crate::report::PortfolioReport> for PortfolioReport {
+  fn from(f: crate::report::PortfolioReport) -> Self {
+    Self {
+      from: f.from.to_rfc3339(),
+      till: f.till.to_rfc3339(),
+      tiers: f
+        .tiers
+        .into_iter()
+        .map(|t| t.into())
+        .collect::<Vec<TierStats>>(),
+      months: f
+        .months
+        .into_iter()
+        .map(|m| m.into())
+        .collect::<Vec<MonthStats>>(),
+      total_target: f.total_target,
+      total_balance: f.total_balance,
+      total_discount: f.total_discount,
+    }
+  }
+}
+
+impl From<crate::import::ImportResult> for ImportRowResult {
+  fn from(f: crate::import::ImportResult) -> Self {
+    let (commitment_id, error) = match f.result {
+      Ok(commitment_id) => (commitment_id.map(|id| id.to_string()), None),
+      Err(e) => (None, Some(e)),
+    };
+    Self {
+      row: f.row,
+      customer_id: f.customer_id,
+      ok: error.is_none(),
+      commitment_id: commitment_id.unwrap_or_default(),
+      code: error
+        .as_ref()
+        .map(|e| e.code.to_string())
+        .unwrap_or_default(),
+      error: error.map(|e| e.message).unwrap_or_default(),
+    }
+  }
+}
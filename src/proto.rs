@@ -0,0 +1,4 @@
+/// Generated from proto/commitment.proto
+pub mod commitment {
+  tonic::include_proto!("commitment");
+}
@@ -0,0 +1,285 @@
+use crate::commitment::{Commitment, CommitmentExt, Customer, CustomerExt};
+use crate::error::DomainError;
+use crate::evaluation;
+use chrono::{DateTime, Utc};
+
+/// Default number of commitments per page
+pub const DEFAULT_LIMIT: usize = 100;
+/// Maximum number of commitments per page
+pub const MAX_LIMIT: usize = 1000;
+
+/// Commitment state to filter by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommitmentState {
+  // Not withdrawn and inside its validity period
+  Active,
+  // Withdrawn by a successor
+  Withdrawn,
+  // Not withdrawn and its validity period is over
+  Expired,
+}
+
+impl std::str::FromStr for CommitmentState {
+  type Err = DomainError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "active" => Ok(CommitmentState::Active),
+      "withdrawn" => Ok(CommitmentState::Withdrawn),
+      "expired" => Ok(CommitmentState::Expired),
+      _ => Err(DomainError::UnknownCommitmentState(s.to_string())),
+    }
+  }
+}
+
+impl CommitmentState {
+  fn matches(&self, c: &Commitment, now: DateTime<Utc>) -> bool {
+    match self {
+      CommitmentState::Active => !c.is_withdrawn() && c.is_valid_at(now),
+      CommitmentState::Withdrawn => c.is_withdrawn(),
+      CommitmentState::Expired => !c.is_withdrawn() && c.valid_till <= now,
+    }
+  }
+}
+
+/// Position of a commitment in the stable order:
+/// by customer ID, then by creation order
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Cursor {
+  pub customer_id: u32,
+  pub index: usize, // Commitment index under the customer
+}
+
+impl std::fmt::Display for Cursor {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}:{}", self.customer_id, self.index)
+  }
+}
+
+impl std::str::FromStr for Cursor {
+  type Err = DomainError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let err = || DomainError::InvalidCursor(s.to_string());
+    let mut parts = s.splitn(2, ':');
+    let customer_id = parts
+      .next()
+      .and_then(|p| p.parse::<u32>().ok())
+      .ok_or_else(err)?;
+    let index = parts
+      .next()
+      .and_then(|p| p.parse::<usize>().ok())
+      .ok_or_else(err)?;
+    Ok(Self { customer_id, index })
+  }
+}
+
+/// Commitment filters; None means no filter
+/// Ranges are inclusive
+#[derive(Debug, Clone, Default)]
+pub struct CommitmentQuery {
+  pub states: Vec<CommitmentState>, // Any of them
+  pub discount_percentage: Option<u32>,
+  pub target_min: Option<u32>,
+  pub target_max: Option<u32>,
+  pub balance_min: Option<u32>,
+  pub balance_max: Option<u32>,
+  pub achievement_min: Option<u32>, // Balance / target in percentage
+  pub achievement_max: Option<u32>,
+  pub created_from: Option<DateTime<Utc>>,
+  pub created_till: Option<DateTime<Utc>>,
+  pub created_by: Option<u32>,
+  pub after: Option<Cursor>, // Continue after this position
+  pub limit: usize,
+}
+
+// Inclusive range check
+fn in_range<T: PartialOrd>(value: T, min: Option<T>, max: Option<T>) -> bool {
+  min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+}
+
+impl CommitmentQuery {
+  /// true if the commitment matches all the filters
+  pub fn matches(&self, c: &Commitment, now: DateTime<Utc>) -> bool {
+    (self.states.is_empty() || self.states.iter().any(|s| s.matches(c, now)))
+      && self
+        .discount_percentage
+        .is_none_or(|d| c.discount_percentage == d)
+      && in_range(c.target, self.target_min, self.target_max)
+      && in_range(c.balance, self.balance_min, self.balance_max)
+      && in_range(
+        evaluation::achievement_percentage(c.balance, c.target),
+        self.achievement_min,
+        self.achievement_max,
+      )
+      && in_range(c.created_at, self.created_from, self.created_till)
+      && self.created_by.is_none_or(|uid| c.created_by == uid)
+  }
+}
+
+/// Matching commitments of a customer after the cursor
+/// with the cursor of each
+pub fn customer_matches(
+  customer: &Customer,
+  q: &CommitmentQuery,
+  now: DateTime<Utc>,
+) -> Vec<(Cursor, Commitment)> {
+  customer
+    .commitment_views()
+    .into_iter()
+    .enumerate()
+    .map(|(index, c)| {
+      let cursor = Cursor {
+        customer_id: customer.customer_id,
+        index,
+      };
+      (cursor, c)
+    })
+    .filter(|(cursor, c)| q.after.is_none_or(|after| *cursor > after) && q.matches(c, now))
+    .collect()
+}
+
+/// One page of matching commitments in stable order
+/// with the cursor of each
+/// Customers are visited one by one from the cursor,
+/// matches_of returns the matching commitments of one
+pub fn query<F>(
+  mut customer_ids: Vec<u32>,
+  q: &CommitmentQuery,
+  mut matches_of: F,
+) -> Vec<(Cursor, Commitment)>
+where
+  F: FnMut(u32) -> Vec<(Cursor, Commitment)>,
+{
+  let limit = match q.limit {
+    0 => DEFAULT_LIMIT,
+    limit => limit.min(MAX_LIMIT),
+  };
+  // Skip customers before the cursor
+  customer_ids.retain(|id| q.after.is_none_or(|after| *id >= after.customer_id));
+  customer_ids.sort_unstable();
+  let mut res = Vec::new();
+  for customer_id in customer_ids {
+    res.extend(matches_of(customer_id));
+    if res.len() >= limit {
+      res.truncate(limit);
+      break;
+    }
+  }
+  res
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::commitment::{CommitmentOptions, PurchaseInfo};
+  use crate::policy::Policy;
+  use uuid::Uuid;
+
+  fn customers() -> Vec<Customer> {
+    let policy = Policy::default();
+    let mut res = Vec::new();
+    for customer_id in (1..=3).rev() {
+      let mut customer = Customer::new(
+        customer_id,
+        1000,
+        2,
+        customer_id,
+        CommitmentOptions::default(),
+        &policy,
+      )
+      .unwrap();
+      let id = customer.commitments[0].commitment_id;
+      customer
+        .add_purchase(
+          id,
+          PurchaseInfo::new(Uuid::new_v4(), 0, 300 * customer_id, 2),
+        )
+        .unwrap();
+      customer
+        .add_commitment(2000, 3, 9, CommitmentOptions::default(), &policy)
+        .unwrap();
+      res.push(customer);
+    }
+    res
+  }
+
+  // Query over the given customers
+  fn run(
+    customers: &[Customer],
+    q: &CommitmentQuery,
+    now: DateTime<Utc>,
+  ) -> Vec<(Cursor, Commitment)> {
+    let ids = customers.iter().map(|c| c.customer_id).collect();
+    query(ids, q, |id| {
+      let customer = customers.iter().find(|c| c.customer_id == id).unwrap();
+      customer_matches(customer, q, now)
+    })
+  }
+
+  #[test]
+  fn test_query_filters() {
+    let now = Utc::now();
+    let customers = customers();
+    let q = CommitmentQuery {
+      states: vec![CommitmentState::Withdrawn],
+      ..CommitmentQuery::default()
+    };
+    let res = run(&customers, &q, now);
+    assert_eq!(res.len(), 3);
+    assert!(res.iter().all(|(_, c)| c.discount_percentage == 2));
+    let q = CommitmentQuery {
+      states: vec![CommitmentState::Active],
+      balance_min: Some(600),
+      created_by: Some(9),
+      ..CommitmentQuery::default()
+    };
+    let res = run(&customers, &q, now);
+    assert_eq!(
+      res.iter().map(|(_, c)| c.customer_id).collect::<Vec<u32>>(),
+      vec![2, 3]
+    );
+    let q = CommitmentQuery {
+      achievement_min: Some(50),
+      target_max: Some(1000),
+      ..CommitmentQuery::default()
+    };
+    let res = run(&customers, &q, now);
+    assert_eq!(res.len(), 2);
+    let q = CommitmentQuery {
+      states: vec![CommitmentState::Expired],
+      ..CommitmentQuery::default()
+    };
+    assert!(run(&customers, &q, now).is_empty());
+  }
+
+  #[test]
+  fn test_query_pages() {
+    let now = Utc::now();
+    let customers = customers();
+    let mut q = CommitmentQuery {
+      limit: 4,
+      ..CommitmentQuery::default()
+    };
+    let first = run(&customers, &q, now);
+    assert_eq!(first.len(), 4);
+    let last = first.last().unwrap().0;
+    assert_eq!(last.to_string(), "2:1");
+    q.after = Some(last.to_string().parse().unwrap());
+    let second = run(&customers, &q, now);
+    assert_eq!(
+      second.iter().map(|(c, _)| *c).collect::<Vec<Cursor>>(),
+      vec![
+        Cursor {
+          customer_id: 3,
+          index: 0
+        },
+        Cursor {
+          customer_id: 3,
+          index: 1
+        }
+      ]
+    );
+    assert!("3".parse::<Cursor>().is_err());
+  }
+}
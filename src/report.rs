@@ -0,0 +1,248 @@
+use crate::commitment::{Commitment, CommitmentExt, Customer, CustomerExt, PurchaseInfo};
+use crate::discount::discount_amount;
+use crate::error::DomainError;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+
+/// Statistics of the commitments with the same discount percentage
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TierStats {
+  pub discount_percentage: u32,
+  pub customers: u32,      // Customers holding this tier
+  pub total_target: u64,   // Committed targets
+  pub total_balance: u64,  // Balance realised in the period
+  pub total_discount: u64, // Discount granted in the period
+}
+
+/// Statistics of the purchases in a calendar month
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MonthStats {
+  pub month: String, // YYYY-MM
+  pub purchases: u32,
+  pub total_net: u64,
+  pub total_gross: u64,
+  pub total_discount: u64, // Discount granted
+}
+
+/// Commitment portfolio statistics of a period
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortfolioReport {
+  pub from: DateTime<Utc>,
+  pub till: DateTime<Utc>,
+  pub tiers: Vec<TierStats>,   // By discount percentage
+  pub months: Vec<MonthStats>, // By month of purchase
+  pub total_target: u64,
+  pub total_balance: u64,
+  pub total_discount: u64,
+}
+
+/// Commitment the customer held in the period:
+/// the last one created before its end
+/// whose validity period overlaps it
+pub fn held_commitment(
+  customer: &Customer,
+  from: DateTime<Utc>,
+  till: DateTime<Utc>,
+) -> Option<&Commitment> {
+  customer
+    .commitments
+    .iter()
+    .rev()
+    .find(|c| c.created_at < till && c.valid_from < till && c.valid_till > from)
+}
+
+/// Discount granted on a purchase
+/// The discount recorded on it, not recalculated by the rules
+pub fn granted_discount(purchase: &PurchaseInfo) -> u64 {
+  discount_amount(purchase.total_gross, purchase.applied_discount) as u64
+}
+
+/// Portfolio statistics of a period,
+/// accumulated customer by customer
+pub struct PortfolioBuilder {
+  from: DateTime<Utc>,
+  till: DateTime<Utc>,
+  tiers: BTreeMap<u32, TierStats>,      // By discount percentage
+  months: BTreeMap<String, MonthStats>, // By month of purchase
+}
+
+impl PortfolioBuilder {
+  /// Empty statistics of the given period
+  pub fn new(from: DateTime<Utc>, till: DateTime<Utc>) -> Result<Self, DomainError> {
+    if from >= till {
+      return Err(DomainError::InvalidPeriod);
+    }
+    Ok(Self {
+      from,
+      till,
+      tiers: BTreeMap::new(),
+      months: BTreeMap::new(),
+    })
+  }
+
+  /// Count the commitment the customer held in the period
+  /// and its purchases of the period
+  pub fn add(&mut self, customer: &Customer) -> Result<(), DomainError> {
+    let (from, till) = (self.from, self.till);
+    let held = match held_commitment(customer, from, till) {
+      Some(c) => c,
+      None => return Ok(()),
+    };
+    let tier = self
+      .tiers
+      .entry(held.discount_percentage)
+      .or_insert_with(|| TierStats {
+        discount_percentage: held.discount_percentage,
+        ..TierStats::default()
+      });
+    tier.customers += 1;
+    tier.total_target += held.target as u64;
+
+    // Purchases of the period, not removed
+    for purchase in customer
+      .purchases
+      .values()
+      .filter(|p| p.crated_at >= from && p.crated_at < till)
+      .filter(|p| customer.current_removal(p).is_none())
+    {
+      let c = customer
+        .commitments
+        .iter()
+        .find(|c| c.commitment_id == purchase.commitment_id)
+        .ok_or(DomainError::CommitmentNotFound)?;
+      let discount = granted_discount(purchase);
+      let month = purchase.crated_at.format("%Y-%m").to_string();
+      let stats = self
+        .months
+        .entry(month.clone())
+        .or_insert_with(|| MonthStats {
+          month,
+          ..MonthStats::default()
+        });
+      stats.purchases += 1;
+      stats.total_net += purchase.total_net as u64;
+      stats.total_gross += purchase.total_gross as u64;
+      stats.total_discount += discount;
+      // Balance and discount realised by the tier
+      // of the commitment the purchase was added to
+      let tier = self
+        .tiers
+        .entry(c.discount_percentage)
+        .or_insert_with(|| TierStats {
+          discount_percentage: c.discount_percentage,
+          ..TierStats::default()
+        });
+      tier.total_balance += c.eligible_amount(purchase) as u64;
+      tier.total_discount += discount;
+    }
+    Ok(())
+  }
+
+  /// Statistics of the added customers
+  pub fn build(self) -> PortfolioReport {
+    let tiers = self.tiers.into_values().collect::<Vec<TierStats>>();
+    PortfolioReport {
+      from: self.from,
+      till: self.till,
+      total_target: tiers.iter().map(|t| t.total_target).sum(),
+      total_balance: tiers.iter().map(|t| t.total_balance).sum(),
+      total_discount: tiers.iter().map(|t| t.total_discount).sum(),
+      tiers,
+      months: self.months.into_values().collect(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::commitment::{CategoryAmount, CommitmentOptions, RemovalInfo};
+  use crate::policy::Policy;
+  use chrono::{Duration, TimeZone};
+  use uuid::Uuid;
+
+  fn portfolio_report(
+    customers: &[Customer],
+    from: DateTime<Utc>,
+    till: DateTime<Utc>,
+  ) -> Result<PortfolioReport, DomainError> {
+    let mut report = PortfolioBuilder::new(from, till)?;
+    for customer in customers {
+      report.add(customer)?;
+    }
+    Ok(report.build())
+  }
+
+  #[test]
+  fn test_portfolio_report() {
+    let policy = Policy::default();
+    let mut customers = Vec::new();
+    for &(customer_id, discount_percentage) in [(1, 2), (2, 2), (3, 4)].iter() {
+      let mut customer = Customer::new(
+        customer_id,
+        1000,
+        discount_percentage,
+        0,
+        CommitmentOptions::default(),
+        &policy,
+      )
+      .unwrap();
+      let id = customer.commitments[0].commitment_id;
+      for _ in 0..2 {
+        customer
+          .add_purchase(
+            id,
+            PurchaseInfo::new(Uuid::new_v4(), 1000, 1000, discount_percentage),
+          )
+          .unwrap();
+      }
+      customers.push(customer);
+    }
+    // Removed purchases are not counted
+    let purchase_id = customers[2].purchases[0].purchase_id;
+    let id = customers[2].commitments[0].commitment_id;
+    customers[2]
+      .remove_purchase(id, &purchase_id, &RemovalInfo::new(1, None))
+      .unwrap();
+
+    let now = Utc::now();
+    let r = portfolio_report(&customers, now - Duration::days(1), now + Duration::days(1)).unwrap();
+    assert_eq!(r.tiers.len(), 2);
+    assert_eq!(r.tiers[0].discount_percentage, 2);
+    assert_eq!(r.tiers[0].customers, 2);
+    assert_eq!(r.tiers[0].total_target, 2000);
+    assert_eq!(r.tiers[0].total_balance, 4000);
+    assert_eq!(r.tiers[0].total_discount, 80);
+    assert_eq!(r.tiers[1].total_balance, 1000);
+    assert_eq!(r.tiers[1].total_discount, 40);
+    assert_eq!(r.total_discount, 120);
+    assert_eq!(r.months.len(), 1);
+    assert_eq!(r.months[0].purchases, 5);
+    assert_eq!(r.months[0].month, now.format("%Y-%m").to_string());
+
+    // Period without commitments
+    let r = portfolio_report(
+      &customers,
+      Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap(),
+      Utc.with_ymd_and_hms(2000, 2, 1, 0, 0, 0).unwrap(),
+    )
+    .unwrap();
+    assert!(r.tiers.is_empty());
+    assert!(portfolio_report(&customers, now, now).is_err());
+
+    // Balances count the purchases of the period only
+    customers[0].purchases[0].crated_at = now - Duration::days(10);
+    let r = portfolio_report(&customers, now - Duration::days(1), now + Duration::days(1)).unwrap();
+    assert_eq!(r.tiers[0].total_balance, 3000);
+    assert_eq!(customers[0].commitment_views()[0].balance, 2000);
+
+    // Recorded discount, whatever the breakdown is
+    let purchase =
+      PurchaseInfo::new(Uuid::new_v4(), 1000, 1270, 3).with_categories(vec![CategoryAmount {
+        category: "tobacco".to_string(),
+        total_net: 1000,
+        total_gross: 1270,
+      }]);
+    assert_eq!(granted_discount(&purchase), 38);
+  }
+}
@@ -0,0 +1,196 @@
+use crate::commitment::{
+  Commitment, CommitmentExt, CommitmentOptions, Customer, CustomerExt, SYSTEM_UID,
+};
+use crate::error::DomainError;
+use crate::evaluation;
+use crate::journal;
+use crate::policy::{Policy, RolloverRule};
+use crate::prelude::*;
+use crate::store::Store;
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Get the expired commitment that needs a successor
+/// None if the customer has an active or upcoming commitment
+pub fn expired_commitment(customer: &Customer, now: DateTime<Utc>) -> Option<&Commitment> {
+  match customer.commitments.last() {
+    Some(last) if !last.is_withdrawn() && last.valid_till <= now => Some(last),
+    _ => None,
+  }
+}
+
+/// Try to roll over the expired customer commitment into the next period
+/// Returns the new commitment ID, or None if no rollover is needed
+pub fn rollover_customer(
+  customer: &mut Customer,
+  policy: &Policy,
+  now: DateTime<Utc>,
+) -> Result<Option<Uuid>, DomainError> {
+  let (target, discount_percentage, options) = match expired_commitment(customer, now) {
+    Some(expired) => {
+      // Expired commitment with its balance
+      let expired = customer.get_commitment(&expired.commitment_id)?;
+      // Next period following the expired one,
+      // skipping periods passed in the meantime
+      let mut period = policy.validity.next_period(expired.valid_till);
+      while period.valid_till <= now {
+        period = policy.validity.next_period(period.valid_till);
+      }
+      let discount_percentage = match policy.rollover.rule {
+        // Keep discount if still allowed,
+        // otherwise the highest lower one
+        RolloverRule::Carry => {
+          let dp = policy
+            .discount_policy_at(now)
+            .ok_or(DomainError::NoDiscountPolicy)?;
+          evaluation::allowed_discount(expired.discount_percentage, expired.target, dp).ok_or(
+            DomainError::NoAllowedDiscount {
+              target: expired.target,
+            },
+          )?
+        }
+        // Apply the evaluation suggestion
+        RolloverRule::PromoteDemote { .. } => evaluation::suggest_discount(&expired, policy, now)?,
+      };
+      // Category rules and balance basis
+      // are carried over from the expired commitment
+      let options = CommitmentOptions {
+        validity: Some(period),
+        category_rules: Some(expired.category_rules),
+        balance_basis: Some(expired.balance_basis),
+      };
+      (expired.target, discount_percentage, options)
+    }
+    None => return Ok(None),
+  };
+  customer.add_commitment(target, discount_percentage, SYSTEM_UID, options, policy)?;
+  Ok(customer.commitments.last().map(|c| c.commitment_id))
+}
+
+/// Roll over all customers with expired commitment
+/// Returns the affected customer IDs with their results;
+/// customers failing to save are logged, not returned
+pub async fn rollover_all(store: &Store, policy: &Policy) -> Vec<(u32, Result<Uuid, DomainError>)> {
+  let now = Utc::now();
+  let mut res = Vec::new();
+  // Customers needing rollover; only these are locked and saved
+  let customer_ids = store
+    .customers()
+    .iter()
+    .filter(|c| expired_commitment(c, now).is_some())
+    .map(|c| c.customer_id)
+    .collect::<Vec<u32>>();
+  for customer_id in customer_ids {
+    let rolled = store
+      .update(customer_id, |customer| {
+        let from_index = customer.commitments.len();
+        // Could be rolled over in the meantime
+        let commitment_id = rollover_customer(customer, policy, now)?;
+        Ok((
+          commitment_id,
+          journal::commitment_events(customer, from_index),
+        ))
+      })
+      .await;
+    match rolled {
+      Ok(Some(commitment_id)) => res.push((customer_id, Ok(commitment_id))),
+      Ok(None) => (),
+      Err(ServiceError::Domain(e)) => res.push((customer_id, Err(e))),
+      Err(e) => tracing::error!(customer_id, error = %e, "Rollover could not be saved"),
+    }
+  }
+  res
+}
+
+/// Scheduled rollover job
+/// Checks expired commitments periodically, first right at startup
+pub async fn run(store: Arc<Store>, policy: Policy) {
+  let mut interval =
+    tokio::time::interval(Duration::from_secs(policy.rollover.check_interval_secs));
+  loop {
+    interval.tick().await;
+    for (customer_id, res) in rollover_all(&store, &policy).await {
+      match res {
+        Ok(commitment_id) => tracing::info!(
+          customer_id,
+          commitment_id = %commitment_id,
+          "Rollover: new commitment"
+        ),
+        Err(e) => tracing::error!(customer_id, error = %e, "Rollover failed"),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::commitment::PurchaseInfo;
+  use chrono::Duration;
+
+  // Customer with an expired commitment
+  fn expired_customer(target: u32, balance: u32, discount_percentage: u32) -> Customer {
+    let mut customer = Customer::new(
+      1,
+      target,
+      discount_percentage,
+      5,
+      CommitmentOptions::default(),
+      &Policy::default(),
+    )
+    .unwrap();
+    let commitment_id = customer.commitments[0].commitment_id;
+    customer
+      .add_purchase(
+        commitment_id,
+        PurchaseInfo::new(Uuid::new_v4(), balance, balance, discount_percentage),
+      )
+      .unwrap();
+    let c = &mut customer.commitments[0];
+    c.valid_from = Utc::now() - Duration::days(400);
+    c.valid_till = Utc::now() - Duration::days(35);
+    customer
+  }
+
+  #[test]
+  fn test_rollover_carry() {
+    let policy = Policy::default();
+    let mut customer = expired_customer(1000, 10, 3);
+    assert!(!customer.has_active_commitment());
+    let id = rollover_customer(&mut customer, &policy, Utc::now())
+      .unwrap()
+      .unwrap();
+    let c = customer.get_active_commitment().unwrap();
+    assert_eq!(c.commitment_id, id);
+    assert_eq!(c.target, 1000);
+    assert_eq!(c.discount_percentage, 3);
+    assert_eq!(c.created_by, SYSTEM_UID);
+    assert_eq!(c.balance, 0);
+    // Nothing to do anymore
+    assert_eq!(
+      rollover_customer(&mut customer, &policy, Utc::now()),
+      Ok(None)
+    );
+  }
+
+  #[test]
+  fn test_rollover_promote_demote() {
+    let mut policy = Policy::default();
+    policy.rollover.rule = RolloverRule::PromoteDemote {
+      promote_by: 1,
+      demote_by: 2,
+    };
+
+    let mut achieved = expired_customer(1000, 1000, 3);
+    rollover_customer(&mut achieved, &policy, Utc::now()).unwrap();
+    assert_eq!(achieved.commitments[1].discount_percentage, 4);
+    let mut missed = expired_customer(1000, 999, 3);
+    rollover_customer(&mut missed, &policy, Utc::now()).unwrap();
+    assert_eq!(missed.commitments[1].discount_percentage, 1);
+    let mut top = expired_customer(1000, 1000, 6);
+    rollover_customer(&mut top, &policy, Utc::now()).unwrap();
+    assert_eq!(top.commitments[1].discount_percentage, 6);
+  }
+}
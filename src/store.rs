@@ -0,0 +1,542 @@
+use crate::commitment::{self, Customer, CustomerExt};
+use crate::journal::{self, Event, Journal};
+use crate::metrics::Metrics;
+use crate::prelude::*;
+use packman::*;
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+/// Customer store with per-customer locking
+/// Mutations of the same customer are serialized,
+/// different customers are updated concurrently
+pub struct Store {
+  // Loaded records; locked only while records
+  // are read or a saved one is swapped in
+  db: Arc<Mutex<Db>>,
+  // Directory of the record files
+  db_path: PathBuf,
+  // Mutation locks, sharded by customer ID
+  locks: Vec<tokio::sync::Mutex<()>>,
+  journal: Arc<Journal>,
+  metrics: Metrics,
+}
+
+/// Number of customer mutation lock shards
+/// Customers of the same shard are serialized as well
+const LOCK_SHARDS: usize = 256;
+
+/// Number of customers read at a time by a scan,
+/// writes wait for the storage lock at most this long
+pub const SCAN_BATCH: usize = 1000;
+
+// Loaded records with their position by customer ID
+struct Db {
+  records: VecPack<Customer>,
+  index: BTreeMap<u32, usize>,
+}
+
+impl Db {
+  fn new(records: VecPack<Customer>) -> Self {
+    let index = records
+      .iter()
+      .enumerate()
+      .map(|(i, c)| (c.unpack().customer_id, i))
+      .collect();
+    Self { records, index }
+  }
+
+  fn get(&self, customer_id: u32) -> Option<&Customer> {
+    let i = self.index.get(&customer_id)?;
+    Some(self.records[*i].unpack())
+  }
+
+  fn range<R: RangeBounds<u32>>(&self, ids: R) -> impl Iterator<Item = &Customer> {
+    self
+      .index
+      .range(ids)
+      .map(move |(_, i)| self.records[*i].unpack())
+  }
+}
+
+/// Customers read without copying them, in customer ID order
+/// The storage is locked while it is held, so it must be dropped
+/// quickly, and it cannot be held across an await
+pub struct Customers<'a>(MutexGuard<'a, Db>);
+
+impl Customers<'_> {
+  /// The given customer if exists
+  pub fn get(&self, customer_id: u32) -> Option<&Customer> {
+    self.0.get(customer_id)
+  }
+
+  /// All the customers
+  pub fn iter(&self) -> impl Iterator<Item = &Customer> {
+    self.0.range(..)
+  }
+
+  /// Customers of the given customer ID range
+  pub fn range<R: RangeBounds<u32>>(&self, ids: R) -> impl Iterator<Item = &Customer> {
+    self.0.range(ids)
+  }
+}
+
+impl Store {
+  pub fn new(db: VecPack<Customer>, journal: Arc<Journal>) -> Self {
+    // Customer gauges are kept up to date by the writes from now on
+    let metrics = Metrics::default();
+    for customer in db.iter() {
+      metrics.customer_saved(customer.unpack());
+    }
+    Self {
+      db_path: db.get_path().to_path_buf(),
+      db: Arc::new(Mutex::new(Db::new(db))),
+      locks: (0..LOCK_SHARDS)
+        .map(|_| tokio::sync::Mutex::new(()))
+        .collect(),
+      journal,
+      metrics,
+    }
+  }
+
+  /// Open the customer database with its event journal
+  /// A new journal is seeded with the existing state
+  /// Legacy purchase logs are not migrated here, the database
+  /// must be migrated by recalc-balances --apply first
+  pub fn open(db_path: &Path, journal_path: &Path) -> Result<Self, String> {
+    let db = load_db(db_path)?;
+
+    // Check balance invariant and report corrupted balances
+    for mismatch in commitment::check_balances(db.iter().map(|c| c.unpack())) {
+      tracing::warn!("Balance mismatch: {}", mismatch);
+    }
+
+    let legacy = db.iter().filter(|c| c.unpack().needs_migration()).count();
+    if legacy > 0 {
+      return Err(format!(
+        "{} customers have legacy purchase logs, run recalc-balances --apply first",
+        legacy
+      ));
+    }
+
+    let journal = Journal::open(journal_path.to_path_buf())?;
+    if journal.is_empty()? {
+      for customer in db.iter() {
+        journal.append(journal::snapshot_events(customer.unpack()))?;
+      }
+    }
+
+    Ok(Self::new(db, Arc::new(journal)))
+  }
+
+  // Storage lock; no await is allowed while it is held
+  fn db(&self) -> MutexGuard<'_, Db> {
+    lock_db(&self.db, &self.metrics)
+  }
+
+  // Wait for the mutation lock of the given customer
+  async fn lock_customer(&self, customer_id: u32) -> tokio::sync::MutexGuard<'_, ()> {
+    let start = Instant::now();
+    let guard = self.locks[customer_id as usize % LOCK_SHARDS].lock().await;
+    self.metrics.lock_wait("customer", start);
+    guard
+  }
+
+  /// Event journal
+  pub fn journal(&self) -> &Journal {
+    &self.journal
+  }
+
+  /// Service metrics
+  pub fn metrics(&self) -> &Metrics {
+    &self.metrics
+  }
+
+  /// All customer IDs in ascending order
+  pub fn customer_ids(&self) -> Vec<u32> {
+    self.db().index.keys().copied().collect::<Vec<u32>>()
+  }
+
+  /// Copy of the given customer
+  pub fn get(&self, customer_id: u32) -> ServiceResult<Customer> {
+    self.read(customer_id, |c| c.clone())
+  }
+
+  /// Read the given customer without copying it
+  /// The storage is locked meanwhile, so f must be quick
+  pub fn read<R, F>(&self, customer_id: u32, f: F) -> ServiceResult<R>
+  where
+    F: FnOnce(&Customer) -> R,
+  {
+    let db = self.db();
+    let customer = db.get(customer_id).ok_or(PackError::ObjectNotFound)?;
+    Ok(f(customer))
+  }
+
+  /// Read the customers without copying them
+  /// The storage is locked until the result is dropped
+  pub fn customers(&self) -> Customers<'_> {
+    Customers(self.db())
+  }
+
+  /// Visit the customers in customer ID order without copying them
+  /// The storage is locked for SCAN_BATCH customers at a time,
+  /// so writes are not blocked for the whole scan
+  pub fn scan<E, F>(&self, mut f: F) -> Result<(), E>
+  where
+    F: FnMut(&Customer) -> Result<(), E>,
+  {
+    let mut from = Bound::Unbounded;
+    loop {
+      let customers = self.customers();
+      let mut last = None;
+      for c in customers.range((from, Bound::Unbounded)).take(SCAN_BATCH) {
+        f(c)?;
+        last = Some(c.customer_id);
+      }
+      match last {
+        Some(customer_id) => from = Bound::Excluded(customer_id),
+        None => return Ok(()),
+      }
+    }
+  }
+
+  /// Apply a mutation on an existing customer while holding its lock
+  /// The customer is saved and the returned events are journaled
+  /// only if the mutation succeeds
+  pub async fn update<R, F>(&self, customer_id: u32, f: F) -> ServiceResult<R>
+  where
+    F: FnOnce(&mut Customer) -> ServiceResult<(R, Vec<Event>)>,
+  {
+    let _guard = self.lock_customer(customer_id).await;
+    let customer = self.get(customer_id)?;
+    self.apply(customer, f).await
+  }
+
+  /// Same as update, but starts from an empty customer if not exists
+  /// Get or create is atomic, as it is done under the customer lock
+  pub async fn upsert<R, F>(&self, customer_id: u32, f: F) -> ServiceResult<R>
+  where
+    F: FnOnce(&mut Customer) -> ServiceResult<(R, Vec<Event>)>,
+  {
+    let _guard = self.lock_customer(customer_id).await;
+    let customer = match self.get(customer_id) {
+      Ok(customer) => customer,
+      Err(ServiceError::NotFound(_)) => Customer {
+        customer_id,
+        ..Customer::default()
+      },
+      Err(e) => return Err(e),
+    };
+    self.apply(customer, f).await
+  }
+
+  // Mutate, journal and save the customer
+  // Must be called while holding the customer lock
+  async fn apply<R, F>(&self, mut customer: Customer, f: F) -> ServiceResult<R>
+  where
+    F: FnOnce(&mut Customer) -> ServiceResult<(R, Vec<Event>)>,
+  {
+    let (res, events) = f(&mut customer)?;
+    // Every change is journaled,
+    // so no events means nothing has changed
+    if events.is_empty() {
+      return Ok(res);
+    }
+    // Both wait for the disk, so they run on a blocking thread
+    let db = self.db.clone();
+    let db_path = self.db_path.clone();
+    let journal = self.journal.clone();
+    let metrics = self.metrics.clone();
+    tokio::task::spawn_blocking(move || {
+      persist(&db, &db_path, &journal, &metrics, customer, events)
+    })
+    .await
+    .map_err(|e| ServiceError::internal_error(&e.to_string()))??;
+    Ok(res)
+  }
+}
+
+/// Load the customer records of the database directory
+/// Records of the first release are converted to the current format
+/// A record of no known format is an error naming its file,
+/// VecPack::try_load_or_init would panic on it
+pub fn load_db(db_path: &Path) -> Result<VecPack<Customer>, String> {
+  let error = |e: &dyn std::fmt::Display| format!("Error while loading commitments db: {}", e);
+  if db_path.is_file() {
+    return Err(error(&format!("{} is not a directory", db_path.display())));
+  }
+  let mut db = VecPack::new(db_path.to_path_buf()).map_err(|e| error(&e))?;
+  for entry in std::fs::read_dir(db_path).map_err(|e| error(&e))? {
+    let path = entry.map_err(|e| error(&e))?.path();
+    let pack = Pack::<Customer>::try_load_from_path(path.clone())
+      .map_err(|e| error(&format!("cannot read record {}: {}", path.display(), e)))?;
+    db.insert_pack(pack)
+      .map_err(|e| error(&format!("cannot add record {}: {}", path.display(), e)))?;
+  }
+  Ok(db)
+}
+
+// Storage lock; no await is allowed while it is held
+// Panics cannot happen while a record is half written,
+// so a poisoned lock is still consistent
+fn lock_db<'a>(db: &'a Mutex<Db>, metrics: &Metrics) -> MutexGuard<'a, Db> {
+  let start = Instant::now();
+  let db = db.lock().unwrap_or_else(|e| e.into_inner());
+  metrics.lock_wait("db", start);
+  db
+}
+
+// Journal the events, then save the customer, insert it if new
+// A saved change is always journaled; a journaled change
+// failed to save is restored by the rebuild command
+// An existing record is saved without the storage lock, which is
+// held only to swap it in, so saves run concurrently
+// Must be called while holding the customer lock
+fn persist(
+  db: &Mutex<Db>,
+  db_path: &Path,
+  journal: &Journal,
+  metrics: &Metrics,
+  customer: Customer,
+  events: Vec<Event>,
+) -> ServiceResult<()> {
+  let start = Instant::now();
+  journal
+    .append(events)
+    .map_err(|e| ServiceError::internal_error(&e))?;
+  metrics.persistence_write("journal", start);
+  let start = Instant::now();
+  // Only the holder of the customer lock writes its file,
+  // so it cannot be created meanwhile
+  let path = db_path.join(customer.customer_id.to_string());
+  match path.is_file() {
+    true => {
+      let pack = save_record(path, customer)?;
+      metrics.persistence_write("db", start);
+      // Gauges follow the saved state
+      metrics.customer_saved(pack.unpack());
+      let mut db = lock_db(db, metrics);
+      let i = *db
+        .index
+        .get(&pack.unpack().customer_id)
+        .ok_or(PackError::ObjectNotFound)?;
+      db.records.as_vec_mut()[i] = pack;
+    }
+    // New record, saved by VecPack under the storage lock
+    false => {
+      metrics.customer_saved(&customer);
+      let customer_id = customer.customer_id;
+      let mut db = lock_db(db, metrics);
+      db.records.insert(customer)?;
+      let i = db.records.len() - 1;
+      db.index.insert(customer_id, i);
+      metrics.persistence_write("db", start);
+    }
+  }
+  Ok(())
+}
+
+// Save an existing customer record through its Pack,
+// and return the saved Pack to be swapped in
+fn save_record(path: PathBuf, customer: Customer) -> PackResult<Pack<Customer>> {
+  let mut pack = Pack::<Customer>::load_from_path(path)?;
+  pack.update(|c| *c = customer)?;
+  Ok(pack)
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use crate::commitment::{
+    Commitment, CommitmentExt, CommitmentOptions, CustomerExt, PurchaseInfo, RemovalInfo,
+  };
+  use crate::journal;
+  use crate::policy::Policy;
+  use uuid::Uuid;
+
+  // Store in a new temporary directory
+  pub fn temp_store() -> Store {
+    let dir = std::env::temp_dir().join(format!("commitment_test_{}", Uuid::new_v4()));
+    let db = load_db(&dir.join("commitments")).unwrap();
+    let journal = Journal::open(dir.join("events.jsonl")).unwrap();
+    Store::new(db, Arc::new(journal))
+  }
+
+  #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+  async fn test_upsert_atomic() {
+    let store = Arc::new(temp_store());
+    let policy = Arc::new(Policy::default());
+    // Concurrent first commitments of the same new customer
+    let mut handles = Vec::new();
+    for _ in 0..20 {
+      let store = store.clone();
+      let policy = policy.clone();
+      handles.push(tokio::spawn(async move {
+        store
+          .upsert(1, |customer| {
+            let from_index = customer.commitments.len();
+            customer.add_commitment(1000, 2, 1, CommitmentOptions::default(), &policy)?;
+            Ok(((), journal::commitment_events(customer, from_index)))
+          })
+          .await
+      }));
+    }
+    for handle in handles {
+      assert!(handle.await.unwrap().is_ok());
+    }
+    assert_eq!(store.customer_ids(), vec![1]);
+    let customer = store.get(1).unwrap();
+    // Each one withdrew the previous one
+    assert_eq!(customer.commitments.len(), 20);
+    assert_eq!(
+      customer
+        .commitments
+        .iter()
+        .filter(|c| !c.is_withdrawn())
+        .count(),
+      1
+    );
+    // Journal replays to the same state
+    let rebuilt =
+      journal::rebuild_customer(1, &store.journal().customer_events(1).unwrap()).unwrap();
+    assert_eq!(
+      serde_json::to_string(&rebuilt).unwrap(),
+      serde_json::to_string(&customer).unwrap()
+    );
+  }
+
+  #[tokio::test]
+  async fn test_reopen() {
+    let dir = std::env::temp_dir().join(format!("commitment_test_{}", Uuid::new_v4()));
+    let (db_path, journal_path) = (dir.join("commitments"), dir.join("events.jsonl"));
+    let policy = Policy::default();
+    let purchase_id = Uuid::new_v4();
+    let store = Store::open(&db_path, &journal_path).unwrap();
+    store
+      .upsert(1, |customer| {
+        customer.add_commitment(1000, 2, 1, CommitmentOptions::default(), &policy)?;
+        let commitment_id = customer.commitments[0].commitment_id;
+        customer.add_purchase(commitment_id, PurchaseInfo::new(purchase_id, 100, 127, 2))?;
+        customer.remove_purchase(commitment_id, &purchase_id, &RemovalInfo::new(1, None))?;
+        Ok(((), journal::commitment_events(customer, 0)))
+      })
+      .await
+      .unwrap();
+    let customer = store.get(1).unwrap();
+    drop(store);
+
+    // Registry records are read back as stored
+    let store = Store::open(&db_path, &journal_path).unwrap();
+    assert_eq!(
+      serde_json::to_string(&store.get(1).unwrap()).unwrap(),
+      serde_json::to_string(&customer).unwrap()
+    );
+  }
+
+  #[test]
+  fn test_persist_without_storage_lock() {
+    let store = temp_store();
+    let db = store.db.clone();
+    let db_path = store.db_path.clone();
+    let journal = store.journal.clone();
+    let metrics = store.metrics.clone();
+    let mut customer = Customer {
+      customer_id: 1,
+      ..Customer::default()
+    };
+    persist(
+      &db,
+      &db_path,
+      &journal,
+      &metrics,
+      customer.clone(),
+      Vec::new(),
+    )
+    .unwrap();
+    // Storage lock is held by a slow reader
+    let guard = store.db();
+    customer.commitments.push(Commitment::default());
+    let handle =
+      std::thread::spawn(move || persist(&db, &db_path, &journal, &metrics, customer, Vec::new()));
+    // The record is saved meanwhile
+    let start = Instant::now();
+    while Pack::<Customer>::load_from_path(store.db_path.join("1"))
+      .map_or(true, |p| p.unpack().commitments.is_empty())
+    {
+      assert!(
+        start.elapsed() < std::time::Duration::from_secs(10),
+        "Record saved under the storage lock"
+      );
+      std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    drop(guard);
+    handle.join().unwrap().unwrap();
+    assert_eq!(store.customer_ids(), vec![1]);
+  }
+
+  #[test]
+  fn test_customers_by_id() {
+    let store = temp_store();
+    for customer_id in [3, 1, 2] {
+      let customer = Customer {
+        customer_id,
+        ..Customer::default()
+      };
+      persist(
+        &store.db,
+        &store.db_path,
+        &store.journal,
+        &store.metrics,
+        customer,
+        Vec::new(),
+      )
+      .unwrap();
+    }
+    let ids = |customers: Vec<&Customer>| {
+      customers
+        .iter()
+        .map(|c| c.customer_id)
+        .collect::<Vec<u32>>()
+    };
+    {
+      let customers = store.customers();
+      assert_eq!(ids(customers.iter().collect()), vec![1, 2, 3]);
+      assert_eq!(ids(customers.range(2..).collect()), vec![2, 3]);
+      assert_eq!(customers.get(3).unwrap().customer_id, 3);
+      assert!(customers.get(4).is_none());
+    }
+    let mut scanned = Vec::new();
+    store
+      .scan(|c| {
+        scanned.push(c.customer_id);
+        Ok::<(), ()>(())
+      })
+      .unwrap();
+    assert_eq!(scanned, vec![1, 2, 3]);
+    assert_eq!(store.customer_ids(), vec![1, 2, 3]);
+  }
+
+  #[tokio::test]
+  async fn test_update_not_found() {
+    let store = temp_store();
+    let res = store.update(1, |_| Ok(((), Vec::new()))).await;
+    assert!(matches!(res, Err(ServiceError::NotFound(_))));
+  }
+
+  #[test]
+  fn test_load_db_bad_record() {
+    let dir = std::env::temp_dir().join(format!("commitment_test_{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("1"), b"no record").unwrap();
+    let res = load_db(&dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+    // Error names the record instead of a panic
+    assert!(res
+      .err()
+      .unwrap()
+      .contains(&dir.join("1").display().to_string()));
+  }
+}
@@ -0,0 +1,185 @@
+use crate::config::TlsConfig;
+use std::path::Path;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+
+// Read a PEM file
+fn read_pem(path: &Path) -> Result<Vec<u8>, String> {
+  std::fs::read(path).map_err(|e| format!("Error while reading TLS file {}: {}", path.display(), e))
+}
+
+/// Server TLS settings from the configured PEM files
+/// With client CA only clients presenting a certificate
+/// signed by it are accepted
+pub fn server_tls_config(config: &TlsConfig) -> Result<ServerTlsConfig, String> {
+  let identity = Identity::from_pem(read_pem(&config.cert_path)?, read_pem(&config.key_path)?);
+  let mut res = ServerTlsConfig::new().identity(identity);
+  if let Some(client_ca_path) = &config.client_ca_path {
+    res = res.client_ca_root(Certificate::from_pem(read_pem(client_ca_path)?));
+  }
+  Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::idempotency::IdempotencyCache;
+  use crate::policy::Policy;
+  use crate::proto::commitment::{
+    commitment_client::CommitmentClient, commitment_server::CommitmentServer,
+  };
+  use crate::store::tests::temp_store;
+  use crate::CommitmentService;
+  use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa};
+  use std::net::SocketAddr;
+  use std::path::PathBuf;
+  use std::sync::Arc;
+  use tokio::sync::oneshot;
+  use tokio_stream::wrappers::TcpListenerStream;
+  use tonic::transport::{Channel, ClientTlsConfig, Server};
+  use uuid::Uuid;
+
+  // Locally generated CA with server and client certificates
+  struct TestPki {
+    dir: PathBuf,
+    ca: rcgen::Certificate,
+  }
+
+  impl TestPki {
+    fn new() -> Self {
+      let dir = std::env::temp_dir().join(format!("commitment_tls_{}", Uuid::new_v4()));
+      std::fs::create_dir_all(&dir).unwrap();
+      let mut params = CertificateParams::new(Vec::new());
+      params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+      params
+        .distinguished_name
+        .push(DnType::CommonName, "Commitment test CA");
+      let ca = rcgen::Certificate::from_params(params).unwrap();
+      std::fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+      Self { dir, ca }
+    }
+
+    fn ca_pem(&self) -> Vec<u8> {
+      std::fs::read(self.dir.join("ca.pem")).unwrap()
+    }
+
+    // Certificate signed by the CA; returns the cert and key paths
+    fn issue(&self, name: &str) -> (PathBuf, PathBuf) {
+      let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+      let cert_path = self.dir.join(format!("{}.pem", name));
+      let key_path = self.dir.join(format!("{}.key", name));
+      std::fs::write(
+        &cert_path,
+        cert.serialize_pem_with_signer(&self.ca).unwrap(),
+      )
+      .unwrap();
+      std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+      (cert_path, key_path)
+    }
+
+    fn identity(&self, name: &str) -> Identity {
+      let (cert_path, key_path) = self.issue(name);
+      Identity::from_pem(
+        std::fs::read(cert_path).unwrap(),
+        std::fs::read(key_path).unwrap(),
+      )
+    }
+  }
+
+  // Start a TLS server on a free local port
+  async fn start_server(config: TlsConfig) -> (SocketAddr, oneshot::Sender<()>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let service = CommitmentService::init(
+      Arc::new(temp_store()),
+      Policy::default(),
+      Arc::new(IdempotencyCache::new(chrono::Duration::hours(1))),
+      false,
+    );
+    let (tx, rx) = oneshot::channel();
+    let server = Server::builder()
+      .tls_config(server_tls_config(&config).unwrap())
+      .unwrap()
+      .add_service(CommitmentServer::new(service))
+      .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+        let _ = rx.await;
+      });
+    tokio::spawn(server);
+    (addr, tx)
+  }
+
+  // Call the server over TLS, with client certificate if given
+  async fn call(
+    addr: SocketAddr,
+    ca_pem: Vec<u8>,
+    identity: Option<Identity>,
+  ) -> Result<(), String> {
+    let mut tls = ClientTlsConfig::new()
+      .domain_name("localhost")
+      .ca_certificate(Certificate::from_pem(ca_pem));
+    if let Some(identity) = identity {
+      tls = tls.identity(identity);
+    }
+    let channel = Channel::from_shared(format!("https://{}", addr))
+      .unwrap()
+      .tls_config(tls)
+      .map_err(|e| e.to_string())?
+      .connect()
+      .await
+      .map_err(|e| e.to_string())?;
+    CommitmentClient::new(channel)
+      .get_customer_ids(())
+      .await
+      .map(|_| ())
+      .map_err(|e| e.to_string())
+  }
+
+  #[tokio::test]
+  async fn test_tls() {
+    let pki = TestPki::new();
+    let (cert_path, key_path) = pki.issue("localhost");
+    let (addr, shutdown) = start_server(TlsConfig {
+      cert_path,
+      key_path,
+      client_ca_path: None,
+    })
+    .await;
+    assert!(call(addr, pki.ca_pem(), None).await.is_ok());
+    // Server certificate not signed by the trusted CA
+    let other = TestPki::new();
+    assert!(call(addr, other.ca_pem(), None).await.is_err());
+    let _ = shutdown.send(());
+  }
+
+  #[tokio::test]
+  async fn test_mutual_tls() {
+    let pki = TestPki::new();
+    let (cert_path, key_path) = pki.issue("localhost");
+    let (addr, shutdown) = start_server(TlsConfig {
+      cert_path,
+      key_path,
+      client_ca_path: Some(pki.dir.join("ca.pem")),
+    })
+    .await;
+    assert!(call(addr, pki.ca_pem(), Some(pki.identity("pos")))
+      .await
+      .is_ok());
+    // Without client certificate
+    assert!(call(addr, pki.ca_pem(), None).await.is_err());
+    // Client certificate of another CA
+    let other = TestPki::new();
+    assert!(call(addr, pki.ca_pem(), Some(other.identity("pos")))
+      .await
+      .is_err());
+    let _ = shutdown.send(());
+  }
+
+  #[test]
+  fn test_server_tls_config_missing_file() {
+    let config = TlsConfig {
+      cert_path: PathBuf::from("/nonexistent/server.pem"),
+      key_path: PathBuf::from("/nonexistent/server.key"),
+      client_ca_path: None,
+    };
+    assert!(server_tls_config(&config).is_err());
+  }
+}